@@ -0,0 +1,103 @@
+use std::ops::Range;
+
+use crate::error::{Result, TorrentError};
+
+/// Deepest nesting of lists and dictionaries accepted, deeper data comes from
+/// a peer trying to exhaust the stack rather than from a real torrent
+const MAX_NESTING: usize = 256;
+
+/// Byte span of the value stored under `key` in the top level dictionary of
+/// a bencoded document, exactly as it appears in `bytes`.
+pub fn dict_value_span(bytes: &[u8], key: &[u8]) -> Result<Range<usize>> {
+    if bytes.first() != Some(&b'd') {
+        return Err(TorrentError::Metainfo("expected a dictionary".to_string()));
+    }
+    let mut pos = 1;
+    while bytes.get(pos) != Some(&b'e') {
+        let key_span = string_span(bytes, pos)?;
+        let value_start = key_span.end;
+        let value_end = nested_value_end(bytes, value_start, 1)?;
+        if string_content(bytes, key_span.start)? == key {
+            return Ok(value_start..value_end);
+        }
+        pos = value_end;
+    }
+    Err(TorrentError::Metainfo(format!(
+        "missing key {}",
+        String::from_utf8_lossy(key)
+    )))
+}
+
+/// Position right after the value starting at `pos`, used to find data
+/// appended after a bencoded value
+pub fn value_end(bytes: &[u8], pos: usize) -> Result<usize> {
+    nested_value_end(bytes, pos, 0)
+}
+
+/// `value_end` for a value inside `depth` lists and dictionaries
+fn nested_value_end(bytes: &[u8], pos: usize, depth: usize) -> Result<usize> {
+    if depth > MAX_NESTING {
+        return Err(TorrentError::Metainfo(format!(
+            "value at {} is nested too deeply",
+            pos
+        )));
+    }
+    match bytes.get(pos) {
+        Some(b'i') => {
+            let end = find(bytes, pos, b'e')?;
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut pos = pos + 1;
+            while bytes.get(pos) != Some(&b'e') {
+                pos = nested_value_end(bytes, pos, depth + 1)?;
+            }
+            Ok(pos + 1)
+        }
+        Some(b'd') => {
+            let mut pos = pos + 1;
+            while bytes.get(pos) != Some(&b'e') {
+                pos = string_span(bytes, pos)?.end;
+                pos = nested_value_end(bytes, pos, depth + 1)?;
+            }
+            Ok(pos + 1)
+        }
+        Some(b'0'..=b'9') => Ok(string_span(bytes, pos)?.end),
+        Some(byte) => Err(TorrentError::Metainfo(format!(
+            "unexpected byte {:?} at {}",
+            *byte as char, pos
+        ))),
+        None => Err(TorrentError::Metainfo("unexpected end of data".to_string())),
+    }
+}
+
+/// Span of a whole `<length>:<content>` string
+fn string_span(bytes: &[u8], pos: usize) -> Result<Range<usize>> {
+    let colon = find(bytes, pos, b':')?;
+    let length: usize = std::str::from_utf8(&bytes[pos..colon])
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| TorrentError::Metainfo(format!("invalid string length at {}", pos)))?;
+    // A length prefix near usize::MAX would wrap around past the check
+    let end = (colon + 1)
+        .checked_add(length)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| TorrentError::Metainfo(format!("string at {} exceeds data", pos)))?;
+    Ok(pos..end)
+}
+
+fn string_content(bytes: &[u8], pos: usize) -> Result<&[u8]> {
+    let span = string_span(bytes, pos)?;
+    let colon = find(bytes, pos, b':')?;
+    Ok(&bytes[colon + 1..span.end])
+}
+
+fn find(bytes: &[u8], from: usize, byte: u8) -> Result<usize> {
+    bytes
+        .get(from..)
+        .unwrap_or_default()
+        .iter()
+        .position(|b| *b == byte)
+        .map(|offset| from + offset)
+        .ok_or_else(|| TorrentError::Metainfo(format!("unterminated value at {}", from)))
+}
@@ -1,4 +1,4 @@
-pub fn bitfield_has_piece(bitfield: &Vec<u8>, index: usize) -> bool {
+pub fn bitfield_has_piece(bitfield: &[u8], index: usize) -> bool {
     let byte_index = index / 8;
     let offset = index % 8;
 
@@ -9,7 +9,7 @@ pub fn bitfield_has_piece(bitfield: &Vec<u8>, index: usize) -> bool {
     bitfield[byte_index] >> (7 - offset) & 1 != 0
 }
 
-pub fn bitfield_set_piece(bitfield: &mut Vec<u8>, index: usize) {
+pub fn bitfield_set_piece(bitfield: &mut [u8], index: usize) {
     let byte_index = index / 8;
     let offset = index % 8;
 
@@ -1,152 +1,490 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    env,
-    fs::File,
-    io::Write,
-    net::Ipv4Addr,
-    path::PathBuf,
-    str::FromStr,
+    future::Future,
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use byte_unit::{Byte, UnitType};
 use tokio::{
-    sync::{mpsc, Mutex},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, Mutex},
+    task::JoinSet,
     time,
 };
 
-use crate::{torrent_file::TorrentFile, tracker::fetch_peers, worker::start_download_worker};
-
-#[derive(Debug)]
-pub struct PieceWork {
-    pub index: usize,
-    pub hash: [u8; 20],
-    pub length: usize,
-}
+use crate::{
+    error::{Result, TorrentError},
+    event::{send_event, Event},
+    listener::IncomingPeer,
+    lsd::LocalPeer,
+    picker::PiecePicker,
+    storage::Storage,
+    swarm::{ConnectionSlots, PeerCandidates, PeerSource},
+    torrent_file::TorrentFile,
+    tracker::{AnnounceEvent, AnnounceStats, TrackerTiers},
+    webseed::{start_web_seed_worker, WebSeed},
+    worker::{start_download_worker, start_incoming_worker},
+};
 
 #[derive(Debug)]
 pub struct PieceResult {
     pub index: usize,
+    /// None for pieces downloaded from web seeds
+    pub peer: Option<SocketAddr>,
     pub buf: Vec<u8>,
 }
 
-pub struct WorkerStatusMessage {
-    pub connected: bool,
-    pub id: Ipv4Addr,
-}
+const SLOT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+/// Used until a tracker replies with its own interval
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Shortest time between announces when trackers don't set one
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Keeps trackers asking for no delay from being flooded
+const LOWEST_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Trackers that never reply must not hold up the download
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Leaving the swarm must not hold up a shutdown for long
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn download_file(torrent_file: &TorrentFile) {
-    // Fetch peers list from tracker
-    let peers = fetch_peers(torrent_file).await;
+/// Where peers come from besides trackers
+#[derive(Default)]
+pub struct PeerFeeds<'a> {
+    /// Peers of the local network, when local service discovery is enabled
+    pub local: Option<broadcast::Receiver<LocalPeer>>,
+    /// Peers that connected to us, when incoming connections are accepted.
+    /// The receiver outlives a download so pausing doesn't close it.
+    pub incoming: Option<&'a mut mpsc::Receiver<IncomingPeer>>,
+}
 
-    let work_queue = Arc::new(Mutex::new(VecDeque::new()));
-    let (result_sender, mut result_receiver) = mpsc::channel::<PieceResult>(100);
-    let (status_sender, mut status_receiver) = mpsc::channel::<WorkerStatusMessage>(100);
-
-    // Init work queue
-    {
-        let mut queue = work_queue.lock().await;
-        for (index, hash) in torrent_file.piece_hashes.iter().enumerate() {
-            let length = torrent_file.calculate_piece_size(index);
-            let piece_work = PieceWork {
-                index,
-                hash: hash.to_owned(),
-                length,
-            };
-            queue.push_back(piece_work)
-        }
+/// Download the pieces `picker` is still missing into `storage`, until they
+/// are all verified or `stop` resolves. Progress is kept in `picker` so the
+/// download can be started again later.
+pub async fn download_file(
+    torrent_file: &TorrentFile,
+    picker: &Arc<Mutex<PiecePicker>>,
+    storage: &Storage,
+    slots: &ConnectionSlots,
+    events: &broadcast::Sender<Event>,
+    feeds: PeerFeeds<'_>,
+    stop: impl Future<Output = ()>,
+) -> Result<()> {
+    let PeerFeeds {
+        local: mut local_peers,
+        incoming: mut incoming_peers,
+    } = feeds;
+    if picker.lock().await.is_complete() {
+        return Ok(());
     }
 
-    // Start logger thread
-    tokio::spawn(async move {
-        let mut connected_workers = 0;
-        let mut workers_status: HashMap<Ipv4Addr, bool> = HashMap::new();
-        while let Some(status) = status_receiver.recv().await {
-            if status.connected && !workers_status.get(&status.id).unwrap_or(&false) {
-                connected_workers += 1;
-                workers_status.insert(status.id, true);
-                println!(
-                    "connected workers: {}, new connection: {}",
-                    connected_workers, &status.id
-                );
-            } else if !status.connected && *workers_status.get(&status.id).unwrap_or(&false) {
-                connected_workers -= 1;
-                workers_status.insert(status.id, false);
-                println!(
-                    "connected workers: {}, connection stopped: {}",
-                    connected_workers, &status.id
-                );
-            }
-        }
-    });
+    let (result_sender, mut result_receiver) = mpsc::channel::<PieceResult>(100);
 
-    for peer in peers {
+    // Web seeds don't use connection slots, they are all started right away
+    let mut web_seeds = JoinSet::new();
+    for web_seed in WebSeed::all(torrent_file) {
         let thread_result_sender = result_sender.clone();
-        let thread_status_sender = status_sender.clone();
+        let thread_events = events.clone();
         let thread_torrent_file = torrent_file.clone();
-        let thread_work_queue = work_queue.clone();
-        tokio::spawn(async move {
-            while (start_download_worker(
-                &peer,
+        let thread_picker = picker.clone();
+        // Each mirror is limited like a peer connection
+        let bandwidth = slots.bandwidth.peer(torrent_file.infohash);
+        web_seeds.spawn(async move {
+            start_web_seed_worker(
+                &web_seed,
                 &thread_torrent_file,
-                &thread_work_queue,
+                &thread_picker,
+                &bandwidth,
                 &thread_result_sender,
-                &thread_status_sender,
+                &thread_events,
             )
-            .await)
-                .is_err()
-            {
-                if let Err(e) = thread_status_sender
-                    .send(WorkerStatusMessage {
-                        connected: false,
-                        id: peer.ip,
-                    })
-                    .await
-                {
-                    eprintln!("error sending status to main thread:\n{}", e);
-                }
-                time::sleep(Duration::from_secs(3)).await;
-            }
+            .await
         });
     }
 
+    // Fetch peers list from tracker for every swarm of the torrent, web seeds
+    // or peers of the local network may be enough without them
+    let mut candidates = PeerCandidates::new(torrent_file.private);
+    let mut announcer = Announcer::new(torrent_file);
+    // Bytes of the pieces verified since the started announce
+    let mut downloaded = 0;
+    let tracker_error = announce_swarms(
+        torrent_file,
+        slots,
+        events,
+        &mut candidates,
+        &mut announcer,
+        announce_stats(picker, downloaded).await,
+        Some(AnnounceEvent::Started),
+    )
+    .await;
+    if let Some(e) = tracker_error {
+        if candidates.is_empty()
+            && web_seeds.is_empty()
+            && local_peers.is_none()
+            && incoming_peers.is_none()
+        {
+            return Err(e);
+        }
+    }
+
+    let mut workers = JoinSet::new();
+    let (exit_sender, mut exit_receiver) = mpsc::unbounded_channel();
+    // Whether the last announce left no peer to try
+    let mut trackers_exhausted = !candidates.has_waiting();
+
     // Collect results pieces
-    let mut buf = vec![0u8; torrent_file.length];
-    let mut done_pieces = 0;
+    let mut done_pieces = picker.lock().await.num_verified();
 
-    // Bandwidth display
+    // Bandwidth measure
     let mut start_time = Instant::now();
     let mut window_bytes_received = 0;
-    let window_duration = Duration::from_secs(3);
 
-    while done_pieces < torrent_file.piece_hashes.len() {
-        let result_piece = result_receiver
-            .recv()
-            .await
-            .expect("result channel closed unexpectedly");
-        let (start, end) = torrent_file.calculate_bound_for_piece(result_piece.index);
-        buf[start..end].copy_from_slice(&result_piece.buf);
-        done_pieces += 1;
-        window_bytes_received += result_piece.buf.len();
-
-        if start_time.elapsed() >= window_duration {
-            let speed = Byte::from_u64(window_bytes_received as u64 / window_duration.as_secs())
-                .get_appropriate_unit(UnitType::Decimal);
-            println!(
-                "{:.2}/s, {}/{} pieces",
-                speed,
-                done_pieces,
-                torrent_file.piece_hashes.len()
+    tokio::pin!(stop);
+    // Skipped files may leave pieces missing for good
+    while !picker.lock().await.is_complete() {
+        // Fill free connection slots with the best known peers
+        while workers.len() < slots.limits.max_connections_per_torrent {
+            let Some(permit) = slots.try_acquire_connection() else {
+                break;
+            };
+            let Some((peer, infohash)) = candidates.pop_best() else {
+                break;
+            };
+            let thread_result_sender = result_sender.clone();
+            let thread_events = events.clone();
+            let thread_torrent_file = torrent_file.clone();
+            let thread_picker = picker.clone();
+            let thread_slots = slots.clone();
+            let exit = WorkerExit::new(peer.addr(), &exit_sender);
+            workers.spawn(async move {
+                let _permit = permit;
+                let result = start_download_worker(
+                    &peer,
+                    &infohash,
+                    &thread_torrent_file,
+                    &thread_picker,
+                    &thread_slots,
+                    &thread_result_sender,
+                    &thread_events,
+                )
+                .await;
+                exit.finish(result.is_err());
+            });
+        }
+
+        if workers.is_empty()
+            && web_seeds.is_empty()
+            && !candidates.has_pending()
+            && local_peers.is_none()
+            && incoming_peers.is_none()
+            && (announcer.trackers.is_empty() || trackers_exhausted)
+        {
+            return Err(TorrentError::PeerProtocol(
+                "every peer failed before the download completed".to_string(),
+            ));
+        }
+
+        // Trackers may know new peers once every known one is used up
+        let starving =
+            workers.len() < slots.limits.max_connections_per_torrent && !candidates.has_waiting();
+        let next_announce = announcer.next(starving);
+
+        tokio::select! {
+            Some(result_piece) = result_receiver.recv() => {
+                store_piece(torrent_file, picker, storage, events, &result_piece).await?;
+                done_pieces += 1;
+                downloaded += result_piece.buf.len();
+                window_bytes_received += result_piece.buf.len();
+                if let Some(peer) = result_piece.peer {
+                    candidates.credit(peer, result_piece.buf.len());
+                }
+            }
+            Some((addr, failed)) = exit_receiver.recv() => {
+                candidates.disconnected(addr, failed);
+                trackers_exhausted = false;
+            }
+            // Exits are reported by the workers themselves
+            Some(_) = workers.join_next() => {}
+            // Failing mirrors already reported every error as an event
+            Some(_) = web_seeds.join_next() => {}
+            local_peer = recv_local_peer(&mut local_peers) => {
+                if torrent_file.swarm_infohashes().contains(&local_peer.infohash) {
+                    candidates.add([local_peer.peer], PeerSource::LocalDiscovery, local_peer.infohash);
+                }
+            }
+            incoming = recv_incoming_peer(&mut incoming_peers) => {
+                // Peers beyond the limits are disconnected, like the ones
+                // the listener couldn't hand over
+                let permit = (workers.len() < slots.limits.max_connections_per_torrent)
+                    .then(|| slots.try_acquire_connection())
+                    .flatten();
+                if let Some(permit) = permit {
+                    let thread_result_sender = result_sender.clone();
+                    let thread_events = events.clone();
+                    let thread_torrent_file = torrent_file.clone();
+                    let thread_picker = picker.clone();
+                    let thread_slots = slots.clone();
+                    let exit = WorkerExit::new(incoming.addr, &exit_sender);
+                    workers.spawn(async move {
+                        let _permit = permit;
+                        let result = start_incoming_worker(
+                            incoming,
+                            &thread_torrent_file,
+                            &thread_picker,
+                            &thread_slots,
+                            &thread_result_sender,
+                            &thread_events,
+                        )
+                        .await;
+                        exit.finish(result.is_err());
+                    });
+                }
+            }
+            _ = time::sleep_until(next_announce.into()), if !announcer.trackers.is_empty() => {
+                // Failing trackers are asked again at the next interval
+                let _ = announce_swarms(
+                    torrent_file,
+                    slots,
+                    events,
+                    &mut candidates,
+                    &mut announcer,
+                    announce_stats(picker, downloaded).await,
+                    None,
+                )
+                .await;
+                trackers_exhausted = !candidates.has_waiting();
+            }
+            // Retry filling slots freed by other torrents or peers done backing off
+            _ = time::sleep(SLOT_POLL_INTERVAL) => {}
+            _ = &mut stop => break,
+        }
+
+        let elapsed = start_time.elapsed();
+        if elapsed >= PROGRESS_INTERVAL {
+            send_event(
+                events,
+                Event::Progress {
+                    infohash: torrent_file.infohash,
+                    verified_pieces: done_pieces,
+                    total_pieces: torrent_file.num_pieces(),
+                    download_rate: (window_bytes_received as f64 / elapsed.as_secs_f64()) as u64,
+                },
             );
             window_bytes_received = 0;
             start_time = Instant::now();
         }
     }
 
-    let target_dir = env::current_dir().unwrap_or(PathBuf::from_str("/tmp/").unwrap());
-    let file_path = target_dir.join(&torrent_file.name);
-    let mut file = File::create(&file_path).expect("failed to create file");
-    file.write_all(&buf).expect("failed to write data to file");
-    println!("file downloaded successfully to {:?}", file_path);
+    // Stop workers still connected or waiting for a retry, and wait for
+    // their connections to close
+    workers.abort_all();
+    web_seeds.abort_all();
+    while workers.join_next().await.is_some() {}
+    while web_seeds.join_next().await.is_some() {}
+
+    // Pieces verified by workers but not written yet
+    result_receiver.close();
+    while let Ok(result_piece) = result_receiver.try_recv() {
+        store_piece(torrent_file, picker, storage, events, &result_piece).await?;
+        downloaded += result_piece.buf.len();
+    }
+    // Blocks requested by the workers will never arrive
+    picker.lock().await.reset_requests();
+
+    // Nothing is seeded once the download ends, a completed download leaves
+    // the swarm right after telling so
+    let stats = announce_stats(picker, downloaded).await;
+    let completed = picker
+        .lock()
+        .await
+        .is_complete()
+        .then_some(AnnounceEvent::Completed);
+    for event in completed.into_iter().chain([AnnounceEvent::Stopped]) {
+        for infohash in torrent_file.swarm_infohashes() {
+            // Trackers forget silent peers eventually, failing here is harmless
+            let _ = time::timeout(
+                STOPPED_ANNOUNCE_TIMEOUT,
+                announcer
+                    .trackers
+                    .fetch_peers(&infohash, &slots.identity, stats, Some(event)),
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// Trackers of the torrent and when they are announced to again
+struct Announcer {
+    trackers: TrackerTiers,
+    last: Instant,
+    interval: Duration,
+    min_interval: Duration,
+}
+
+impl Announcer {
+    fn new(torrent_file: &TorrentFile) -> Self {
+        Announcer {
+            trackers: TrackerTiers::new(&torrent_file.trackers),
+            last: Instant::now(),
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            min_interval: MIN_ANNOUNCE_INTERVAL,
+        }
+    }
+
+    /// Next announce, as soon as trackers allow when `starving` for peers
+    fn next(&self, starving: bool) -> Instant {
+        if starving {
+            self.last + self.min_interval.min(self.interval)
+        } else {
+            self.last + self.interval
+        }
+    }
+}
+
+/// Announce every swarm of the torrent and add the peers trackers return.
+/// Returns the error of the last swarm no tracker replied for.
+async fn announce_swarms(
+    torrent_file: &TorrentFile,
+    slots: &ConnectionSlots,
+    events: &broadcast::Sender<Event>,
+    candidates: &mut PeerCandidates,
+    announcer: &mut Announcer,
+    stats: AnnounceStats,
+    event: Option<AnnounceEvent>,
+) -> Option<TorrentError> {
+    let mut tracker_error = None;
+    let mut intervals = None;
+    for infohash in torrent_file.swarm_infohashes() {
+        let announced = time::timeout(
+            ANNOUNCE_TIMEOUT,
+            announcer
+                .trackers
+                .fetch_peers(&infohash, &slots.identity, stats, event),
+        )
+        .await
+        .unwrap_or_else(|_| Err(TorrentError::Tracker("announce timed out".to_string())));
+        match announced {
+            Ok(announced) => {
+                send_event(
+                    events,
+                    Event::TrackerReplied {
+                        infohash: torrent_file.infohash,
+                        tracker: announced.tracker,
+                        peers: announced.peers.len(),
+                    },
+                );
+                candidates.add(announced.peers, PeerSource::Tracker, infohash);
+                // Swarms of hybrid torrents are announced together, as
+                // often as the most demanding tracker wants
+                let min_interval = announced
+                    .min_interval
+                    .unwrap_or(MIN_ANNOUNCE_INTERVAL)
+                    .max(LOWEST_ANNOUNCE_INTERVAL);
+                let interval = announced.interval.max(min_interval);
+                intervals = Some(match intervals {
+                    Some((other_interval, other_min_interval)) => (
+                        interval.min(other_interval),
+                        min_interval.max(other_min_interval),
+                    ),
+                    None => (interval, min_interval),
+                });
+            }
+            Err(e) => tracker_error = Some(e),
+        }
+    }
+    announcer.last = Instant::now();
+    if let Some((interval, min_interval)) = intervals {
+        announcer.interval = interval;
+        announcer.min_interval = min_interval;
+    }
+    tracker_error
+}
+
+/// Totals for the next announce. Nothing is uploaded, peers stay choked.
+async fn announce_stats(picker: &Mutex<PiecePicker>, downloaded: usize) -> AnnounceStats {
+    AnnounceStats {
+        uploaded: 0,
+        downloaded,
+        left: picker.lock().await.wanted_bytes_left(),
+    }
+}
+
+/// Reports the end of a worker task to the controller when dropped, so
+/// workers that panic or get aborted free their peer too
+struct WorkerExit {
+    addr: SocketAddr,
+    /// Stays set unless the worker returns without error
+    failed: bool,
+    sender: mpsc::UnboundedSender<(SocketAddr, bool)>,
+}
+
+impl WorkerExit {
+    fn new(addr: SocketAddr, sender: &mpsc::UnboundedSender<(SocketAddr, bool)>) -> Self {
+        WorkerExit {
+            addr,
+            failed: true,
+            sender: sender.clone(),
+        }
+    }
+
+    fn finish(mut self, failed: bool) {
+        self.failed = failed;
+    }
+}
+
+impl Drop for WorkerExit {
+    fn drop(&mut self) {
+        // The controller is gone when the download ended
+        let _ = self.sender.send((self.addr, self.failed));
+    }
+}
+
+/// Write a verified piece to `storage` and record it
+async fn store_piece(
+    torrent_file: &TorrentFile,
+    picker: &Mutex<PiecePicker>,
+    storage: &Storage,
+    events: &broadcast::Sender<Event>,
+    result_piece: &PieceResult,
+) -> Result<()> {
+    storage.write_piece(result_piece.index, &result_piece.buf)?;
+    picker.lock().await.piece_verified(result_piece.index);
+    send_event(
+        events,
+        Event::PieceVerified {
+            infohash: torrent_file.infohash,
+            index: result_piece.index,
+        },
+    );
+    Ok(())
+}
+
+/// Next peer that connected to us, never resolving when incoming connections
+/// are not accepted
+async fn recv_incoming_peer(
+    incoming_peers: &mut Option<&mut mpsc::Receiver<IncomingPeer>>,
+) -> IncomingPeer {
+    let Some(receiver) = incoming_peers else {
+        return std::future::pending().await;
+    };
+    match receiver.recv().await {
+        Some(incoming) => incoming,
+        None => std::future::pending().await,
+    }
+}
+
+/// Next peer found on the local network, never resolving when local service
+/// discovery is disabled
+async fn recv_local_peer(local_peers: &mut Option<broadcast::Receiver<LocalPeer>>) -> LocalPeer {
+    let Some(receiver) = local_peers else {
+        return std::future::pending().await;
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(local_peer) => return local_peer,
+            // Peers are announced again every few minutes
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
 }
@@ -0,0 +1,231 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_bencode::ser;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    error::{Result, TorrentError},
+    storage::Storage,
+    torrent_file::{BencodeFile, FileAttributes, FileInfo},
+};
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Number of pieces automatic piece lengths aim for
+const TARGET_NUM_PIECES: usize = 1500;
+
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Picked from the total size when not set
+    pub piece_length: Option<usize>,
+    /// Tracker tiers, the first tracker is also used as `announce`
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Unix timestamp, skipped when not set
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    /// BEP 19 `url-list`
+    pub web_seeds: Vec<String>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|duration| duration.as_secs() as i64),
+            private: false,
+            web_seeds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CreatedTorrent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+    announce_list: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<i64>,
+    info: CreatedInfo,
+    #[serde(rename = "url-list", skip_serializing_if = "Vec::is_empty")]
+    url_list: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CreatedInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<BencodeFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<i64>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: i64,
+    pieces: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+}
+
+/// Piece length giving about `TARGET_NUM_PIECES` pieces
+pub fn default_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_NUM_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Bencoded torrent for the file or directory at `path`
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| TorrentError::Storage(format!("invalid file name for {:?}", path)))?
+        .to_string();
+    let metadata = fs::metadata(path)?;
+
+    // Paths relative to the parent of `path`, as Storage expects
+    let mut files = Vec::new();
+    let mut relative_paths = Vec::new();
+    if metadata.is_dir() {
+        walk_dir(path, Path::new(""), &mut relative_paths)?;
+        relative_paths.sort();
+        let mut offset = 0;
+        for relative_path in &relative_paths {
+            let length = fs::metadata(path.join(relative_path))?.len() as usize;
+            files.push(FileInfo {
+                path: PathBuf::from(&name).join(relative_path),
+                length,
+                offset,
+                pieces_root: None,
+                attributes: FileAttributes::default(),
+            });
+            offset += length;
+        }
+    } else {
+        files.push(FileInfo {
+            path: PathBuf::from(&name),
+            length: metadata.len() as usize,
+            offset: 0,
+            pieces_root: None,
+            attributes: FileAttributes::default(),
+        });
+    }
+    let total_length = files.iter().map(|file| file.length).sum();
+    if total_length == 0 {
+        return Err(TorrentError::Storage(format!("{:?} has no content", path)));
+    }
+
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| default_piece_length(total_length));
+    if piece_length == 0 {
+        return Err(TorrentError::Metainfo(
+            "piece length must not be zero".to_string(),
+        ));
+    }
+    let root = path.parent().unwrap_or(Path::new(""));
+    let storage = Storage::from_files(root, &files, piece_length);
+    let pieces = hash_pieces(&storage, total_length, piece_length)?;
+
+    let info = CreatedInfo {
+        files: metadata.is_dir().then(|| {
+            relative_paths
+                .iter()
+                .zip(&files)
+                .map(|(relative_path, file)| BencodeFile {
+                    length: file.length as i64,
+                    path: relative_path
+                        .iter()
+                        .map(|component| component.to_string_lossy().into_owned())
+                        .collect(),
+                    attr: None,
+                    symlink_path: None,
+                })
+                .collect()
+        }),
+        length: (!metadata.is_dir()).then_some(total_length as i64),
+        name,
+        piece_length: piece_length as i64,
+        pieces: ByteBuf::from(pieces),
+        private: options.private.then_some(1),
+    };
+    let torrent = CreatedTorrent {
+        announce: options.trackers.iter().flatten().next().cloned(),
+        announce_list: options.trackers.clone(),
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: options.creation_date,
+        info,
+        url_list: options.web_seeds.clone(),
+    };
+    ser::to_bytes(&torrent)
+        .map_err(|e| TorrentError::Metainfo(format!("error encoding torrent: {}", e)))
+}
+
+/// Regular files under `root.join(relative)`, relative to `root`
+fn walk_dir(root: &Path, relative: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let relative_path = relative.join(entry?.file_name());
+        let metadata = fs::metadata(root.join(&relative_path))?;
+        if metadata.is_dir() {
+            walk_dir(root, &relative_path, paths)?;
+        } else if metadata.is_file() {
+            paths.push(relative_path);
+        }
+    }
+    Ok(())
+}
+
+/// SHA-1 of every piece, computed on all available cores
+fn hash_pieces(storage: &Storage, total_length: usize, piece_length: usize) -> Result<Vec<u8>> {
+    let num_pieces = total_length.div_ceil(piece_length);
+    let num_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(num_pieces);
+    let pieces_per_thread = num_pieces.div_ceil(num_threads);
+
+    let chunks: Vec<Result<Vec<u8>>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..num_threads)
+            .map(|thread_index| {
+                scope.spawn(move || {
+                    let first = std::cmp::min(thread_index * pieces_per_thread, num_pieces);
+                    let last = std::cmp::min(first + pieces_per_thread, num_pieces);
+                    let mut hashes = Vec::with_capacity((last - first) * 20);
+                    for index in first..last {
+                        let start = index * piece_length;
+                        let length = std::cmp::min(piece_length, total_length - start);
+                        let buf = storage.read_piece(index, length)?;
+                        hashes.extend_from_slice(&<Sha1 as Digest>::digest(&buf));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect()
+    });
+    Ok(chunks.into_iter().collect::<Result<Vec<_>>>()?.concat())
+}
@@ -0,0 +1,26 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TorrentError {
+    /// Torrent file or metadata that can't be decoded or is inconsistent
+    #[error("invalid metainfo: {0}")]
+    Metainfo(String),
+    #[error("tracker error: {0}")]
+    Tracker(String),
+    /// Peer misbehaving, timing out or sending unexpected data
+    #[error("peer protocol error: {0}")]
+    PeerProtocol(String),
+    /// Web seed unreachable or answering with unexpected data
+    #[error("web seed error: {0}")]
+    WebSeed(String),
+    /// Downloaded data could not be stored
+    #[error("storage error: {0}")]
+    Storage(String),
+    /// Torrent data asked for will not be downloaded, e.g. the torrent stopped
+    #[error("data not available: {0}")]
+    Unavailable(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TorrentError>;
@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+
+/// Notifications published by a `Session` to its subscribers, serializable
+/// so they can be logged as JSON
+#[derive(Debug, Clone, Serialize)]
+pub enum Event {
+    TorrentAdded {
+        infohash: [u8; 20],
+    },
+    /// Metadata of the torrent is known and its download can start, right
+    /// after adding a torrent file, once downloaded from peers for a magnet
+    /// link
+    MetadataReceived {
+        infohash: [u8; 20],
+        name: String,
+    },
+    TrackerReplied {
+        infohash: [u8; 20],
+        tracker: String,
+        peers: usize,
+    },
+    PeerConnected {
+        infohash: [u8; 20],
+        peer: SocketAddr,
+    },
+    PeerDisconnected {
+        infohash: [u8; 20],
+        peer: SocketAddr,
+        error: Option<String>,
+    },
+    /// Piece passed its hash check and was written to storage
+    PieceVerified {
+        infohash: [u8; 20],
+        index: usize,
+    },
+    HashFailed {
+        infohash: [u8; 20],
+        index: usize,
+        peer: SocketAddr,
+    },
+    /// Request to a web seed failed, it is retried after a delay
+    WebSeedFailed {
+        infohash: [u8; 20],
+        url: String,
+        error: String,
+    },
+    /// Sent periodically while downloading
+    Progress {
+        infohash: [u8; 20],
+        verified_pieces: usize,
+        total_pieces: usize,
+        /// Bytes per second since the previous progress event
+        download_rate: u64,
+    },
+    TorrentPaused {
+        infohash: [u8; 20],
+    },
+    TorrentResumed {
+        infohash: [u8; 20],
+    },
+    TorrentFinished {
+        infohash: [u8; 20],
+    },
+    /// The session shut down before the download completed
+    TorrentStopped {
+        infohash: [u8; 20],
+    },
+    TorrentFailed {
+        infohash: [u8; 20],
+        error: String,
+    },
+    TorrentRemoved {
+        infohash: [u8; 20],
+    },
+}
+
+/// Publish `event`, having nobody listening is fine
+pub fn send_event(events: &broadcast::Sender<Event>, event: Event) {
+    let _ = events.send(event);
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use serde_bencode::{de, ser};
+
+use crate::{
+    bencode::value_end,
+    error::{Result, TorrentError},
+};
+
+/// Extension message id reserved for the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// Id peers must use when sending us ut_metadata messages
+pub const UT_METADATA_ID: u8 = 1;
+
+/// BEP 10 extension handshake
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message id the sender expects
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+}
+
+impl ExtensionHandshake {
+    pub fn new() -> Self {
+        ExtensionHandshake {
+            m: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID as i64)]),
+            metadata_size: None,
+            v: None,
+        }
+    }
+
+    /// Id to use when sending `extension` messages to the peer
+    pub fn extension_id(&self, extension: &str) -> Option<u8> {
+        self.m
+            .get(extension)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        ser::to_bytes(self).map_err(|e| {
+            TorrentError::PeerProtocol(format!("error encoding extension handshake: {}", e))
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // Rejects nesting deep enough to exhaust the decoder's stack
+        value_end(bytes, 0)?;
+        de::from_bytes(bytes).map_err(|e| {
+            TorrentError::PeerProtocol(format!("error decoding extension handshake: {}", e))
+        })
+    }
+}
+
+/// Header of a BEP 9 ut_metadata message, piece data follows it for `data`
+/// messages
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
+}
+
+pub const METADATA_REQUEST: i64 = 0;
+pub const METADATA_DATA: i64 = 1;
+pub const METADATA_REJECT: i64 = 2;
@@ -0,0 +1,196 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use url::Url;
+
+use crate::error::{Result, TorrentError};
+
+const DEFAULT_PORT: u16 = 21;
+
+/// FTP control connection fetching byte ranges of files (RFC 959), with
+/// passive data connections (RFC 2428 EPSV, or PASV) and restart offsets
+/// (RFC 3659 REST). Reused for every range of a mirror.
+pub struct FtpConnection {
+    control: BufReader<TcpStream>,
+    /// Address of the control connection, data connections go to the same
+    /// host whatever the server says
+    peer: SocketAddr,
+}
+
+impl FtpConnection {
+    /// Log in to the server of `url`, as anonymous unless it has credentials
+    pub async fn connect(url: &Url) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| TorrentError::WebSeed(format!("no host in {}", url)))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let stream = TcpStream::connect((host, port)).await?;
+        let peer = stream.peer_addr()?;
+        let mut connection = FtpConnection {
+            control: BufReader::new(stream),
+            peer,
+        };
+        connection.expect_reply(&[220]).await?;
+
+        let user = match url.username() {
+            "" => "anonymous".to_string(),
+            user => percent_decode(user),
+        };
+        let password = percent_decode(url.password().unwrap_or("anonymous@"));
+        let (code, _) = connection.command(&format!("USER {}", user)).await?;
+        match code {
+            230 => {}
+            331 => {
+                connection
+                    .command_expecting(&format!("PASS {}", password), &[230, 202])
+                    .await?;
+            }
+            _ => return Err(unexpected_reply("USER", code)),
+        }
+        connection.command_expecting("TYPE I", &[200]).await?;
+        Ok(connection)
+    }
+
+    /// Bytes `from..to` of the file at the path of `url`
+    pub async fn read_range(&mut self, url: &Url, from: usize, to: usize) -> Result<Vec<u8>> {
+        let path = percent_decode(url.path());
+        let mut data = self.open_data_connection().await?;
+        self.command_expecting(&format!("REST {}", from), &[350])
+            .await?;
+        self.command_expecting(&format!("RETR {}", path), &[125, 150])
+            .await?;
+
+        let mut buf = vec![0u8; to - from];
+        let read = data.read_exact(&mut buf).await;
+        // Closing the data connection early aborts the rest of the transfer,
+        // the server then reports it either done or aborted
+        drop(data);
+        let (code, _) = self.read_reply().await?;
+        read.map_err(|e| TorrentError::WebSeed(format!("reading {}: {}", url, e)))?;
+        if !matches!(code, 226 | 250 | 426 | 451) {
+            return Err(unexpected_reply("RETR", code));
+        }
+        Ok(buf)
+    }
+
+    async fn open_data_connection(&mut self) -> Result<TcpStream> {
+        let (code, text) = self.command("EPSV").await?;
+        let port = if code == 229 {
+            parse_epsv(&text)
+        } else {
+            let (code, text) = self.command("PASV").await?;
+            if code != 227 {
+                return Err(unexpected_reply("PASV", code));
+            }
+            parse_pasv(&text)
+        }
+        .ok_or_else(|| {
+            TorrentError::WebSeed(format!("invalid passive reply from {}", self.peer))
+        })?;
+        Ok(TcpStream::connect(SocketAddr::new(self.peer.ip(), port)).await?)
+    }
+
+    async fn command_expecting(&mut self, command: &str, expected: &[u16]) -> Result<String> {
+        let (code, text) = self.command(command).await?;
+        if !expected.contains(&code) {
+            let verb = command.split(' ').next().unwrap_or(command);
+            return Err(unexpected_reply(verb, code));
+        }
+        Ok(text)
+    }
+
+    async fn command(&mut self, command: &str) -> Result<(u16, String)> {
+        self.control
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.read_reply().await
+    }
+
+    async fn expect_reply(&mut self, expected: &[u16]) -> Result<String> {
+        let (code, text) = self.read_reply().await?;
+        if !expected.contains(&code) {
+            return Err(unexpected_reply("greeting", code));
+        }
+        Ok(text)
+    }
+
+    /// Code and text of the next reply, multi-line replies end with a line
+    /// starting with their code followed by a space
+    async fn read_reply(&mut self) -> Result<(u16, String)> {
+        let mut text = String::new();
+        let mut code = None;
+        loop {
+            let mut line = String::new();
+            if self.control.read_line(&mut line).await? == 0 {
+                return Err(TorrentError::WebSeed(format!(
+                    "{} closed the connection",
+                    self.peer
+                )));
+            }
+            text.push_str(&line);
+            let line_code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            let code = *code.get_or_insert(line_code.ok_or_else(|| {
+                TorrentError::WebSeed(format!("invalid reply from {}: {:?}", self.peer, line))
+            })?);
+            if line_code == Some(code) && line.as_bytes().get(3) == Some(&b' ') {
+                return Ok((code, text));
+            }
+        }
+    }
+}
+
+fn unexpected_reply(command: &str, code: u16) -> TorrentError {
+    TorrentError::WebSeed(format!("unexpected FTP reply {} to {}", code, command))
+}
+
+/// Port of `229 Entering Extended Passive Mode (|||6446|)`
+fn parse_epsv(text: &str) -> Option<u16> {
+    let start = text.find('(')? + 1;
+    let end = start + text[start..].find(')')?;
+    let fields: Vec<&str> = text[start..end].split('|').collect();
+    fields.get(3)?.parse().ok()
+}
+
+/// Port of `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`, the host is
+/// ignored since servers behind NAT often send a private one
+fn parse_pasv(text: &str) -> Option<u16> {
+    let rest = text.get(4..)?;
+    let start = rest.find(|c: char| c.is_ascii_digit())?;
+    let numbers: Vec<u16> = rest[start..]
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|number| !number.is_empty())
+        .take(6)
+        .map(|number| number.parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some(high << 8 | low),
+        _ => None,
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| input.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
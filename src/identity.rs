@@ -0,0 +1,143 @@
+use rand::Rng;
+
+/// Azureus-style prefix: client code and version 0.1.0.0
+pub const DEFAULT_CLIENT_PREFIX: &str = "-TC0100-";
+
+/// How the session presents itself to trackers and peers
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// Client prefix followed by random bytes, new for every session
+    pub peer_id: [u8; 20],
+    /// Lets trackers recognize us across announces, even if our IP changes
+    pub tracker_key: String,
+}
+
+impl Identity {
+    /// Peer id starting with `client_prefix`, which is cut to 20 bytes at most
+    pub fn new(client_prefix: &str) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut peer_id: [u8; 20] = rng.gen();
+        let prefix = &client_prefix.as_bytes()[..std::cmp::min(client_prefix.len(), 20)];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        Identity {
+            peer_id,
+            tracker_key: format!("{:08X}", rng.gen::<u32>()),
+        }
+    }
+}
+
+/// Software a remote peer runs, as told by its peer id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: String,
+}
+
+/// Two letter codes of Azureus-style peer ids, `-XX1234-`
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TC", "torrent-client"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// First letters of Shadow-style peer ids, `S58B-----` or `T03I--`
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Client of a remote peer id following the Azureus or Shadow conventions.
+/// Unknown Azureus codes are kept as the name.
+pub fn decode_peer_id(peer_id: &[u8; 20]) -> Option<Client> {
+    decode_azureus(peer_id).or_else(|| decode_shadow(peer_id))
+}
+
+fn decode_azureus(peer_id: &[u8; 20]) -> Option<Client> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    if !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    // One digit per component, letters for components over 9
+    let mut components = peer_id[3..7]
+        .iter()
+        .map(|c| char::from(*c).to_digit(36))
+        .collect::<Option<Vec<u32>>>()?;
+    if components[3] == 0 {
+        components.pop();
+    }
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or(code, |(_, name)| name);
+    Some(Client {
+        name: name.to_string(),
+        version: join_version(&components),
+    })
+}
+
+fn decode_shadow(peer_id: &[u8; 20]) -> Option<Client> {
+    let (_, name) = SHADOW_CLIENTS
+        .iter()
+        .find(|(letter, _)| *letter == peer_id[0])?;
+    // Up to five version characters padded with dashes, older ids have
+    // three followed by two dashes
+    if &peer_id[6..9] != b"---" && &peer_id[4..6] != b"--" {
+        return None;
+    }
+    let components = peer_id[1..6]
+        .iter()
+        .take_while(|c| **c != b'-')
+        .map(|c| shadow_digit(*c))
+        .collect::<Option<Vec<u32>>>()?;
+    if components.is_empty() {
+        return None;
+    }
+    Some(Client {
+        name: name.to_string(),
+        version: join_version(&components),
+    })
+}
+
+fn shadow_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+fn join_version(components: &[u32]) -> String {
+    components
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -1,30 +1,20 @@
-use crate::torrent_file::BencodeInfo;
-use serde_bencode::ser;
 use sha1::{Digest, Sha1};
 
-static ALLOWED_CHARS: &[u8] = &[
-    b'.', b'-', b'_', b'~', b'-', b'_', b'.', b'!', b'~', b'*', b'\'', b'(', b')', b';', b'/',
-    b'?', b':', b'@', b'&', b'=', b'+', b'$', b',', b'#',
-];
+static ALLOWED_CHARS: &[u8] = b".-_~";
 
 fn is_allowed_byte(byte: &u8) -> bool {
-    // checks if the byte is within the ranges of '0-9', 'a-z', 'A-Z', or is '.', '-', '_', '~'
+    // checks if the byte is within the ranges of '0-9', 'a-z', 'A-Z', or is '.', '-', '_', '~',
+    // anything else could be taken for a query separator
     byte.is_ascii_digit()
         || byte.is_ascii_lowercase()
         || byte.is_ascii_uppercase()
         || ALLOWED_CHARS.contains(byte)
 }
 
-pub fn infohash(info: &BencodeInfo) -> [u8; 20] {
-    let encoded = match ser::to_bytes::<BencodeInfo>(info) {
-        Ok(encoded) => encoded,
-        Err(e) => {
-            eprintln!("error while encoding torrent info:\n{}", e);
-            std::process::exit(1);
-        }
-    };
-
-    <Sha1 as Digest>::digest(encoded).into()
+/// Hash of the info dictionary, taken from the torrent file as is since
+/// re-encoding the parsed struct would drop fields we don't know about
+pub fn infohash(info_bytes: &[u8]) -> [u8; 20] {
+    <Sha1 as Digest>::digest(info_bytes).into()
 }
 
 pub fn url_encode(bytes: &[u8]) -> String {
@@ -38,3 +28,57 @@ pub fn url_encode(bytes: &[u8]) -> String {
     }
     result.join("")
 }
+
+static BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Encode as RFC 4648 base32 without padding
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u64;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+/// Decode RFC 4648 base32 without padding, as used by magnet links
+pub fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for byte in input.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|c| *c == byte.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+pub fn hex_decode(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
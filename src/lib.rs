@@ -0,0 +1,41 @@
+pub mod bencode;
+pub mod bitfield;
+pub mod controller;
+pub mod create;
+pub mod error;
+pub mod event;
+pub mod extension;
+pub mod ftp;
+pub mod identity;
+pub mod infohash;
+pub mod listener;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod message;
+pub mod metadata;
+pub mod mse;
+pub mod peer;
+pub mod picker;
+pub mod rate_limit;
+pub mod reader;
+pub mod resume;
+pub mod session;
+pub mod storage;
+pub mod streaming;
+pub mod swarm;
+pub mod torrent_file;
+pub mod tracker;
+pub mod utp;
+pub mod webseed;
+pub mod worker;
+
+pub use error::{Result, TorrentError};
+pub use event::Event;
+pub use session::{Session, SessionConfig, TorrentHandle, TorrentState, TorrentStatus};
+pub use torrent_file::TorrentFile;
+
+#[macro_use]
+extern crate serde_derive;
+
+static PORT: u16 = 6881;
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex, OnceLock},
+};
+
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle, time};
+
+use crate::{
+    error::{Result, TorrentError},
+    mse,
+    peer::{accept_handshake, PeerStream, RemoteHandshake, HANDSHAKE_TIMEOUT},
+    swarm::ConnectionSlots,
+};
+
+/// Peer that connected to us, past the MSE negotiation and the BitTorrent
+/// handshakes
+pub struct IncomingPeer {
+    pub addr: SocketAddr,
+    /// Swarm the peer asked for, hybrid torrents have two
+    pub infohash: [u8; 20],
+    pub stream: PeerStream,
+    pub remote: RemoteHandshake,
+}
+
+/// Accepts connections from peers on the port announced to trackers, over TCP
+/// and uTP, and hands them to the torrent of the swarm they ask for
+pub struct PeerListener {
+    shared: Arc<ListenerShared>,
+    task: OnceLock<JoinHandle<()>>,
+}
+
+/// Torrents accepting peers, by swarm infohash
+type Swarms = HashMap<[u8; 20], mpsc::Sender<IncomingPeer>>;
+
+struct ListenerShared {
+    slots: ConnectionSlots,
+    swarms: StdMutex<Swarms>,
+}
+
+impl ListenerShared {
+    fn swarms(&self) -> std::sync::MutexGuard<'_, Swarms> {
+        self.swarms.lock().expect("listener swarms lock poisoned")
+    }
+}
+
+impl PeerListener {
+    /// Listens on the port of `slots.transports`, uTP shares its socket with
+    /// outgoing connections
+    pub fn new(slots: ConnectionSlots) -> Self {
+        PeerListener {
+            shared: Arc::new(ListenerShared {
+                slots,
+                swarms: StdMutex::new(HashMap::new()),
+            }),
+            task: OnceLock::new(),
+        }
+    }
+
+    /// Send peers connecting for the swarm `infohash` to `peers`. Peers are
+    /// disconnected when it is full. The port is opened with the first swarm.
+    pub fn add(&self, infohash: [u8; 20], peers: mpsc::Sender<IncomingPeer>) {
+        self.shared.swarms().insert(infohash, peers);
+        self.task
+            .get_or_init(|| tokio::spawn(run(self.shared.clone())));
+    }
+
+    pub fn remove(&self, infohash: &[u8; 20]) {
+        self.shared.swarms().remove(infohash);
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get() {
+            task.abort();
+        }
+    }
+}
+
+async fn run(shared: Arc<ListenerShared>) {
+    tokio::join!(accept_tcp(&shared), accept_utp(&shared));
+}
+
+async fn accept_tcp(shared: &Arc<ListenerShared>) {
+    let port = shared.slots.transports.port;
+    // Another client may hold the port, we then only connect to peers
+    let Ok(listener) = TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)).await
+    else {
+        return;
+    };
+    loop {
+        // Errors like too many open files are per connection
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let _ = accept(&shared, PeerStream::Tcp(stream), addr).await;
+        });
+    }
+}
+
+async fn accept_utp(shared: &Arc<ListenerShared>) {
+    let Ok(socket) = shared
+        .slots
+        .transports
+        .utp_socket(Ipv4Addr::UNSPECIFIED.into())
+        .await
+    else {
+        return;
+    };
+    while let Ok(stream) = socket.accept().await {
+        let addr = stream.peer_addr();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let _ = accept(&shared, PeerStream::Utp(stream), addr).await;
+        });
+    }
+}
+
+/// Negotiate encryption as the policy asks, exchange handshakes and hand the
+/// connection to its torrent
+async fn accept(shared: &ListenerShared, stream: PeerStream, addr: SocketAddr) -> Result<()> {
+    let half_open = shared.slots.acquire_half_open().await;
+    let infohashes: Vec<[u8; 20]> = shared.swarms().keys().copied().collect();
+    let (stream, infohash, remote) = time::timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake(stream, &infohashes, &shared.slots),
+    )
+    .await
+    .map_err(|_| TorrentError::PeerProtocol("timed out accepting peer".to_string()))??;
+    drop(half_open);
+
+    let peers = shared.swarms().get(&infohash).cloned().ok_or_else(|| {
+        TorrentError::PeerProtocol("torrent stopped while accepting peer".to_string())
+    })?;
+    peers
+        .try_send(IncomingPeer {
+            addr,
+            infohash,
+            stream,
+            remote,
+        })
+        .map_err(|_| TorrentError::PeerProtocol("torrent is not accepting peers".to_string()))
+}
+
+async fn handshake(
+    stream: PeerStream,
+    infohashes: &[[u8; 20]],
+    slots: &ConnectionSlots,
+) -> Result<(PeerStream, [u8; 20], RemoteHandshake)> {
+    // Plaintext peers are told apart from their first bytes
+    let stream = mse::accept(stream, infohashes, slots.transports.encryption).await?;
+    let mut stream = PeerStream::Mse(Box::new(stream));
+    let (infohash, remote) =
+        accept_handshake(&mut stream, infohashes, &slots.identity.peer_id).await?;
+    Ok((stream, infohash, remote))
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex as StdMutex, OnceLock},
+    time::Duration,
+};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, Notify},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{
+    infohash::{hex_decode, hex_encode},
+    tracker::Peer,
+};
+
+const LSD_PORT: u16 = 6771;
+const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// BEP 14 asks for at most one announce per torrent every minute, every
+/// five minutes is plenty to find peers joining the network
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+/// Infohashes per announce, keeping datagrams under 1400 bytes
+const INFOHASHES_PER_ANNOUNCE: usize = 20;
+const PEER_CAPACITY: usize = 256;
+
+/// Peer of the local network announcing one of our torrents
+#[derive(Debug, Clone)]
+pub struct LocalPeer {
+    pub infohash: [u8; 20],
+    pub peer: Peer,
+}
+
+/// BEP 14 local service discovery: multicasts the infohashes of active
+/// torrents and listens for other clients of the network doing the same
+pub struct LocalDiscovery {
+    shared: Arc<LsdShared>,
+    task: OnceLock<JoinHandle<()>>,
+}
+
+struct LsdShared {
+    /// Recognizes our own announces coming back from the multicast group
+    cookie: String,
+    /// Port peers should connect to
+    port: u16,
+    infohashes: StdMutex<HashSet<[u8; 20]>>,
+    peers: broadcast::Sender<LocalPeer>,
+    /// Wakes the announcer up when a torrent is added
+    added: Notify,
+}
+
+impl LocalDiscovery {
+    pub fn new(port: u16) -> Self {
+        let cookie: u32 = rand::thread_rng().gen();
+        LocalDiscovery {
+            shared: Arc::new(LsdShared {
+                cookie: format!("{:08x}", cookie),
+                port,
+                infohashes: StdMutex::new(HashSet::new()),
+                peers: broadcast::channel(PEER_CAPACITY).0,
+                added: Notify::new(),
+            }),
+            task: OnceLock::new(),
+        }
+    }
+
+    /// Announce `infohash` on the local network and report its peers. The
+    /// sockets are opened with the first torrent.
+    pub fn add(&self, infohash: [u8; 20]) {
+        self.shared
+            .infohashes
+            .lock()
+            .expect("lsd infohashes lock poisoned")
+            .insert(infohash);
+        self.task
+            .get_or_init(|| tokio::spawn(run(self.shared.clone())));
+        self.shared.added.notify_one();
+    }
+
+    pub fn remove(&self, infohash: &[u8; 20]) {
+        self.shared
+            .infohashes
+            .lock()
+            .expect("lsd infohashes lock poisoned")
+            .remove(infohash);
+    }
+
+    /// Peers found from now on, for every announced torrent
+    pub fn subscribe(&self) -> broadcast::Receiver<LocalPeer> {
+        self.shared.peers.subscribe()
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get() {
+            task.abort();
+        }
+    }
+}
+
+async fn run(shared: Arc<LsdShared>) {
+    // Networks without IPv6 or multicast routes are common, any of the two
+    // groups is enough
+    let v4 = bind_v4().ok();
+    let v6 = bind_v6().ok();
+    if v4.is_none() && v6.is_none() {
+        return;
+    }
+
+    let mut announced: HashMap<[u8; 20], Instant> = HashMap::new();
+    let mut buf_v4 = vec![0u8; 1500];
+    let mut buf_v6 = vec![0u8; 1500];
+    let mut tick = time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => announce_due(&shared, &mut announced, &v4, &v6).await,
+            _ = shared.added.notified() => announce_due(&shared, &mut announced, &v4, &v6).await,
+            Some((length, from)) = recv(&v4, &mut buf_v4) => {
+                handle_announce(&shared, &buf_v4[..length], from);
+            }
+            Some((length, from)) = recv(&v6, &mut buf_v6) => {
+                handle_announce(&shared, &buf_v6[..length], from);
+            }
+        }
+    }
+}
+
+fn bind_v4() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other clients of the same machine listen on the same port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_PORT).into())?;
+    socket.join_multicast_v4(&LSD_GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_v6() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, LSD_PORT, 0, 0).into())?;
+    socket.join_multicast_v6(&LSD_GROUP_V6, 0)?;
+    socket.set_multicast_loop_v6(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Next datagram of `socket`, never resolving without one
+async fn recv(socket: &Option<UdpSocket>, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+/// Announce the torrents that were never announced or not for a while
+async fn announce_due(
+    shared: &LsdShared,
+    announced: &mut HashMap<[u8; 20], Instant>,
+    v4: &Option<UdpSocket>,
+    v6: &Option<UdpSocket>,
+) {
+    let infohashes = shared
+        .infohashes
+        .lock()
+        .expect("lsd infohashes lock poisoned")
+        .clone();
+    announced.retain(|infohash, _| infohashes.contains(infohash));
+    let now = Instant::now();
+    let due: Vec<[u8; 20]> = infohashes
+        .into_iter()
+        .filter(|infohash| {
+            announced
+                .get(infohash)
+                .is_none_or(|at| now.duration_since(*at) >= ANNOUNCE_INTERVAL)
+        })
+        .collect();
+
+    for chunk in due.chunks(INFOHASHES_PER_ANNOUNCE) {
+        // A failed send is retried with the next announce
+        if let Some(socket) = v4 {
+            let host = SocketAddr::from((LSD_GROUP_V4, LSD_PORT));
+            let _ = socket
+                .send_to(&announce_message(shared, &host, chunk), host)
+                .await;
+        }
+        if let Some(socket) = v6 {
+            let host = SocketAddr::from((LSD_GROUP_V6, LSD_PORT));
+            let _ = socket
+                .send_to(&announce_message(shared, &host, chunk), host)
+                .await;
+        }
+        for infohash in chunk {
+            announced.insert(*infohash, now);
+        }
+    }
+}
+
+fn announce_message(shared: &LsdShared, host: &SocketAddr, infohashes: &[[u8; 20]]) -> Vec<u8> {
+    let mut message = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+        host, shared.port
+    );
+    for infohash in infohashes {
+        message.push_str(&format!("Infohash: {}\r\n", hex_encode(infohash)));
+    }
+    message.push_str(&format!("cookie: {}\r\n\r\n\r\n", shared.cookie));
+    message.into_bytes()
+}
+
+fn handle_announce(shared: &LsdShared, datagram: &[u8], from: SocketAddr) {
+    let Some(announce) = parse_announce(datagram) else {
+        return;
+    };
+    if announce.cookie.as_deref() == Some(shared.cookie.as_str()) {
+        return;
+    }
+    let infohashes = shared
+        .infohashes
+        .lock()
+        .expect("lsd infohashes lock poisoned");
+    for infohash in announce.infohashes {
+        if infohashes.contains(&infohash) {
+            // Nobody listening just means no torrent is downloading
+            let _ = shared.peers.send(LocalPeer {
+                infohash,
+                peer: Peer {
+                    ip: from.ip(),
+                    port: announce.port,
+                },
+            });
+        }
+    }
+}
+
+struct Announce {
+    port: u16,
+    infohashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+/// Headers of a `BT-SEARCH` request, case insensitive as in HTTP
+fn parse_announce(datagram: &[u8]) -> Option<Announce> {
+    let text = std::str::from_utf8(datagram).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let mut port = None;
+    let mut infohashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => {
+                if let Some(Ok(infohash)) = hex_decode(value).map(<[u8; 20]>::try_from) {
+                    infohashes.push(infohash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Announce {
+        port: port.filter(|port| *port != 0)?,
+        infohashes,
+        cookie,
+    })
+}
@@ -0,0 +1,142 @@
+use url::{form_urlencoded, Url};
+
+use crate::{
+    error::{Result, TorrentError},
+    infohash::{base32_decode, hex_decode, hex_encode},
+    torrent_file::TorrentFile,
+};
+
+/// Multihash prefix of SHA-256 digests, used by `urn:btmh` (BEP 52)
+const SHA256_MULTIHASH: &str = "1220";
+
+/// Parsed `magnet:` URI
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    /// The v1 infohash, or the v2 one truncated to 20 bytes for v2 only links
+    pub infohash: [u8; 20],
+    /// From `urn:btmh`, for v2 and hybrid torrents
+    pub infohash_v2: Option<[u8; 32]>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    /// BEP 19 web seeds, from `ws` parameters
+    pub web_seeds: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)
+            .map_err(|e| TorrentError::Metainfo(format!("invalid magnet link: {}", e)))?;
+        if url.scheme() != "magnet" {
+            return Err(TorrentError::Metainfo(format!(
+                "not a magnet link: {}",
+                uri
+            )));
+        }
+
+        let mut infohash = None;
+        let mut infohash_v2 = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut web_seeds = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(encoded) = value.strip_prefix("urn:btih:") {
+                        infohash = Some(decode_infohash(encoded)?);
+                    } else if let Some(encoded) = value.strip_prefix("urn:btmh:") {
+                        infohash_v2 = Some(decode_infohash_v2(encoded)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "ws" => web_seeds.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let infohash = infohash
+            .or_else(|| infohash_v2.map(|v2| v2[..20].try_into().expect("infohash is 32 bytes")))
+            .ok_or_else(|| {
+                TorrentError::Metainfo(
+                    "magnet link has no urn:btih or urn:btmh infohash".to_string(),
+                )
+            })?;
+        Ok(Magnet {
+            infohash,
+            infohash_v2,
+            display_name,
+            trackers,
+            web_seeds,
+        })
+    }
+
+    pub fn from_torrent_file(torrent_file: &TorrentFile) -> Self {
+        Magnet {
+            infohash: torrent_file.infohash,
+            infohash_v2: torrent_file.infohash_v2,
+            display_name: Some(torrent_file.name.clone()),
+            trackers: torrent_file.trackers.iter().flatten().cloned().collect(),
+            web_seeds: torrent_file.web_seeds.clone(),
+        }
+    }
+
+    /// Whether the link has no v1 infohash
+    pub fn is_v2_only(&self) -> bool {
+        self.infohash_v2
+            .is_some_and(|infohash_v2| infohash_v2[..20] == self.infohash)
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut topics = Vec::new();
+        if !self.is_v2_only() {
+            topics.push(format!("urn:btih:{}", hex_encode(&self.infohash)));
+        }
+        if let Some(infohash_v2) = &self.infohash_v2 {
+            topics.push(format!(
+                "urn:btmh:{}{}",
+                SHA256_MULTIHASH,
+                hex_encode(infohash_v2)
+            ));
+        }
+        let mut uri = "magnet:?".to_string();
+        let params = topics
+            .iter()
+            .map(|topic| ("xt", topic))
+            .chain(self.display_name.iter().map(|name| ("dn", name)))
+            .chain(self.trackers.iter().map(|tracker| ("tr", tracker)))
+            .chain(self.web_seeds.iter().map(|url| ("ws", url)));
+        for (i, (key, value)) in params.enumerate() {
+            if i > 0 {
+                uri.push('&');
+            }
+            uri.push_str(key);
+            uri.push('=');
+            // Topics are URNs, escaping their colons would break some clients
+            if key == "xt" {
+                uri.push_str(value);
+            } else {
+                uri.extend(form_urlencoded::byte_serialize(value.as_bytes()));
+            }
+        }
+        uri
+    }
+}
+
+fn decode_infohash(encoded: &str) -> Result<[u8; 20]> {
+    let bytes = match encoded.len() {
+        40 => hex_decode(encoded),
+        32 => base32_decode(encoded),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| TorrentError::Metainfo(format!("invalid infohash in magnet: {}", encoded)))
+}
+
+fn decode_infohash_v2(encoded: &str) -> Result<[u8; 32]> {
+    encoded
+        .strip_prefix(SHA256_MULTIHASH)
+        .and_then(hex_decode)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| TorrentError::Metainfo(format!("invalid infohash in magnet: {}", encoded)))
+}
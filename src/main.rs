@@ -1,35 +1,614 @@
-pub mod bitfield;
-pub mod controller;
-pub mod infohash;
-pub mod message;
-pub mod peer;
-pub mod torrent_file;
-pub mod tracker;
-pub mod worker;
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Component, PathBuf},
+};
 
-use crate::controller::download_file;
-use crate::torrent_file::read_and_decode;
-use std::env;
+use byte_unit::{Byte, UnitType};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_derive::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use torrent_client::{
+    create::{create_torrent, CreateOptions},
+    infohash::{base32_encode, hex_encode},
+    magnet::Magnet,
+    mse::EncryptionPolicy,
+    peer::TransportPreference,
+    picker::{FilePriority, PickOrder},
+    rate_limit::RateLimits,
+    streaming::StreamingServer,
+    torrent_file::{read_and_decode, MetaVersion},
+    Event, Session, SessionConfig, TorrentFile,
+};
 
-#[macro_use]
-extern crate serde_derive;
+/// Inside the download directory, resume data of interrupted downloads
+const RESUME_DIR: &str = ".resume";
 
-static PORT: u16 = 6881;
-static CLIENT_ID: &str = "d198c9596d8ccf89a0e5";
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Simple BitTorrent client",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    /// Torrent file or magnet link to download
+    source: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a torrent file or magnet link
+    Download {
+        source: String,
+        /// Transports used to connect to peers
+        #[arg(long, value_enum, default_value_t = TransportArg::PreferTcp)]
+        transport: TransportArg,
+        /// Message Stream Encryption of peer connections
+        #[arg(long, value_enum, default_value_t = EncryptionArg::Preferred)]
+        encryption: EncryptionArg,
+        /// Download rate cap per second, like 500KiB or 2MB
+        #[arg(long, value_parser = parse_rate)]
+        download_limit: Option<u64>,
+        /// Upload rate cap per second, only protocol messages are uploaded
+        #[arg(long, value_parser = parse_rate)]
+        upload_limit: Option<u64>,
+        /// File priority as INDEX=skip|low|normal|high, with indexes shown by
+        /// `info`. Can be repeated.
+        #[arg(long = "priority", value_parser = parse_file_priority)]
+        file_priorities: Vec<(usize, FilePriority)>,
+        /// Download pieces in order, to play media while it downloads
+        #[arg(long)]
+        sequential: bool,
+        /// Pieces downloaded at once ahead of the first missing one, in
+        /// sequential mode
+        #[arg(long, default_value_t = 16, requires = "sequential")]
+        lookahead: usize,
+        /// Download the first and last pieces of each file first
+        #[arg(long)]
+        first_last: bool,
+        /// Serve the files over HTTP at this address while they download,
+        /// like 127.0.0.1:8080, pieces players ask for are fetched first
+        #[arg(long, value_name = "ADDR")]
+        http: Option<SocketAddr>,
+    },
+    /// Create a torrent file from a file or directory
+    Create(CreateArgs),
+    /// Print the metadata of a torrent file
+    Info {
+        path: PathBuf,
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TransportArg {
+    Tcp,
+    Utp,
+    PreferTcp,
+    PreferUtp,
+}
+
+impl From<TransportArg> for TransportPreference {
+    fn from(transport: TransportArg) -> Self {
+        match transport {
+            TransportArg::Tcp => TransportPreference::TcpOnly,
+            TransportArg::Utp => TransportPreference::UtpOnly,
+            TransportArg::PreferTcp => TransportPreference::PreferTcp,
+            TransportArg::PreferUtp => TransportPreference::PreferUtp,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EncryptionArg {
+    Disabled,
+    Preferred,
+    Required,
+}
+
+impl From<EncryptionArg> for EncryptionPolicy {
+    fn from(encryption: EncryptionArg) -> Self {
+        match encryption {
+            EncryptionArg::Disabled => EncryptionPolicy::Disabled,
+            EncryptionArg::Preferred => EncryptionPolicy::Preferred,
+            EncryptionArg::Required => EncryptionPolicy::Required,
+        }
+    }
+}
+
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    Byte::parse_str(rate, true)
+        .map(|byte| byte.as_u64())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_file_priority(file_priority: &str) -> Result<(usize, FilePriority), String> {
+    let (index, priority) = file_priority
+        .split_once('=')
+        .ok_or_else(|| "expected INDEX=PRIORITY".to_string())?;
+    let index = index.parse().map_err(|e| format!("invalid index: {}", e))?;
+    let priority = match priority {
+        "skip" => FilePriority::Skip,
+        "low" => FilePriority::Low,
+        "normal" => FilePriority::Normal,
+        "high" => FilePriority::High,
+        _ => return Err(format!("unknown priority {:?}", priority)),
+    };
+    Ok((index, priority))
+}
+
+/// Settings of the download command
+#[derive(Default)]
+struct DownloadOptions {
+    transport: TransportPreference,
+    encryption: EncryptionPolicy,
+    rate_limits: RateLimits,
+    file_priorities: Vec<(usize, FilePriority)>,
+    pick_order: PickOrder,
+    http: Option<SocketAddr>,
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    /// File or directory to share
+    path: PathBuf,
+    /// Where to write the torrent, defaults to <name>.torrent
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Tracker URL, repeat for each tier and separate trackers of a tier with commas
+    #[arg(short = 't', long = "tracker")]
+    trackers: Vec<String>,
+    /// In bytes, picked from the content size by default
+    #[arg(long)]
+    piece_length: Option<usize>,
+    #[arg(long)]
+    comment: Option<String>,
+    #[arg(long)]
+    created_by: Option<String>,
+    /// Leave the creation date out, for reproducible torrents
+    #[arg(long)]
+    no_creation_date: bool,
+    /// Restrict peers to the ones given by trackers (BEP 27)
+    #[arg(long)]
+    private: bool,
+    /// Web seed URL (BEP 19), can be repeated
+    #[arg(long = "web-seed")]
+    web_seeds: Vec<String>,
+}
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
+    match (cli.command, cli.source) {
+        (
+            Some(Command::Download {
+                source,
+                transport,
+                encryption,
+                download_limit,
+                upload_limit,
+                file_priorities,
+                sequential,
+                lookahead,
+                first_last,
+                http,
+            }),
+            _,
+        ) => {
+            let options = DownloadOptions {
+                transport: transport.into(),
+                encryption: encryption.into(),
+                rate_limits: RateLimits {
+                    download: download_limit,
+                    upload: upload_limit,
+                },
+                file_priorities,
+                pick_order: PickOrder {
+                    sequential: sequential.then_some(lookahead),
+                    first_last_pieces: first_last,
+                },
+                http,
+            };
+            download(&source, options).await
+        }
+        (None, Some(source)) => download(&source, DownloadOptions::default()).await,
+        (Some(Command::Create(args)), _) => create(args),
+        (Some(Command::Info { path, json }), _) => info(path, json),
+        (None, None) => {
+            eprintln!("provide a torrent file's path or a magnet link, see --help");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn create(args: CreateArgs) {
+    let defaults = CreateOptions::default();
+    let options = CreateOptions {
+        piece_length: args.piece_length,
+        trackers: args
+            .trackers
+            .iter()
+            .map(|tier| tier.split(',').map(str::to_string).collect())
+            .collect(),
+        comment: args.comment,
+        created_by: args.created_by.or(defaults.created_by),
+        creation_date: defaults.creation_date.filter(|_| !args.no_creation_date),
+        private: args.private,
+        web_seeds: args.web_seeds,
+    };
 
-    // Get torrent file name as parameter
-    if args.len() != 2 {
-        eprintln!("provide one only argument, a torrent file's path");
+    let bytes = match create_torrent(&args.path, &options) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("error creating torrent:\n{}", e);
+            std::process::exit(1);
+        }
+    };
+    let output = args.output.unwrap_or_else(|| {
+        let name = args.path.file_name().unwrap_or_default().to_string_lossy();
+        PathBuf::from(format!("{}.torrent", name))
+    });
+    if let Err(e) = fs::write(&output, bytes) {
+        eprintln!("error writing {:?}:\n{}", output, e);
         std::process::exit(1);
     }
-    let torrent_file_name = &args[1];
+    println!("torrent created at {:?}", output);
+}
+
+#[derive(Serialize)]
+struct TorrentInfo {
+    name: String,
+    infohash: String,
+    infohash_base32: String,
+    meta_version: MetaVersion,
+    infohash_v2: Option<String>,
+    piece_count: usize,
+    piece_length: usize,
+    total_length: usize,
+    files: Vec<TorrentInfoFile>,
+    trackers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    magnet: String,
+}
+
+#[derive(Serialize)]
+struct TorrentInfoFile {
+    /// Position among the torrent's files, padding files included
+    index: usize,
+    path: PathBuf,
+    length: usize,
+    executable: bool,
+    symlink: Option<PathBuf>,
+}
+
+impl TorrentInfo {
+    fn new(torrent_file: TorrentFile) -> Self {
+        TorrentInfo {
+            infohash: hex_encode(&torrent_file.infohash),
+            infohash_base32: base32_encode(&torrent_file.infohash),
+            meta_version: torrent_file.meta_version,
+            infohash_v2: torrent_file
+                .infohash_v2
+                .map(|infohash| hex_encode(&infohash)),
+            piece_count: torrent_file.num_pieces(),
+            piece_length: torrent_file.piece_length,
+            total_length: torrent_file.length,
+            files: torrent_file
+                .files
+                .iter()
+                .enumerate()
+                // Padding only exists to align pieces
+                .filter(|(_, file)| !file.attributes.padding)
+                .map(|(index, file)| TorrentInfoFile {
+                    index,
+                    path: file.path.clone(),
+                    length: file.length,
+                    executable: file.attributes.executable,
+                    symlink: file.attributes.symlink.clone(),
+                })
+                .collect(),
+            magnet: Magnet::from_torrent_file(&torrent_file).to_uri(),
+            name: torrent_file.name,
+            trackers: torrent_file.trackers,
+            web_seeds: torrent_file.web_seeds,
+            http_seeds: torrent_file.http_seeds,
+            private: torrent_file.private,
+            comment: torrent_file.comment,
+            created_by: torrent_file.created_by,
+            creation_date: torrent_file.creation_date,
+        }
+    }
+}
+
+fn info(path: PathBuf, json: bool) {
+    let info = match read_and_decode(&path) {
+        Ok(torrent_file) => TorrentInfo::new(torrent_file),
+        Err(e) => {
+            eprintln!("error reading torrent:\n{}", e);
+            std::process::exit(1);
+        }
+    };
+    if json {
+        match serde_json::to_string_pretty(&info) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("error encoding JSON:\n{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    println!("name: {}", info.name);
+    println!(
+        "infohash: {} (base32 {})",
+        info.infohash, info.infohash_base32
+    );
+    if let Some(infohash_v2) = &info.infohash_v2 {
+        println!("infohash v2: {}", infohash_v2);
+    }
+    let meta_version = match info.meta_version {
+        MetaVersion::V1 => "v1",
+        MetaVersion::V2 => "v2",
+        MetaVersion::Hybrid => "hybrid v1 and v2",
+    };
+    println!("version: {}", meta_version);
+    println!(
+        "pieces: {} of {}",
+        info.piece_count,
+        format_size(info.piece_length)
+    );
+    println!("total size: {}", format_size(info.total_length));
+    println!("private: {}", if info.private { "yes" } else { "no" });
+    if let Some(creation_date) = info.creation_date {
+        println!("created on: {}", format_timestamp(creation_date));
+    }
+    if let Some(created_by) = &info.created_by {
+        println!("created by: {}", created_by);
+    }
+    if let Some(comment) = &info.comment {
+        println!("comment: {}", comment);
+    }
 
-    // Read and decode torrent file
-    let torrent_file = read_and_decode(torrent_file_name);
+    if !info.trackers.is_empty() {
+        println!("trackers:");
+        for (tier, trackers) in info.trackers.iter().enumerate() {
+            println!("  tier {}:", tier + 1);
+            for tracker in trackers {
+                println!("    {}", tracker);
+            }
+        }
+    }
+    if !info.web_seeds.is_empty() {
+        println!("web seeds:");
+        for url in &info.web_seeds {
+            println!("  {}", url);
+        }
+    }
+    if !info.http_seeds.is_empty() {
+        println!("http seeds:");
+        for url in &info.http_seeds {
+            println!("  {}", url);
+        }
+    }
+
+    // Files are listed in torrent order, a directory is printed whenever
+    // it differs from the previous file's
+    println!("files:");
+    let mut current_dirs: Vec<Component> = Vec::new();
+    for file in &info.files {
+        let components: Vec<Component> = file.path.components().collect();
+        let (file_name, dirs) = components.split_last().expect("file paths are not empty");
+        let common = current_dirs
+            .iter()
+            .zip(dirs)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            println!(
+                "{}{}/",
+                "  ".repeat(depth + 1),
+                dir.as_os_str().to_string_lossy()
+            );
+        }
+        let details = match &file.symlink {
+            Some(target) => format!("-> {}", target.display()),
+            None if file.executable => format!("({}, executable)", format_size(file.length)),
+            None => format!("({})", format_size(file.length)),
+        };
+        println!(
+            "{}[{}] {} {}",
+            "  ".repeat(dirs.len() + 1),
+            file.index,
+            file_name.as_os_str().to_string_lossy(),
+            details
+        );
+        current_dirs = dirs.to_vec();
+    }
+
+    println!("magnet: {}", info.magnet);
+}
 
-    download_file(&torrent_file).await;
+fn format_size(bytes: usize) -> String {
+    format!(
+        "{:.2}",
+        Byte::from_u64(bytes as u64).get_appropriate_unit(UnitType::Binary)
+    )
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC` for a Unix timestamp
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+    // Civil date from days since 1970-01-01, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+async fn download(source: &str, options: DownloadOptions) {
+    let download_dir = env::current_dir().unwrap_or(env::temp_dir());
+    let session = Session::new(SessionConfig {
+        download_dir: download_dir.clone(),
+        transport: options.transport,
+        encryption: options.encryption,
+        rate_limits: options.rate_limits,
+        resume_dir: Some(download_dir.join(RESUME_DIR)),
+        ..Default::default()
+    });
+    let mut events = session.subscribe();
+
+    let added = if source.starts_with("magnet:") {
+        session.add_magnet(source)
+    } else {
+        read_and_decode(source).map(|torrent_file| session.add_torrent(torrent_file))
+    };
+    let handle = match added {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("error reading torrent:\n{}", e);
+            std::process::exit(1);
+        }
+    };
+    for (index, priority) in options.file_priorities {
+        if let Err(e) = handle.set_file_priority(index, priority).await {
+            eprintln!("error setting file priority:\n{}", e);
+            std::process::exit(1);
+        }
+    }
+    handle.set_pick_order(options.pick_order).await;
+
+    let server = match options.http {
+        Some(addr) => match session.start_streaming(addr).await {
+            Ok(server) => Some(server),
+            Err(e) => {
+                eprintln!("error starting the HTTP server:\n{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut connected_peers = 0;
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = &mut shutdown => {
+                println!("stopping, progress is saved for the next run");
+                session.shutdown().await;
+                return;
+            }
+        };
+        match event {
+            Ok(Event::PeerConnected { peer, .. }) => {
+                connected_peers += 1;
+                println!(
+                    "connected peers: {}, new connection: {}",
+                    connected_peers, peer
+                );
+            }
+            Ok(Event::PeerDisconnected { peer, .. }) => {
+                connected_peers -= 1;
+                println!(
+                    "connected peers: {}, connection stopped: {}",
+                    connected_peers, peer
+                );
+            }
+            Ok(Event::MetadataReceived { .. }) => {
+                if let (Some(server), Some(torrent_file)) = (&server, handle.torrent_file()) {
+                    print_stream_urls(server, &torrent_file);
+                }
+            }
+            Ok(Event::WebSeedFailed { url, error, .. }) => {
+                println!("web seed {} failed: {}", url, error);
+            }
+            Ok(Event::Progress {
+                verified_pieces,
+                total_pieces,
+                download_rate,
+                ..
+            }) => {
+                let speed = Byte::from_u64(download_rate).get_appropriate_unit(UnitType::Decimal);
+                println!(
+                    "{:.2}/s, {}/{} pieces",
+                    speed, verified_pieces, total_pieces
+                );
+            }
+            Ok(Event::TorrentFinished { .. }) => {
+                let name = handle
+                    .torrent_file()
+                    .map(|torrent_file| torrent_file.name)
+                    .unwrap_or_default();
+                println!(
+                    "file downloaded successfully to {:?}",
+                    download_dir.join(name)
+                );
+                if server.is_none() {
+                    return;
+                }
+                println!("still serving over HTTP, press Ctrl-C to stop");
+            }
+            Ok(Event::TorrentFailed { error, .. }) => {
+                eprintln!("download failed:\n{}", error);
+                std::process::exit(1);
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+fn print_stream_urls(server: &StreamingServer, torrent_file: &TorrentFile) {
+    for (index, file) in torrent_file.files.iter().enumerate() {
+        if !file.attributes.padding {
+            println!(
+                "streaming {:?} at {}",
+                file.path,
+                server.url(&torrent_file.infohash, index)
+            );
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
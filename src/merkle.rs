@@ -0,0 +1,102 @@
+use sha2::{Digest, Sha256};
+
+/// Data covered by each leaf of BEP 52 merkle trees
+pub const MERKLE_BLOCK_SIZE: usize = 16384;
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    <Sha256 as Digest>::digest(data).into()
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Leaf hashes of `data`, the last block may be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+/// Root of a tree of `width` leaves, `width` being a power of two. Leaves
+/// past the end of `leaves` are `padding`.
+pub fn merkle_root(leaves: &[[u8; 32]], width: usize, padding: [u8; 32]) -> [u8; 32] {
+    let mut layer = leaves.to_vec();
+    let mut padding = padding;
+    let mut width = width;
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&padding)))
+            .collect();
+        padding = hash_pair(&padding, &padding);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(padding)
+}
+
+/// Root of a subtree of `width` leaves past the end of a file, where leaves
+/// are all zeroes
+pub fn padding_hash(width: usize) -> [u8; 32] {
+    merkle_root(&[], width, [0u8; 32])
+}
+
+/// Content of a BEP 52 hashes message: `length` nodes of `layer` starting at
+/// `index`, followed by the uncle hashes needed to verify them up to
+/// `proof_layers` layers above `layer`. Uncles that can be computed from the
+/// nodes themselves are left out. None for requests outside of the tree.
+pub fn hashes_with_proof(
+    layer: &[[u8; 32]],
+    padding: [u8; 32],
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<[u8; 32]>> {
+    let width = layer.len().next_power_of_two();
+    let height = width.trailing_zeros() as usize;
+    if !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || index + length > width
+        || proof_layers > height
+    {
+        return None;
+    }
+
+    let mut nodes = layer.to_vec();
+    nodes.resize(width, padding);
+    let mut hashes = nodes[index..index + length].to_vec();
+    let subtree_height = length.trailing_zeros() as usize;
+    for level in 0..proof_layers {
+        if level >= subtree_height {
+            hashes.push(nodes[(index >> level) ^ 1]);
+        }
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    Some(hashes)
+}
+
+/// Root of the tree proven by the content of a BEP 52 hashes message: its
+/// first `length` hashes are nodes starting at `index`, the others are the
+/// uncle hashes up to the root. None when there are fewer than `length`.
+pub fn proof_root(hashes: &[[u8; 32]], index: usize, length: usize) -> Option<[u8; 32]> {
+    if hashes.len() < length || !length.is_power_of_two() || !index.is_multiple_of(length) {
+        return None;
+    }
+    let (nodes, uncles) = hashes.split_at(length);
+    // Nodes past the end of the file are sent too, they fill the subtree
+    let mut root = merkle_root(nodes, length, [0u8; 32]);
+    let mut position = index / length;
+    for uncle in uncles {
+        root = if position.is_multiple_of(2) {
+            hash_pair(&root, uncle)
+        } else {
+            hash_pair(uncle, &root)
+        };
+        position /= 2;
+    }
+    Some(root)
+}
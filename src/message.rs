@@ -1,6 +1,6 @@
 use std::io::Write;
 
-use anyhow::{anyhow, Result};
+use crate::error::{Result, TorrentError};
 
 #[derive(Debug)]
 pub enum Message {
@@ -14,6 +14,49 @@ pub enum Message {
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
     Cancel(u32, u32, u32),
+    /// BEP 10 extension message: extension id and bencoded payload
+    Extended(u8, Vec<u8>),
+    /// BEP 52 request for hashes of a file's merkle tree
+    HashRequest(HashRequest),
+    /// Requested hashes followed by their uncle hashes
+    Hashes(HashRequest, Vec<[u8; 32]>),
+    HashReject(HashRequest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    /// Layer of the requested hashes, 0 being the 16 KiB blocks
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    /// Layers above the base one to send uncle hashes for
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LENGTH: usize = 48;
+
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_all(&self.pieces_root)?;
+        buf.write_all(&self.base_layer.to_be_bytes())?;
+        buf.write_all(&self.index.to_be_bytes())?;
+        buf.write_all(&self.length.to_be_bytes())?;
+        buf.write_all(&self.proof_layers.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let field =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        HashRequest {
+            pieces_root: bytes[..32].try_into().expect("hash request is 48 bytes"),
+            base_layer: field(32),
+            index: field(36),
+            length: field(40),
+            proof_layers: field(44),
+        }
+    }
 }
 
 impl Message {
@@ -65,6 +108,31 @@ impl Message {
                 buf.write_all(&begin.to_be_bytes())?;
                 buf.write_all(&length.to_be_bytes())?;
             }
+            Message::Extended(id, payload) => {
+                let length = 2 + payload.len() as u32;
+                buf.write_all(&length.to_be_bytes())?;
+                buf.write_all(&[20, *id])?;
+                buf.write_all(payload)?;
+            }
+            Message::HashRequest(request) => {
+                buf.write_all(&(1 + HashRequest::LENGTH as u32).to_be_bytes())?;
+                buf.write_all(&[21])?;
+                request.write_to(&mut buf)?;
+            }
+            Message::Hashes(request, hashes) => {
+                let length = 1 + HashRequest::LENGTH as u32 + 32 * hashes.len() as u32;
+                buf.write_all(&length.to_be_bytes())?;
+                buf.write_all(&[22])?;
+                request.write_to(&mut buf)?;
+                for hash in hashes {
+                    buf.write_all(hash)?;
+                }
+            }
+            Message::HashReject(request) => {
+                buf.write_all(&(1 + HashRequest::LENGTH as u32).to_be_bytes())?;
+                buf.write_all(&[23])?;
+                request.write_to(&mut buf)?;
+            }
         }
         Ok(buf)
     }
@@ -99,7 +167,26 @@ impl Message {
                 let length = u32::from_be_bytes([rest[8], rest[9], rest[10], rest[11]]);
                 Ok(Message::Cancel(index, begin, length))
             }
-            _ => Err(anyhow!("Unsupported message format")),
+            [_, _, _, _, 20, id, rest @ ..] => Ok(Message::Extended(*id, rest.to_vec())),
+            [_, _, _, _, 21, rest @ ..] if rest.len() == HashRequest::LENGTH => {
+                Ok(Message::HashRequest(HashRequest::from_bytes(rest)))
+            }
+            [_, _, _, _, 22, rest @ ..]
+                if rest.len() >= HashRequest::LENGTH
+                    && (rest.len() - HashRequest::LENGTH).is_multiple_of(32) =>
+            {
+                let hashes = rest[HashRequest::LENGTH..]
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+                    .collect();
+                Ok(Message::Hashes(HashRequest::from_bytes(rest), hashes))
+            }
+            [_, _, _, _, 23, rest @ ..] if rest.len() == HashRequest::LENGTH => {
+                Ok(Message::HashReject(HashRequest::from_bytes(rest)))
+            }
+            _ => Err(TorrentError::PeerProtocol(
+                "unsupported message format".to_string(),
+            )),
         }
     }
 }
@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{task::JoinSet, time};
+
+use crate::{
+    bencode::value_end,
+    error::{Result, TorrentError},
+    extension::{
+        ExtensionHandshake, MetadataMessage, HANDSHAKE_ID, METADATA_DATA, METADATA_REJECT,
+        METADATA_REQUEST, UT_METADATA_ID,
+    },
+    magnet::Magnet,
+    merkle::{proof_root, sha256, MERKLE_BLOCK_SIZE},
+    message::{HashRequest, Message},
+    peer::{handshake, PeerStream},
+    swarm::ConnectionSlots,
+    torrent_file::{required_piece_layers, RequiredLayer, TorrentFile},
+    tracker::{announce, AnnounceStats, Peer},
+    worker::{read_message, write_message},
+};
+
+const METADATA_PIECE_SIZE: usize = 16384;
+/// Refuse metadata bigger than this, real ones are well under it
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Peers asked for metadata at the same time
+const MAX_PARALLEL_PEERS: usize = 8;
+/// Trackers may not return peers to clients that have nothing left
+const ANNOUNCE_LEFT: usize = 1;
+/// Hashes asked for in a single request, peers reject bigger ones (BEP 52)
+const MAX_HASHES_PER_REQUEST: usize = 512;
+
+/// Resolve a magnet link into a torrent by downloading its info dictionary
+/// from peers of the swarm (BEP 9)
+pub async fn fetch_metadata(magnet: &Magnet, slots: &ConnectionSlots) -> Result<TorrentFile> {
+    let announce_url = magnet
+        .trackers
+        .first()
+        .ok_or_else(|| TorrentError::Metainfo("magnet link has no tracker".to_string()))?;
+
+    let mut peers = Vec::new();
+    let mut last_error = None;
+    for tracker in &magnet.trackers {
+        match announce(
+            tracker,
+            &magnet.infohash,
+            AnnounceStats {
+                left: ANNOUNCE_LEFT,
+                ..Default::default()
+            },
+            &slots.identity,
+            None,
+        )
+        .await
+        {
+            Ok(announced) => peers.extend(announced.peers),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if peers.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            TorrentError::Tracker("no peers available for metadata".to_string())
+        }));
+    }
+
+    let mut peers = peers.into_iter();
+    let mut attempts = JoinSet::new();
+    loop {
+        while attempts.len() < MAX_PARALLEL_PEERS {
+            let Some(peer) = peers.next() else {
+                break;
+            };
+            let infohash = magnet.infohash;
+            // Without a v1 infohash the metadata can only be checked with SHA-256
+            let infohash_v2 = magnet.infohash_v2.filter(|_| magnet.is_v2_only());
+            let announce_url = announce_url.clone();
+            let slots = slots.clone();
+            attempts.spawn(async move {
+                time::timeout(
+                    PEER_TIMEOUT,
+                    fetch_from_peer(&peer, &infohash, infohash_v2.as_ref(), announce_url, &slots),
+                )
+                .await
+                .map_err(|_| {
+                    TorrentError::PeerProtocol("timed out fetching metadata".to_string())
+                })?
+            });
+        }
+
+        match attempts.join_next().await {
+            Some(Ok(Ok(mut torrent_file))) => {
+                torrent_file.web_seeds = magnet.web_seeds.clone();
+                // Each tracker of the link is a tier of its own
+                torrent_file.trackers = magnet
+                    .trackers
+                    .iter()
+                    .map(|tracker| vec![tracker.clone()])
+                    .collect();
+                return Ok(torrent_file);
+            }
+            Some(Ok(Err(e))) => last_error = Some(e),
+            Some(Err(_)) => {}
+            None => {
+                return Err(last_error.unwrap_or_else(|| {
+                    TorrentError::PeerProtocol("no peer sent metadata".to_string())
+                }))
+            }
+        }
+    }
+}
+
+async fn fetch_from_peer(
+    peer: &Peer,
+    infohash: &[u8; 20],
+    infohash_v2: Option<&[u8; 32]>,
+    announce: String,
+    slots: &ConnectionSlots,
+) -> Result<TorrentFile> {
+    let (mut stream, remote) =
+        handshake(peer, infohash, &slots.transports, &slots.identity.peer_id).await?;
+    write_message(
+        &mut stream,
+        &Message::Extended(HANDSHAKE_ID, ExtensionHandshake::new().to_bytes()?),
+    )
+    .await?;
+
+    // Wait for the peer's extension handshake
+    let (peer_metadata_id, metadata_size) = loop {
+        if let Message::Extended(HANDSHAKE_ID, payload) = read_message(&mut stream).await? {
+            let peer_handshake = ExtensionHandshake::from_bytes(&payload)?;
+            let id = peer_handshake.extension_id("ut_metadata").ok_or_else(|| {
+                TorrentError::PeerProtocol("peer does not support ut_metadata".to_string())
+            })?;
+            let size = peer_handshake
+                .metadata_size
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|size| *size > 0 && *size <= MAX_METADATA_SIZE)
+                .ok_or_else(|| {
+                    TorrentError::PeerProtocol("invalid metadata size from peer".to_string())
+                })?;
+            break (id, size);
+        }
+    };
+
+    let num_pieces = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..num_pieces {
+        request_piece(&mut stream, peer_metadata_id, piece).await?;
+    }
+
+    let mut metadata = vec![0u8; metadata_size];
+    let mut received = vec![false; num_pieces];
+    while received.contains(&false) {
+        let Message::Extended(UT_METADATA_ID, payload) = read_message(&mut stream).await? else {
+            continue;
+        };
+        let header_end = value_end(&payload, 0)?;
+        let header = de::from_bytes::<MetadataMessage>(&payload[..header_end]).map_err(|e| {
+            TorrentError::PeerProtocol(format!("error decoding metadata message: {}", e))
+        })?;
+        match header.msg_type {
+            METADATA_DATA => {
+                let piece = usize::try_from(header.piece)
+                    .ok()
+                    .filter(|piece| *piece < num_pieces)
+                    .ok_or_else(|| {
+                        TorrentError::PeerProtocol("invalid metadata piece".to_string())
+                    })?;
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = std::cmp::min(start + METADATA_PIECE_SIZE, metadata_size);
+                let data = &payload[header_end..];
+                if data.len() != end - start {
+                    return Err(TorrentError::PeerProtocol(
+                        "metadata piece has wrong length".to_string(),
+                    ));
+                }
+                metadata[start..end].copy_from_slice(data);
+                received[piece] = true;
+            }
+            METADATA_REJECT => {
+                return Err(TorrentError::PeerProtocol(
+                    "peer rejected metadata request".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    let matches = match infohash_v2 {
+        Some(infohash_v2) => sha256(&metadata) == *infohash_v2,
+        None => <Sha1 as Digest>::digest(&metadata).as_slice() == infohash,
+    };
+    if !matches {
+        return Err(TorrentError::PeerProtocol(
+            "metadata does not match infohash".to_string(),
+        ));
+    }
+
+    let piece_layers = if remote.supports_v2() {
+        fetch_piece_layers(&mut stream, &metadata).await
+    } else {
+        Err(TorrentError::PeerProtocol(
+            "peer does not support BitTorrent v2".to_string(),
+        ))
+    };
+    // Only v2 torrents need the piece layers, hybrid ones do without
+    TorrentFile::from_metadata(&metadata, announce, piece_layers.as_ref().ok().cloned())
+        .map_err(|e| piece_layers.err().unwrap_or(e))
+}
+
+/// Piece layers of the files of a v2 or hybrid info dictionary that are
+/// bigger than a piece, requested from the peer that sent it (BEP 52). Each
+/// reply is checked against the pieces root of its file.
+pub async fn fetch_piece_layers(
+    stream: &mut PeerStream,
+    info_bytes: &[u8],
+) -> Result<BTreeMap<ByteBuf, ByteBuf>> {
+    let (piece_length, required) = required_piece_layers(info_bytes)?;
+    let piece_width = piece_length / MERKLE_BLOCK_SIZE;
+    let mut piece_layers = BTreeMap::new();
+    for RequiredLayer {
+        pieces_root,
+        num_pieces,
+    } in required
+    {
+        // Hashes past the end of the file fill the layer up to a power of two
+        let width = num_pieces.next_power_of_two();
+        let length = std::cmp::min(width, MAX_HASHES_PER_REQUEST);
+        let mut layer = Vec::with_capacity(num_pieces * 32);
+        for index in (0..num_pieces).step_by(length) {
+            let request = HashRequest {
+                pieces_root,
+                base_layer: piece_width.trailing_zeros(),
+                index: u32::try_from(index).expect("too many pieces"),
+                length: length as u32,
+                proof_layers: width.trailing_zeros(),
+            };
+            write_message(stream, &Message::HashRequest(request)).await?;
+            let hashes = loop {
+                match read_message(stream).await? {
+                    Message::Hashes(reply, hashes) if reply == request => break hashes,
+                    Message::HashReject(reply) if reply == request => {
+                        return Err(TorrentError::PeerProtocol(
+                            "peer rejected hash request".to_string(),
+                        ))
+                    }
+                    _ => {}
+                }
+            };
+            // Uncle hashes lead from the requested nodes up to the root
+            if proof_root(&hashes, index, length) != Some(pieces_root) {
+                return Err(TorrentError::PeerProtocol(
+                    "hashes do not match their pieces root".to_string(),
+                ));
+            }
+            let received = std::cmp::min(length, num_pieces - index);
+            layer.extend(hashes[..received].iter().flatten());
+        }
+        piece_layers.insert(ByteBuf::from(pieces_root.to_vec()), ByteBuf::from(layer));
+    }
+    Ok(piece_layers)
+}
+
+async fn request_piece(stream: &mut PeerStream, peer_metadata_id: u8, piece: usize) -> Result<()> {
+    let request = MetadataMessage {
+        msg_type: METADATA_REQUEST,
+        piece: piece as i64,
+        total_size: None,
+    };
+    let payload = ser::to_bytes(&request).map_err(|e| {
+        TorrentError::PeerProtocol(format!("error encoding metadata request: {}", e))
+    })?;
+    write_message(stream, &Message::Extended(peer_metadata_id, payload)).await
+}
@@ -0,0 +1,519 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::error::{Result, TorrentError};
+
+/// 768 bit safe prime of the Diffie-Hellman exchange, the generator is 2
+const PRIME: [u8; KEY_LEN] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const KEY_LEN: usize = 96;
+/// Private keys of 160 bits are as strong as the 768 bit group allows
+const PRIVATE_KEY_LEN: usize = 20;
+const MAX_PAD_LEN: usize = 512;
+/// Verification constant, 8 zero bytes
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// RC4 keystream bytes dropped before use, the first ones leak the key
+const RC4_DISCARD: usize = 1024;
+const PSTR: &[u8] = b"BitTorrent protocol";
+
+/// Whether connections are wrapped with Message Stream Encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Plaintext only, encrypted incoming connections are refused
+    Disabled,
+    /// RC4 when the peer supports it, plaintext otherwise
+    #[default]
+    Preferred,
+    /// RC4 only, plaintext peers are refused
+    Required,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Preferred => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Required => CRYPTO_RC4,
+        }
+    }
+
+    /// Method picked among the ones a peer provides
+    fn crypto_select(self, provided: u32) -> Option<u32> {
+        let allowed = provided & self.crypto_provide();
+        if allowed & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if allowed & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+/// Negotiate MSE as the initiator of `stream`, `infohash` being the torrent
+/// the connection is for. The BitTorrent handshake follows on the returned
+/// stream.
+pub async fn initiate<S>(
+    mut stream: S,
+    infohash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let private_key = random_bytes(PRIVATE_KEY_LEN);
+    let mut message = public_key(&private_key).to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message).await?;
+
+    let mut peer_key = [0u8; KEY_LEN];
+    stream.read_exact(&mut peer_key).await?;
+    let secret = shared_secret(&peer_key, &private_key);
+    let mut encrypt = mse_cipher(&hash(&[b"keyA", &secret, infohash]));
+    let mut decrypt = mse_cipher(&hash(&[b"keyB", &secret, infohash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", infohash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut negotiation = VC.to_vec();
+    negotiation.extend(policy.crypto_provide().to_be_bytes());
+    // No padding nor initial payload, the handshake is sent afterwards
+    negotiation.extend(0u16.to_be_bytes());
+    negotiation.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut negotiation);
+    message.extend(negotiation);
+    stream.write_all(&message).await?;
+
+    // The answer starts after the peer's random padding
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    synchronize(&mut stream, &encrypted_vc).await?;
+    let mut answer = [0u8; 6];
+    stream.read_exact(&mut answer).await?;
+    decrypt.apply(&mut answer);
+    let selected = u32::from_be_bytes([answer[0], answer[1], answer[2], answer[3]]);
+    let pad_len = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(handshake_error("padding too long"));
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    match selected {
+        CRYPTO_RC4 if policy != EncryptionPolicy::Disabled => {
+            Ok(MseStream::new(stream, Some((encrypt, decrypt)), Vec::new()))
+        }
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Required => {
+            Ok(MseStream::new(stream, None, Vec::new()))
+        }
+        _ => Err(handshake_error("peer selected a method we don't provide")),
+    }
+}
+
+/// Negotiate MSE as the receiver of an incoming `stream`, for any torrent of
+/// `infohashes`. Plaintext connections are detected from their first bytes
+/// and accepted unless encryption is required.
+pub async fn accept<S>(
+    mut stream: S,
+    infohashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut peer_key = [0u8; KEY_LEN];
+    stream.read_exact(&mut peer_key[..1 + PSTR.len()]).await?;
+    if peer_key[0] as usize == PSTR.len() && peer_key[1..1 + PSTR.len()] == *PSTR {
+        if policy == EncryptionPolicy::Required {
+            return Err(handshake_error("plaintext peer refused"));
+        }
+        return Ok(MseStream::new(
+            stream,
+            None,
+            peer_key[..1 + PSTR.len()].to_vec(),
+        ));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(handshake_error("encrypted peer refused"));
+    }
+    stream.read_exact(&mut peer_key[1 + PSTR.len()..]).await?;
+
+    let private_key = random_bytes(PRIVATE_KEY_LEN);
+    let mut message = public_key(&private_key).to_vec();
+    message.extend(random_pad());
+    stream.write_all(&message).await?;
+
+    let secret = shared_secret(&peer_key, &private_key);
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let infohash = infohashes
+        .iter()
+        .find(|infohash| {
+            let req2 = hash(&[b"req2", infohash.as_slice()]);
+            req2.iter()
+                .zip(req3)
+                .map(|(a, b)| a ^ b)
+                .eq(obfuscated.iter().copied())
+        })
+        .ok_or_else(|| handshake_error("unknown infohash"))?;
+    let mut encrypt = mse_cipher(&hash(&[b"keyB", &secret, infohash]));
+    let mut decrypt = mse_cipher(&hash(&[b"keyA", &secret, infohash]));
+
+    let mut negotiation = [0u8; 14];
+    stream.read_exact(&mut negotiation).await?;
+    decrypt.apply(&mut negotiation);
+    if negotiation[..8] != VC {
+        return Err(handshake_error("wrong verification constant"));
+    }
+    let provided = u32::from_be_bytes([
+        negotiation[8],
+        negotiation[9],
+        negotiation[10],
+        negotiation[11],
+    ]);
+    let pad_len = u16::from_be_bytes([negotiation[12], negotiation[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(handshake_error("padding too long"));
+    }
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let initial_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    // Usually the BitTorrent handshake, read before anything else
+    let mut initial_payload = vec![0u8; initial_len];
+    stream.read_exact(&mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let selected = policy
+        .crypto_select(provided)
+        .ok_or_else(|| handshake_error("no common method"))?;
+    let mut answer = VC.to_vec();
+    answer.extend(selected.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let ciphers = (selected == CRYPTO_RC4).then_some((encrypt, decrypt));
+    Ok(MseStream::new(stream, ciphers, initial_payload))
+}
+
+/// Skip the peer's padding, up to the end of `marker`
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8]) -> Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD_LEN + marker.len());
+    while window.len() < MAX_PAD_LEN + marker.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    Err(handshake_error("no synchronization marker"))
+}
+
+fn handshake_error(reason: &str) -> TorrentError {
+    TorrentError::PeerProtocol(format!("encryption handshake failed: {}", reason))
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill(bytes.as_mut_slice());
+    bytes
+}
+
+fn random_pad() -> Vec<u8> {
+    random_bytes(rand::thread_rng().gen_range(0..=MAX_PAD_LEN))
+}
+
+fn public_key(private_key: &[u8]) -> [u8; KEY_LEN] {
+    let mut generator = [0u8; KEY_LEN];
+    generator[KEY_LEN - 1] = 2;
+    Montgomery::new(&PRIME).pow(&generator, private_key)
+}
+
+fn shared_secret(peer_key: &[u8; KEY_LEN], private_key: &[u8]) -> [u8; KEY_LEN] {
+    Montgomery::new(&PRIME).pow(peer_key, private_key)
+}
+
+const LIMBS: usize = KEY_LEN / 4;
+type Limbs = [u32; LIMBS];
+
+/// Modular arithmetic in Montgomery form, enough for the key exchange
+struct Montgomery {
+    modulus: Limbs,
+    /// -modulus^-1 mod 2^32
+    inverse: u32,
+    /// R^2 mod modulus, R being 2^(32 * LIMBS)
+    r_squared: Limbs,
+}
+
+impl Montgomery {
+    fn new(modulus: &[u8; KEY_LEN]) -> Self {
+        let modulus = to_limbs(modulus);
+        // Newton iteration, each step doubles the correct low bits
+        let mut inverse: u32 = 1;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(modulus[0].wrapping_mul(inverse)));
+        }
+        let mut r_squared = [0u32; LIMBS];
+        r_squared[0] = 1;
+        for _ in 0..2 * 32 * LIMBS {
+            let carry = shift_left(&mut r_squared);
+            if carry || !less_than(&r_squared, &modulus) {
+                subtract(&mut r_squared, &modulus);
+            }
+        }
+        Montgomery {
+            modulus,
+            inverse: inverse.wrapping_neg(),
+            r_squared,
+        }
+    }
+
+    /// `base` ^ `exponent` mod the modulus, big endian numbers
+    fn pow(&self, base: &[u8; KEY_LEN], exponent: &[u8]) -> [u8; KEY_LEN] {
+        let mut one = [0u32; LIMBS];
+        one[0] = 1;
+        let base = self.multiply(&to_limbs(base), &self.r_squared);
+        let mut result = self.multiply(&one, &self.r_squared);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        from_limbs(&self.multiply(&result, &one))
+    }
+
+    /// a * b / R mod the modulus
+    fn multiply(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for &b_limb in b {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let sum = t[j] as u64 + a[j] as u64 * b_limb as u64 + carry;
+                t[j] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS] = sum as u32;
+            t[LIMBS + 1] = (sum >> 32) as u32;
+
+            // Add a multiple of the modulus clearing the low limb, then drop it
+            let m = t[0].wrapping_mul(self.inverse);
+            let mut carry = (t[0] as u64 + m as u64 * self.modulus[0] as u64) >> 32;
+            for j in 1..LIMBS {
+                let sum = t[j] as u64 + m as u64 * self.modulus[j] as u64 + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS - 1] = sum as u32;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 32) as u32;
+            t[LIMBS + 1] = 0;
+        }
+        let mut result: Limbs = t[..LIMBS].try_into().expect("LIMBS long");
+        if t[LIMBS] != 0 || !less_than(&result, &self.modulus) {
+            subtract(&mut result, &self.modulus);
+        }
+        result
+    }
+}
+
+/// Little endian limbs of a big endian number
+fn to_limbs(bytes: &[u8; KEY_LEN]) -> Limbs {
+    let mut limbs = [0u32; LIMBS];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks_exact(4)) {
+        *limb = u32::from_be_bytes(chunk.try_into().expect("4 bytes chunk"));
+    }
+    limbs
+}
+
+fn from_limbs(limbs: &Limbs) -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    for (limb, chunk) in limbs.iter().zip(bytes.rchunks_exact_mut(4)) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn less_than(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().lt(b.iter().rev())
+}
+
+/// Wrapping a - b
+fn subtract(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = false;
+    for (a, b) in a.iter_mut().zip(b) {
+        let (difference, borrow_1) = a.overflowing_sub(*b);
+        let (difference, borrow_2) = difference.overflowing_sub(borrow as u32);
+        *a = difference;
+        borrow = borrow_1 || borrow_2;
+    }
+}
+
+/// Returns the bit shifted out
+fn shift_left(a: &mut Limbs) -> bool {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let next_carry = *limb >> 31;
+        *limb = *limb << 1 | carry;
+        carry = next_carry;
+    }
+    carry == 1
+}
+
+/// RC4 stream cipher, encrypting and decrypting the same way
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// RC4 keyed with `key`, past the keystream bytes MSE drops
+fn mse_cipher(key: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(key);
+    rc4.apply(&mut [0u8; RC4_DISCARD]);
+    rc4
+}
+
+/// Stream past the MSE handshake, RC4 encrypted or plaintext
+pub struct MseStream<S> {
+    inner: S,
+    /// Encryption and decryption keystreams, None for plaintext
+    ciphers: Option<(Rc4, Rc4)>,
+    /// Plaintext read during the handshake, returned before the stream
+    buffered: Vec<u8>,
+    /// Encrypted copy of the data being written
+    scratch: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    fn new(inner: S, ciphers: Option<(Rc4, Rc4)>, buffered: Vec<u8>) -> Self {
+        MseStream {
+            inner,
+            ciphers,
+            buffered,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let length = std::cmp::min(buf.remaining(), this.buffered.len());
+            buf.put_slice(&this.buffered[..length]);
+            this.buffered.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some((_, decrypt)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some((encrypt, _)) = &mut this.ciphers else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        // The keystream only moves forward by what the inner stream took
+        let mut attempt = encrypt.clone();
+        this.scratch.clear();
+        this.scratch.extend_from_slice(buf);
+        attempt.apply(&mut this.scratch);
+        let result = Pin::new(&mut this.inner).poll_write(cx, &this.scratch);
+        if let Poll::Ready(Ok(written)) = result {
+            if written == buf.len() {
+                *encrypt = attempt;
+            } else {
+                encrypt.apply(&mut this.scratch[..written]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
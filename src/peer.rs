@@ -1,62 +1,315 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
 use crate::{
+    error::{Result, TorrentError},
+    extension::ExtensionHandshake,
     message::Message,
-    torrent_file::TorrentFile,
+    mse::{self, EncryptionPolicy, MseStream},
     tracker::Peer,
-    worker::{read_message, write_message, PieceProgress, State},
-    CLIENT_ID,
+    utp::{UtpSocket, UtpStream},
+    worker::{write_message, State},
+    PORT,
 };
-use anyhow::{anyhow, Result};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::TcpStream,
+    sync::OnceCell,
+    time,
 };
 
 const PSTR: &[u8] = b"BitTorrent protocol";
+/// Of each transport, an unreachable peer over TCP may still answer over uTP
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Peers not speaking MSE may just wait for a BitTorrent handshake, they are
+/// then retried in plaintext when encryption is only preferred
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Once connected and encryption negotiated
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub async fn handshake(peer: &Peer, torrent: &TorrentFile) -> Result<TcpStream> {
-    // Open TCP stream
-    let mut stream = TcpStream::connect((peer.ip, peer.port)).await?;
+/// Which transports peers are reached with, and in which order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportPreference {
+    TcpOnly,
+    /// BEP 29 uTP only
+    UtpOnly,
+    /// TCP first, uTP for peers that can't be reached over TCP
+    #[default]
+    PreferTcp,
+    /// uTP first, its congestion control yields to other traffic
+    PreferUtp,
+}
 
-    let pstr_len = PSTR.len() as u8;
-    let reserved = [0u8; 8];
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Tcp,
+    Utp,
+}
 
-    let mut handshake = [0u8; 49 + PSTR.len()];
-    handshake[0] = pstr_len;
-    handshake[1..20].copy_from_slice(PSTR);
-    handshake[20..28].copy_from_slice(&reserved);
-    handshake[28..48].copy_from_slice(&torrent.infohash);
-    handshake[48..].copy_from_slice(CLIENT_ID.as_bytes());
+/// How to open connections to peers, shared by every torrent of the process
+#[derive(Clone)]
+pub struct Transports {
+    pub preference: TransportPreference,
+    pub encryption: EncryptionPolicy,
+    /// Incoming connections are accepted on it, over TCP and uTP
+    pub port: u16,
+    /// uTP sockets of each address family, bound on first use
+    utp_v4: Arc<OnceCell<UtpSocket>>,
+    utp_v6: Arc<OnceCell<UtpSocket>>,
+}
+
+impl Transports {
+    pub fn new(preference: TransportPreference, encryption: EncryptionPolicy) -> Self {
+        Transports {
+            preference,
+            encryption,
+            port: PORT,
+            utp_v4: Arc::new(OnceCell::new()),
+            utp_v6: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Connect to `peer` with each allowed transport in turn
+    pub async fn connect(&self, peer: &Peer) -> Result<PeerStream> {
+        let order: &[Transport] = match self.preference {
+            TransportPreference::TcpOnly => &[Transport::Tcp],
+            TransportPreference::UtpOnly => &[Transport::Utp],
+            TransportPreference::PreferTcp => &[Transport::Tcp, Transport::Utp],
+            TransportPreference::PreferUtp => &[Transport::Utp, Transport::Tcp],
+        };
+        let mut last_error = None;
+        for transport in order {
+            let attempt = async {
+                match transport {
+                    Transport::Tcp => TcpStream::connect(peer.addr()).await.map(PeerStream::Tcp),
+                    Transport::Utp => self.connect_utp(peer.addr()).await.map(PeerStream::Utp),
+                }
+            };
+            let result = time::timeout(CONNECT_TIMEOUT, attempt)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("at least one transport is tried").into())
+    }
 
-    stream.write_all(&handshake).await?;
+    /// Connect to `peer` and negotiate encryption for the `infohash` swarm as
+    /// the policy asks
+    pub async fn open(&self, peer: &Peer, infohash: &[u8; 20]) -> Result<PeerStream> {
+        let stream = self.connect(peer).await?;
+        if self.encryption == EncryptionPolicy::Disabled {
+            return Ok(stream);
+        }
+        let negotiation = time::timeout(
+            ENCRYPTION_TIMEOUT,
+            mse::initiate(stream, infohash, self.encryption),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(TorrentError::PeerProtocol(
+                "timed out negotiating encryption".to_string(),
+            ))
+        });
+        match negotiation {
+            Ok(stream) => Ok(PeerStream::Mse(Box::new(stream))),
+            Err(e) if self.encryption == EncryptionPolicy::Required => Err(e),
+            // The peer likely closed the connection on our public key
+            Err(_) => self.connect(peer).await,
+        }
+    }
 
-    let mut response = [0u8; 49 + PSTR.len()];
-    stream.read_exact(&mut response).await?;
+    async fn connect_utp(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        self.utp_socket(addr.ip()).await?.connect(addr).await
+    }
 
-    if response[28..48] != torrent.infohash {
-        return Err(anyhow!("wrong infohash from peer"));
+    /// uTP socket of the address family of `ip`, outgoing and incoming
+    /// connections share it
+    pub async fn utp_socket(&self, ip: IpAddr) -> io::Result<&UtpSocket> {
+        let (cell, unspecified) = match ip {
+            IpAddr::V4(_) => (&self.utp_v4, Ipv4Addr::UNSPECIFIED.into()),
+            IpAddr::V6(_) => (&self.utp_v6, Ipv6Addr::UNSPECIFIED.into()),
+        };
+        cell.get_or_try_init(|| async {
+            // Peers expect uTP on the TCP port, any port does for outgoing
+            // connections when it's taken
+            match UtpSocket::bind(SocketAddr::new(unspecified, self.port)).await {
+                Ok(socket) => Ok(socket),
+                Err(_) => UtpSocket::bind(SocketAddr::new(unspecified, 0)).await,
+            }
+        })
+        .await
     }
+}
 
-    Ok(stream)
+/// Connection to a peer, whatever its transport
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+    /// Any of the above after a MSE handshake
+    Mse(Box<MseStream<PeerStream>>),
 }
 
-pub async fn init_connection(tcp_stream: &mut TcpStream) -> Result<State> {
-    // Wait for a bitfield as first message
-    let bitfield = match read_message(tcp_stream).await? {
-        Message::Bitfield(payload) => payload,
-        message => {
-            return Err(anyhow!("expected bitfield but got {:?}", message));
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Mse(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Mse(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Mse(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Mse(stream) => Pin::new(stream).poll_shutdown(cx),
         }
+    }
+}
+
+/// What the remote end tells about itself in its handshake
+pub struct RemoteHandshake {
+    pub peer_id: [u8; 20],
+    pub reserved: [u8; 8],
+}
+
+impl RemoteHandshake {
+    /// BEP 52, the peer answers hash requests
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[7] & 0x10 != 0
+    }
+}
+
+const HANDSHAKE_LEN: usize = 49 + PSTR.len();
+
+/// Open a connection and exchange handshakes, every step is timed out on its
+/// own
+pub async fn handshake(
+    peer: &Peer,
+    infohash: &[u8; 20],
+    transports: &Transports,
+    peer_id: &[u8; 20],
+) -> Result<(PeerStream, RemoteHandshake)> {
+    let mut stream = transports.open(peer, infohash).await?;
+    let exchange = async {
+        stream
+            .write_all(&handshake_bytes(infohash, peer_id))
+            .await?;
+        read_handshake(&mut stream).await
     };
+    let (remote_infohash, remote) = time::timeout(HANDSHAKE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| TorrentError::PeerProtocol("timed out exchanging handshakes".to_string()))??;
+    if remote_infohash != *infohash {
+        return Err(TorrentError::PeerProtocol(
+            "wrong infohash from peer".to_string(),
+        ));
+    }
+    Ok((stream, remote))
+}
 
-    // Notify we're interested
-    write_message(tcp_stream, &Message::Interested).await?;
+/// Exchange handshakes on a connection opened by a peer, for any torrent of
+/// `infohashes`. Returns the infohash the peer asked for.
+pub async fn accept_handshake(
+    stream: &mut PeerStream,
+    infohashes: &[[u8; 20]],
+    peer_id: &[u8; 20],
+) -> Result<([u8; 20], RemoteHandshake)> {
+    let (infohash, remote) = read_handshake(stream).await?;
+    if !infohashes.contains(&infohash) {
+        return Err(TorrentError::PeerProtocol(
+            "unknown infohash from peer".to_string(),
+        ));
+    }
+    stream
+        .write_all(&handshake_bytes(&infohash, peer_id))
+        .await?;
+    Ok((infohash, remote))
+}
 
-    // Waiting to be unchoked
-    read_message(tcp_stream).await?;
+fn handshake_bytes(infohash: &[u8; 20], peer_id: &[u8; 20]) -> [u8; HANDSHAKE_LEN] {
+    let mut reserved = [0u8; 8];
+    // Support extension protocol (BEP 10)
+    reserved[5] |= 0x10;
+    // Support BitTorrent v2 (BEP 52)
+    reserved[7] |= 0x10;
 
+    let mut handshake = [0u8; HANDSHAKE_LEN];
+    handshake[0] = PSTR.len() as u8;
+    handshake[1..20].copy_from_slice(PSTR);
+    handshake[20..28].copy_from_slice(&reserved);
+    handshake[28..48].copy_from_slice(infohash);
+    handshake[48..].copy_from_slice(peer_id);
+    handshake
+}
+
+async fn read_handshake(stream: &mut PeerStream) -> Result<([u8; 20], RemoteHandshake)> {
+    let mut handshake = [0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut handshake).await?;
+    if handshake[0] as usize != PSTR.len() || handshake[1..20] != *PSTR {
+        return Err(TorrentError::PeerProtocol(
+            "peer does not speak BitTorrent".to_string(),
+        ));
+    }
+    let infohash = handshake[28..48].try_into().expect("20 bytes infohash");
+    let remote = RemoteHandshake {
+        peer_id: handshake[48..].try_into().expect("20 bytes peer id"),
+        reserved: handshake[20..28].try_into().expect("8 reserved bytes"),
+    };
+    Ok((infohash, remote))
+}
+
+/// Send our bitfield, even without pieces, and tell the peer we're interested
+pub async fn init_connection(stream: &mut PeerStream, bitfield: &[u8]) -> Result<State> {
+    write_message(stream, &Message::Bitfield(bitfield.to_vec())).await?;
+    write_message(stream, &Message::Interested).await?;
+
+    // The peer's bitfield and unchoke are handled by the worker along with
+    // the other messages, peers without pieces may never send a bitfield
     Ok(State {
-        piece_progress: PieceProgress::default(),
-        bitfield,
+        bitfield: vec![0; bitfield.len()],
         peer_choking: true,
+        peer_interested: false,
+        requests: Vec::new(),
     })
 }
+
+/// `v` of an extension handshake, a malformed one tells nothing
+pub fn extension_client_of(payload: &[u8]) -> Option<String> {
+    ExtensionHandshake::from_bytes(payload).ok()?.v
+}
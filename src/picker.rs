@@ -0,0 +1,492 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::{
+    bitfield::{bitfield_has_piece, bitfield_set_piece},
+    torrent_file::{FileInfo, TorrentFile},
+};
+
+pub const BLOCK_SIZE: usize = 16384;
+
+/// How much a file is wanted, pieces of higher priority files are picked
+/// first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    /// Not downloaded, except for pieces shared with wanted files
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+/// Order of pieces within the same file priority, for torrents watched
+/// while they download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PickOrder {
+    /// Lowest missing pieces first: this many pieces from the first missing
+    /// one are picked in order, before any other
+    pub sequential: Option<usize>,
+    /// First and last pieces of each wanted file come before everything
+    /// else, media containers keep headers and indexes there
+    pub first_last_pieces: bool,
+}
+
+/// Earliest deadline, file edge, place in the sequential window, file
+/// priority, in progress, lowest index
+type PickRank = (
+    Option<Reverse<Instant>>,
+    bool,
+    Option<Reverse<usize>>,
+    FilePriority,
+    bool,
+    Reverse<usize>,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceStatus {
+    Missing,
+    InProgress,
+    /// Downloaded whole by a web seed
+    Fetching,
+    Hashing,
+    Verified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(SocketAddr),
+    Received,
+}
+
+struct PartialPiece {
+    buf: Vec<u8>,
+    blocks: Vec<BlockState>,
+}
+
+/// Shared between all workers of a torrent: decides which block each peer
+/// should request next and assembles blocks into pieces.
+pub struct PiecePicker {
+    lengths: Vec<usize>,
+    status: Vec<PieceStatus>,
+    partial: BTreeMap<usize, PartialPiece>,
+    /// Missing pieces by the priority of their files, each set is the order
+    /// they are picked in when nothing ranks them higher
+    missing: [BTreeSet<usize>; 4],
+    num_verified: usize,
+    /// Pieces wanted and not verified yet, the download is complete at zero
+    num_wanted_left: usize,
+    piece_length: usize,
+    /// Torrent data ranges of padding files, known to be zeroes
+    padding: Vec<(usize, usize)>,
+    /// Highest priority of the files each piece overlaps
+    priorities: Vec<FilePriority>,
+    /// Pieces holding the first or last bytes of a wanted file
+    file_edges: Vec<bool>,
+    /// Indexes of the pieces set in `file_edges`
+    edge_pieces: Vec<usize>,
+    /// When someone needs each piece, pieces with a deadline are picked
+    /// before any other, earliest first, until verified
+    deadlines: Vec<Option<Instant>>,
+    /// Indexes of the pieces with a deadline
+    deadline_pieces: BTreeSet<usize>,
+    order: PickOrder,
+    /// First wanted piece that is not verified, where the sequential window
+    /// starts
+    head: usize,
+}
+
+impl PiecePicker {
+    pub fn new(torrent_file: &TorrentFile) -> Self {
+        let num_pieces = torrent_file.num_pieces();
+        let mut picker = PiecePicker {
+            lengths: (0..num_pieces)
+                .map(|index| torrent_file.calculate_piece_size(index))
+                .collect(),
+            status: vec![PieceStatus::Missing; num_pieces],
+            partial: BTreeMap::new(),
+            missing: Default::default(),
+            num_verified: 0,
+            num_wanted_left: 0,
+            piece_length: torrent_file.piece_length,
+            padding: torrent_file
+                .files
+                .iter()
+                .filter(|file| file.attributes.padding)
+                .map(|file| (file.offset, file.offset + file.length))
+                .collect(),
+            priorities: vec![FilePriority::Skip; num_pieces],
+            file_edges: vec![false; num_pieces],
+            edge_pieces: Vec::new(),
+            deadlines: vec![None; num_pieces],
+            deadline_pieces: BTreeSet::new(),
+            order: PickOrder::default(),
+            head: 0,
+        };
+        picker.set_file_priorities(
+            &torrent_file.files,
+            &vec![FilePriority::Normal; torrent_file.files.len()],
+        );
+        picker
+    }
+
+    pub fn set_order(&mut self, order: PickOrder) {
+        self.order = order;
+    }
+
+    /// Priority of each of `files`, in torrent order. Padding files don't
+    /// count, their pieces follow the files they sit between.
+    pub fn set_file_priorities(&mut self, files: &[FileInfo], priorities: &[FilePriority]) {
+        self.priorities.fill(FilePriority::Skip);
+        self.file_edges.fill(false);
+        for (file, priority) in files.iter().zip(priorities) {
+            if file.attributes.padding || file.length == 0 {
+                continue;
+            }
+            let first = file.offset / self.piece_length;
+            let last = (file.offset + file.length - 1) / self.piece_length;
+            for piece_priority in &mut self.priorities[first..=last] {
+                *piece_priority = std::cmp::max(*piece_priority, *priority);
+            }
+            if *priority != FilePriority::Skip {
+                self.file_edges[first] = true;
+                self.file_edges[last] = true;
+            }
+        }
+        self.edge_pieces = (0..self.status.len())
+            .filter(|&index| self.file_edges[index])
+            .collect();
+
+        for missing in &mut self.missing {
+            missing.clear();
+        }
+        for index in 0..self.status.len() {
+            if self.status[index] == PieceStatus::Missing {
+                self.missing[self.priorities[index] as usize].insert(index);
+            }
+        }
+        self.num_wanted_left = (0..self.status.len())
+            .filter(|&index| self.status[index] != PieceStatus::Verified && self.is_wanted(index))
+            .count();
+        self.head = 0;
+        self.advance_head();
+    }
+
+    /// Download the piece `index` by `deadline`, before pieces without one or
+    /// with a later one, even if its files are skipped. An earlier deadline
+    /// already set is kept.
+    pub fn set_piece_deadline(&mut self, index: usize, deadline: Instant) {
+        if index >= self.status.len() || self.status[index] == PieceStatus::Verified {
+            return;
+        }
+        if !self.is_wanted(index) {
+            self.num_wanted_left += 1;
+            self.head = self.head.min(index);
+        }
+        let current = &mut self.deadlines[index];
+        *current = Some(current.map_or(deadline, |current| current.min(deadline)));
+        self.deadline_pieces.insert(index);
+    }
+
+    /// Whether every piece of the wanted files is verified
+    pub fn is_complete(&self) -> bool {
+        self.num_wanted_left == 0
+    }
+
+    /// Bytes of the wanted pieces that are not verified yet
+    pub fn wanted_bytes_left(&self) -> usize {
+        (0..self.status.len())
+            .filter(|&index| self.status[index] != PieceStatus::Verified && self.is_wanted(index))
+            .map(|index| self.lengths[index])
+            .sum()
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        self.status[index] == PieceStatus::Verified
+    }
+
+    fn is_wanted(&self, index: usize) -> bool {
+        self.priorities[index] != FilePriority::Skip || self.deadlines[index].is_some()
+    }
+
+    pub fn num_verified(&self) -> usize {
+        self.num_verified
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.status.len()
+    }
+
+    pub fn piece_length(&self, index: usize) -> usize {
+        self.lengths[index]
+    }
+
+    /// Bitfield of the verified pieces, as sent to peers
+    pub fn verified_bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.status.len().div_ceil(8)];
+        for (index, status) in self.status.iter().enumerate() {
+            if *status == PieceStatus::Verified {
+                bitfield_set_piece(&mut bitfield, index);
+            }
+        }
+        bitfield
+    }
+
+    /// Assign the next block to request from `peer`. Pieces of higher
+    /// priority come first, then blocks of pieces that are already in
+    /// progress so that pieces complete quickly. The pick order goes before
+    /// both.
+    pub fn pick_block(&mut self, peer: SocketAddr, bitfield: &[u8]) -> Option<BlockRequest> {
+        let index = self.best_piece(|index| {
+            bitfield_has_piece(bitfield, index)
+                && match self.status[index] {
+                    PieceStatus::Missing => true,
+                    PieceStatus::InProgress => {
+                        self.partial[&index].blocks.contains(&BlockState::Missing)
+                    }
+                    _ => false,
+                }
+        })?;
+
+        if let Some(partial) = self.partial.get_mut(&index) {
+            let block = partial
+                .blocks
+                .iter()
+                .position(|b| *b == BlockState::Missing)
+                .expect("picked pieces have a missing block");
+            partial.blocks[block] = BlockState::Requested(peer);
+            return Some(block_request(index, block, self.lengths[index]));
+        }
+
+        let length = self.lengths[index];
+        // Blocks made only of padding are never requested, the buffer
+        // already holds their zeroes
+        let mut blocks: Vec<BlockState> = (0..length.div_ceil(BLOCK_SIZE))
+            .map(|block| {
+                let request = block_request(index, block, length);
+                let start = index * self.piece_length + request.begin;
+                if self.is_padding(start, start + request.length) {
+                    BlockState::Received
+                } else {
+                    BlockState::Missing
+                }
+            })
+            .collect();
+        let block = blocks
+            .iter()
+            .position(|b| *b == BlockState::Missing)
+            .unwrap_or(0);
+        blocks[block] = BlockState::Requested(peer);
+        self.set_status(index, PieceStatus::InProgress);
+        self.partial.insert(
+            index,
+            PartialPiece {
+                buf: vec![0u8; length],
+                blocks,
+            },
+        );
+        Some(block_request(index, block, length))
+    }
+
+    /// Pieces picked in order in sequential mode, from the first wanted piece
+    /// that is not verified
+    fn sequential_window(&self) -> std::ops::Range<usize> {
+        let Some(lookahead) = self.order.sequential else {
+            return 0..0;
+        };
+        self.head..self.head.saturating_add(lookahead)
+    }
+
+    /// Move `head` past verified and unwanted pieces
+    fn advance_head(&mut self) {
+        while self.head < self.status.len()
+            && (self.status[self.head] == PieceStatus::Verified || !self.is_wanted(self.head))
+        {
+            self.head += 1;
+        }
+    }
+
+    /// Wanted piece of the highest rank among those `eligible` accepts,
+    /// without ranking every piece of the torrent
+    fn best_piece(&self, eligible: impl Fn(usize) -> bool) -> Option<usize> {
+        let window = self.sequential_window();
+        let rank = |index: &usize| self.pick_rank(*index, &window);
+        let is_candidate = |index: &usize| self.is_wanted(*index) && eligible(*index);
+
+        // Each step holds the pieces ranked above those of the next ones
+        if let Some(index) = self
+            .deadline_pieces
+            .iter()
+            .copied()
+            .filter(is_candidate)
+            .max_by_key(rank)
+        {
+            return Some(index);
+        }
+        if self.order.first_last_pieces {
+            if let Some(index) = self
+                .edge_pieces
+                .iter()
+                .copied()
+                .filter(is_candidate)
+                .max_by_key(rank)
+            {
+                return Some(index);
+            }
+        }
+        let window_end = std::cmp::min(window.end, self.status.len());
+        if let Some(index) = (window.start..window_end).find(is_candidate) {
+            return Some(index);
+        }
+        for priority in [FilePriority::High, FilePriority::Normal, FilePriority::Low] {
+            let in_progress = self
+                .partial
+                .keys()
+                .copied()
+                .filter(|&index| self.priorities[index] == priority)
+                .find(is_candidate);
+            if let Some(index) = in_progress {
+                return Some(index);
+            }
+            if let Some(index) = self.missing[priority as usize]
+                .iter()
+                .copied()
+                .find(is_candidate)
+            {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// Pieces with the highest rank are picked first
+    fn pick_rank(&self, index: usize, window: &std::ops::Range<usize>) -> PickRank {
+        (
+            self.deadlines[index].map(Reverse),
+            self.order.first_last_pieces && self.file_edges[index],
+            window.contains(&index).then_some(Reverse(index)),
+            self.priorities[index],
+            self.status[index] == PieceStatus::InProgress,
+            Reverse(index),
+        )
+    }
+
+    fn is_padding(&self, start: usize, end: usize) -> bool {
+        self.padding
+            .iter()
+            .any(|&(pad_start, pad_end)| pad_start <= start && end <= pad_end)
+    }
+
+    /// Assign a whole missing piece, for web seeds which don't download
+    /// block by block. The piece is then handled like one that was hashing.
+    pub fn pick_piece(&mut self) -> Option<usize> {
+        let index = self.best_piece(|index| self.status[index] == PieceStatus::Missing)?;
+        self.set_status(index, PieceStatus::Fetching);
+        Some(index)
+    }
+
+    /// Store a received block. Returns the whole piece once its last block
+    /// arrived, the piece then waits for `piece_verified` or `piece_failed`.
+    pub fn block_received(&mut self, index: usize, begin: usize, data: &[u8]) -> Option<Vec<u8>> {
+        let partial = self.partial.get_mut(&index)?;
+        if !begin.is_multiple_of(BLOCK_SIZE) || begin + data.len() > partial.buf.len() {
+            return None;
+        }
+        let block = begin / BLOCK_SIZE;
+        if partial.blocks[block] == BlockState::Received {
+            return None;
+        }
+        partial.buf[begin..begin + data.len()].copy_from_slice(data);
+        partial.blocks[block] = BlockState::Received;
+
+        if partial.blocks.iter().all(|b| *b == BlockState::Received) {
+            self.set_status(index, PieceStatus::Hashing);
+            return self.partial.remove(&index).map(|partial| partial.buf);
+        }
+        None
+    }
+
+    pub fn piece_verified(&mut self, index: usize) {
+        if self.status[index] == PieceStatus::Verified {
+            return;
+        }
+        if self.is_wanted(index) {
+            self.num_wanted_left -= 1;
+        }
+        self.set_status(index, PieceStatus::Verified);
+        self.deadlines[index] = None;
+        self.deadline_pieces.remove(&index);
+        self.num_verified += 1;
+        if index == self.head {
+            self.advance_head();
+        }
+    }
+
+    pub fn piece_failed(&mut self, index: usize) {
+        self.set_status(index, PieceStatus::Missing);
+    }
+
+    /// Keeps the missing pieces of each priority up to date
+    fn set_status(&mut self, index: usize, status: PieceStatus) {
+        let missing = &mut self.missing[self.priorities[index] as usize];
+        if status == PieceStatus::Missing {
+            missing.insert(index);
+        } else if self.status[index] == PieceStatus::Missing {
+            missing.remove(&index);
+        }
+        self.status[index] = status;
+    }
+
+    /// Forget every assignment and piece waiting for verification, used when
+    /// all workers are stopped at once
+    pub fn reset_requests(&mut self) {
+        for partial in self.partial.values_mut() {
+            for block in partial.blocks.iter_mut() {
+                if let BlockState::Requested(_) = block {
+                    *block = BlockState::Missing;
+                }
+            }
+        }
+        for index in 0..self.status.len() {
+            if matches!(
+                self.status[index],
+                PieceStatus::Fetching | PieceStatus::Hashing
+            ) {
+                self.set_status(index, PieceStatus::Missing);
+            }
+        }
+    }
+
+    /// Give back every block assigned to `peer` so other workers can pick
+    /// them, e.g. when the peer chokes us or disconnects.
+    pub fn release_peer(&mut self, peer: SocketAddr) {
+        for partial in self.partial.values_mut() {
+            for block in partial.blocks.iter_mut() {
+                if *block == BlockState::Requested(peer) {
+                    *block = BlockState::Missing;
+                }
+            }
+        }
+    }
+}
+
+fn block_request(index: usize, block: usize, piece_length: usize) -> BlockRequest {
+    let begin = block * BLOCK_SIZE;
+    BlockRequest {
+        index,
+        begin,
+        length: std::cmp::min(BLOCK_SIZE, piece_length - begin),
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Upload and download rates in bytes per second, None for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    /// Nothing is seeded, only protocol messages are charged
+    pub upload: Option<u64>,
+}
+
+/// Alternative global limits applying every day between two times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateSchedule {
+    /// Minutes after midnight, the period wraps past midnight when `end` is
+    /// before `start`
+    pub start: u16,
+    pub end: u16,
+    /// Offset of local time from UTC in minutes, the schedule is in local time
+    pub utc_offset: i32,
+    pub limits: RateLimits,
+}
+
+impl RateSchedule {
+    fn is_active(&self, now: SystemTime) -> bool {
+        let utc_minutes = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64 / 60);
+        let minute = (utc_minutes + self.utc_offset as i64).rem_euclid(MINUTES_PER_DAY) as u16;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Token bucket holding up to one second worth of bytes. Taking more than
+/// available is allowed, the debt is waited for.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Bucket {
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` tokens, returns how long to wait before using them
+    fn take(&mut self, bytes: usize, rate: Option<u64>, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        let Some(rate) = rate.filter(|rate| *rate > 0) else {
+            self.tokens = 0.0;
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        self.tokens = f64::min(self.tokens + elapsed * rate, rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Download,
+    Upload,
+}
+
+/// Download and upload buckets of one scope
+#[derive(Debug)]
+struct Buckets {
+    limits: RateLimits,
+    download: Bucket,
+    upload: Bucket,
+}
+
+impl Buckets {
+    fn new(limits: RateLimits) -> Self {
+        Buckets {
+            limits,
+            download: Bucket::new(),
+            upload: Bucket::new(),
+        }
+    }
+
+    fn take(&mut self, direction: Direction, bytes: usize, limits: RateLimits) -> Duration {
+        let now = Instant::now();
+        match direction {
+            Direction::Download => self.download.take(bytes, limits.download, now),
+            Direction::Upload => self.upload.take(bytes, limits.upload, now),
+        }
+    }
+}
+
+struct BandwidthState {
+    global: Buckets,
+    schedule: Option<RateSchedule>,
+    torrents: HashMap<[u8; 20], Buckets>,
+    /// Limits of each peer connection, peers have buckets of their own
+    peer_limits: RateLimits,
+}
+
+impl BandwidthState {
+    fn global_limits(&self) -> RateLimits {
+        match self.schedule {
+            Some(schedule) if schedule.is_active(SystemTime::now()) => schedule.limits,
+            _ => self.global.limits,
+        }
+    }
+}
+
+/// Rate limits of a session: over every torrent, per torrent and per peer.
+/// They can all be changed while downloading.
+#[derive(Clone)]
+pub struct Bandwidth {
+    state: Arc<StdMutex<BandwidthState>>,
+}
+
+impl Bandwidth {
+    pub fn new(
+        global_limits: RateLimits,
+        peer_limits: RateLimits,
+        schedule: Option<RateSchedule>,
+    ) -> Self {
+        Bandwidth {
+            state: Arc::new(StdMutex::new(BandwidthState {
+                global: Buckets::new(global_limits),
+                schedule,
+                torrents: HashMap::new(),
+                peer_limits,
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BandwidthState> {
+        self.state.lock().expect("bandwidth lock poisoned")
+    }
+
+    /// Limits over every torrent, outside of the schedule
+    pub fn global_limits(&self) -> RateLimits {
+        self.state().global.limits
+    }
+
+    pub fn set_global_limits(&self, limits: RateLimits) {
+        self.state().global.limits = limits;
+    }
+
+    pub fn schedule(&self) -> Option<RateSchedule> {
+        self.state().schedule
+    }
+
+    pub fn set_schedule(&self, schedule: Option<RateSchedule>) {
+        self.state().schedule = schedule;
+    }
+
+    pub fn peer_limits(&self) -> RateLimits {
+        self.state().peer_limits
+    }
+
+    pub fn set_peer_limits(&self, limits: RateLimits) {
+        self.state().peer_limits = limits;
+    }
+
+    pub fn torrent_limits(&self, infohash: &[u8; 20]) -> RateLimits {
+        self.state()
+            .torrents
+            .get(infohash)
+            .map(|buckets| buckets.limits)
+            .unwrap_or_default()
+    }
+
+    pub fn set_torrent_limits(&self, infohash: [u8; 20], limits: RateLimits) {
+        self.state()
+            .torrents
+            .entry(infohash)
+            .or_insert_with(|| Buckets::new(limits))
+            .limits = limits;
+    }
+
+    pub fn remove_torrent(&self, infohash: &[u8; 20]) {
+        self.state().torrents.remove(infohash);
+    }
+
+    /// Buckets of a new connection of the torrent `infohash`
+    pub fn peer(&self, infohash: [u8; 20]) -> PeerBandwidth {
+        PeerBandwidth {
+            bandwidth: self.clone(),
+            infohash,
+            buckets: StdMutex::new(Buckets::new(RateLimits::default())),
+        }
+    }
+}
+
+/// Rate limits as seen by one peer connection
+pub struct PeerBandwidth {
+    bandwidth: Bandwidth,
+    infohash: [u8; 20],
+    buckets: StdMutex<Buckets>,
+}
+
+impl PeerBandwidth {
+    /// Wait until `bytes` read from the peer fit in every limit
+    pub async fn download(&self, bytes: usize) {
+        self.consume(Direction::Download, bytes).await
+    }
+
+    /// Wait until `bytes` written to the peer fit in every limit
+    pub async fn upload(&self, bytes: usize) {
+        self.consume(Direction::Upload, bytes).await
+    }
+
+    async fn consume(&self, direction: Direction, bytes: usize) {
+        let wait = self.take(direction, bytes);
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from every bucket, returns how long to wait before using
+    /// them
+    fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        let mut state = self.bandwidth.state();
+        let global_limits = state.global_limits();
+        let peer_limits = state.peer_limits;
+        let global_wait = state.global.take(direction, bytes, global_limits);
+        let torrent_wait = match state.torrents.get_mut(&self.infohash) {
+            Some(torrent) => torrent.take(direction, bytes, torrent.limits),
+            None => Duration::ZERO,
+        };
+        let peer_wait = self
+            .buckets
+            .lock()
+            .expect("peer bandwidth lock poisoned")
+            .take(direction, bytes, peer_limits);
+        global_wait.max(torrent_wait).max(peer_wait)
+    }
+}
+
+/// Stream whose reads are charged to the download limits of a connection.
+/// A read first waits for the ones before it to fit in the limits, the peer
+/// slows down as its data piles up unread.
+pub struct RateLimited<'a, S> {
+    stream: S,
+    bandwidth: &'a PeerBandwidth,
+    /// Until the bytes read so far fit in the limits
+    delay: Option<Pin<Box<Sleep>>>,
+    /// When reads fail, pushed back by every wait for bandwidth
+    timeout: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, S> RateLimited<'a, S> {
+    pub fn new(stream: S, bandwidth: &'a PeerBandwidth) -> Self {
+        RateLimited {
+            stream,
+            bandwidth,
+            delay: None,
+            timeout: None,
+        }
+    }
+
+    /// Fail reads with `TimedOut` once `timeout` passes from now, not
+    /// counting the waits for bandwidth. None for no timeout.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout.map(|timeout| Box::pin(time::sleep(timeout)));
+    }
+
+    /// Wait until the bytes read so far fit in the limits. Reads do it
+    /// anyway, this keeps the wait out of their timeouts.
+    pub async fn throttle(&mut self) {
+        if let Some(delay) = self.delay.take() {
+            delay.await;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimited<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(delay) = &mut this.delay {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        let filled = buf.filled().len();
+        let Poll::Ready(read) = Pin::new(&mut this.stream).poll_read(cx, buf) else {
+            if let Some(timeout) = &mut this.timeout {
+                ready!(timeout.as_mut().poll(cx));
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+            return Poll::Pending;
+        };
+        read?;
+        let wait = this
+            .bandwidth
+            .take(Direction::Download, buf.filled().len() - filled);
+        if !wait.is_zero() {
+            if let Some(timeout) = &mut this.timeout {
+                let deadline = timeout.deadline() + wait;
+                timeout.as_mut().reset(deadline);
+            }
+            this.delay = Some(Box::pin(time::sleep(wait)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writes are charged by their callers, before they are made
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimited<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
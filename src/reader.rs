@@ -0,0 +1,139 @@
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::{error::Result, session::TorrentHandle, torrent_file::FileInfo};
+
+/// Pieces after the one being read that get a deadline too, so reading on
+/// doesn't stall at every piece
+const READ_AHEAD_PIECES: usize = 4;
+/// Time given to each read ahead piece after the previous one
+const READ_AHEAD_DEADLINE_STEP: Duration = Duration::from_millis(500);
+
+type PieceFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>;
+
+/// Reader over one file of a torrent being downloaded. Verified data is read
+/// from storage, missing pieces get deadlines and are waited for, reads fail
+/// if the torrent stops before they arrive.
+pub struct FileReader {
+    handle: TorrentHandle,
+    /// Position of the file's first byte in the torrent data
+    offset: usize,
+    length: usize,
+    piece_length: usize,
+    /// Inside the file, may be past its end
+    position: u64,
+    /// Last piece read, most reads are served from it
+    piece: Option<(usize, Vec<u8>)>,
+    /// Piece being waited for
+    pending: Option<(usize, PieceFuture)>,
+}
+
+impl FileReader {
+    pub(crate) fn new(handle: TorrentHandle, file: &FileInfo, piece_length: usize) -> Self {
+        FileReader {
+            handle,
+            offset: file.offset,
+            length: file.length,
+            piece_length,
+            position: 0,
+            piece: None,
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Data of the piece `index` along with the following pieces of the file
+    fn fetch_piece(&self, index: usize) -> PieceFuture {
+        let handle = self.handle.clone();
+        let end_piece = (self.offset + self.length).div_ceil(self.piece_length);
+        Box::pin(async move {
+            for (ahead, next) in (index + 1..end_piece).take(READ_AHEAD_PIECES).enumerate() {
+                let deadline = READ_AHEAD_DEADLINE_STEP * (ahead as u32 + 1);
+                handle.set_piece_deadline(next, deadline).await;
+            }
+            handle.read_piece(index).await
+        })
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let reader = self.get_mut();
+        if reader.position >= reader.length as u64 || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let position = reader.offset + reader.position as usize;
+        let index = position / reader.piece_length;
+
+        if reader
+            .piece
+            .as_ref()
+            .is_none_or(|(piece, _)| *piece != index)
+        {
+            // A seek may have moved away from the piece being waited for
+            if reader
+                .pending
+                .as_ref()
+                .is_none_or(|(piece, _)| *piece != index)
+            {
+                reader.pending = Some((index, reader.fetch_piece(index)));
+            }
+            let (_, future) = reader.pending.as_mut().expect("pending piece was just set");
+            let result = std::task::ready!(future.as_mut().poll(cx));
+            reader.pending = None;
+            let data = result.map_err(io::Error::other)?;
+            reader.piece = Some((index, data));
+        }
+
+        let (_, data) = reader.piece.as_ref().expect("piece was just read");
+        let piece_start = index * reader.piece_length;
+        let end = std::cmp::min(piece_start + data.len(), reader.offset + reader.length);
+        let count = std::cmp::min(buf.remaining(), end - position);
+        buf.put_slice(&data[position - piece_start..position - piece_start + count]);
+        reader.position += count as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let reader = self.get_mut();
+        let (base, delta) = match position {
+            SeekFrom::Start(position) => {
+                reader.position = position;
+                return Ok(());
+            }
+            SeekFrom::End(delta) => (reader.length as u64, delta),
+            SeekFrom::Current(delta) => (reader.position, delta),
+        };
+        reader.position = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seeking before the start of the file",
+            )
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_bytes::ByteBuf;
+
+use crate::{
+    bitfield::bitfield_has_piece,
+    error::{Result, TorrentError},
+    infohash::hex_encode,
+    picker::PiecePicker,
+    storage::Storage,
+    torrent_file::TorrentFile,
+};
+
+/// Progress of a torrent kept between sessions, so verified pieces are not
+/// downloaded again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub num_pieces: usize,
+    /// Bitfield of the verified pieces
+    pub pieces: ByteBuf,
+}
+
+impl ResumeData {
+    pub fn new(picker: &PiecePicker) -> Self {
+        ResumeData {
+            num_pieces: picker.num_pieces(),
+            pieces: ByteBuf::from(picker.verified_bitfield()),
+        }
+    }
+
+    /// Mark the saved pieces verified in `picker`, unless their files are
+    /// gone. Pieces of the part file are hashed again. Data saved for another
+    /// number of pieces is ignored.
+    pub fn restore(
+        &self,
+        picker: &mut PiecePicker,
+        storage: &Storage,
+        torrent_file: &TorrentFile,
+    ) -> Result<()> {
+        storage.verify_part_file(torrent_file)?;
+        if self.num_pieces != picker.num_pieces() {
+            return Ok(());
+        }
+        for index in 0..self.num_pieces {
+            if bitfield_has_piece(&self.pieces, index)
+                && storage.has_piece(index, picker.piece_length(index))
+            {
+                picker.piece_verified(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume data of the torrent `infohash` saved in `resume_dir`, None when
+    /// there is none or it can't be decoded
+    pub fn load(resume_dir: &Path, infohash: &[u8; 20]) -> Option<Self> {
+        let bytes = fs::read(resume_path(resume_dir, infohash)).ok()?;
+        serde_bencode::from_bytes(&bytes).ok()
+    }
+
+    /// Replace the saved resume data of the torrent `infohash`
+    pub fn save(&self, resume_dir: &Path, infohash: &[u8; 20]) -> Result<()> {
+        let path = resume_path(resume_dir, infohash);
+        let save = || -> std::io::Result<()> {
+            let bytes = serde_bencode::to_bytes(self)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            fs::create_dir_all(resume_dir)?;
+            // Written aside first, a crash never leaves half a file behind
+            let partial = path.with_extension("resume.part");
+            fs::write(&partial, bytes)?;
+            fs::rename(&partial, &path)
+        };
+        save()
+            .map_err(|e| TorrentError::Storage(format!("saving resume data to {:?}: {}", path, e)))
+    }
+}
+
+fn resume_path(resume_dir: &Path, infohash: &[u8; 20]) -> PathBuf {
+    resume_dir.join(format!("{}.resume", hex_encode(infohash)))
+}
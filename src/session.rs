@@ -0,0 +1,828 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{broadcast, broadcast::error::RecvError, mpsc, watch, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    controller::{download_file, PeerFeeds},
+    error::{Result, TorrentError},
+    event::{send_event, Event},
+    identity::{Identity, DEFAULT_CLIENT_PREFIX},
+    listener::PeerListener,
+    lsd::LocalDiscovery,
+    magnet::Magnet,
+    metadata::fetch_metadata,
+    mse::EncryptionPolicy,
+    peer::{TransportPreference, Transports},
+    picker::{FilePriority, PickOrder, PiecePicker},
+    rate_limit::{Bandwidth, RateLimits, RateSchedule},
+    reader::FileReader,
+    resume::ResumeData,
+    storage::Storage,
+    streaming::StreamingServer,
+    swarm::{ConnectionLimits, ConnectionSlots, PeerStatus},
+    torrent_file::TorrentFile,
+    PORT,
+};
+
+const EVENT_CAPACITY: usize = 1024;
+/// Peers handed over by the listener and not yet taken by their torrent,
+/// more are disconnected
+const INCOMING_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Directory torrent data is written to
+    pub download_dir: PathBuf,
+    pub connection_limits: ConnectionLimits,
+    /// BEP 14, announce torrents on the local network and find peers there.
+    /// Private torrents are never announced.
+    pub local_discovery: bool,
+    /// Accept connections from peers on the announced port, encrypted as
+    /// `encryption` asks
+    pub accept_incoming: bool,
+    pub transport: TransportPreference,
+    /// Message Stream Encryption of peer connections
+    pub encryption: EncryptionPolicy,
+    /// Start of the peer id, Azureus-style `-XX0100-` with a client code and
+    /// version. The rest is random for every session.
+    pub client_prefix: String,
+    /// Over every torrent, `Session::set_rate_limits` changes them later
+    pub rate_limits: RateLimits,
+    /// Of each peer connection
+    pub peer_rate_limits: RateLimits,
+    /// Alternative global limits for part of the day
+    pub rate_schedule: Option<RateSchedule>,
+    /// Where verified pieces of each torrent are saved when it stops, so a
+    /// later session starts from there. Nothing is saved when None.
+    pub resume_dir: Option<PathBuf>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            download_dir: PathBuf::from("."),
+            connection_limits: ConnectionLimits::default(),
+            local_discovery: true,
+            accept_incoming: true,
+            transport: TransportPreference::default(),
+            encryption: EncryptionPolicy::default(),
+            client_prefix: DEFAULT_CLIENT_PREFIX.to_string(),
+            rate_limits: RateLimits::default(),
+            peer_rate_limits: RateLimits::default(),
+            rate_schedule: None,
+            resume_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    FetchingMetadata,
+    Downloading,
+    Paused,
+    /// The session shut down before the download completed
+    Stopped,
+    Finished,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub infohash: [u8; 20],
+    /// Unknown for magnet links until metadata is received
+    pub name: Option<String>,
+    pub state: TorrentState,
+    pub verified_pieces: usize,
+    pub total_pieces: usize,
+}
+
+/// Downloads any number of torrents, sharing connection limits between them
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+struct SessionInner {
+    config: SessionConfig,
+    slots: ConnectionSlots,
+    events: broadcast::Sender<Event>,
+    torrents: StdMutex<HashMap<[u8; 20], Arc<TorrentShared>>>,
+    local_discovery: Option<Arc<LocalDiscovery>>,
+    listener: Option<Arc<PeerListener>>,
+    shutdown: watch::Sender<bool>,
+}
+
+struct TorrentShared {
+    infohash: [u8; 20],
+    display_name: Option<String>,
+    state: StdMutex<TorrentState>,
+    paused: watch::Sender<bool>,
+    events: broadcast::Sender<Event>,
+    /// Set once metadata is known
+    download: OnceLock<Download>,
+    /// By file index, files past the end are wanted with normal priority
+    file_priorities: StdMutex<Vec<FilePriority>>,
+    pick_order: StdMutex<PickOrder>,
+    task: StdMutex<Option<JoinHandle<()>>>,
+}
+
+/// What a torrent needs to download once its metadata is known
+struct Download {
+    torrent_file: TorrentFile,
+    picker: Arc<Mutex<PiecePicker>>,
+    storage: Storage,
+}
+
+impl TorrentShared {
+    fn state(&self) -> TorrentState {
+        self.state
+            .lock()
+            .expect("torrent state lock poisoned")
+            .clone()
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        *self.state.lock().expect("torrent state lock poisoned") = state;
+    }
+
+    fn send_event(&self, event: Event) {
+        send_event(&self.events, event);
+    }
+
+    fn stop_local_discovery(&self, local_discovery: &LocalDiscovery) {
+        if let Some(download) = self.download.get() {
+            for infohash in download.torrent_file.swarm_infohashes() {
+                local_discovery.remove(&infohash);
+            }
+        }
+    }
+
+    fn stop_listening(&self, listener: &PeerListener) {
+        if let Some(download) = self.download.get() {
+            for infohash in download.torrent_file.swarm_infohashes() {
+                listener.remove(&infohash);
+            }
+        }
+    }
+
+    fn pick_order(&self) -> PickOrder {
+        *self.pick_order.lock().expect("pick order lock poisoned")
+    }
+
+    fn file_priorities(&self, num_files: usize) -> Vec<FilePriority> {
+        let mut priorities = self
+            .file_priorities
+            .lock()
+            .expect("file priorities lock poisoned")
+            .clone();
+        priorities.resize(num_files, FilePriority::Normal);
+        priorities
+    }
+
+    /// Hand the file priorities to the picker and storage, once metadata is
+    /// known
+    async fn apply_file_priorities(&self) -> Result<()> {
+        let Some(download) = self.download.get() else {
+            return Ok(());
+        };
+        let priorities = self.file_priorities(download.torrent_file.files.len());
+        download
+            .picker
+            .lock()
+            .await
+            .set_file_priorities(&download.torrent_file.files, &priorities);
+        download
+            .storage
+            .set_file_priorities(&download.torrent_file, &priorities)
+    }
+}
+
+enum TorrentSource {
+    Metainfo(Box<TorrentFile>),
+    Magnet(Magnet),
+}
+
+/// What a torrent task needs from its session
+struct TorrentContext {
+    download_dir: PathBuf,
+    slots: ConnectionSlots,
+    local_discovery: Option<Arc<LocalDiscovery>>,
+    listener: Option<Arc<PeerListener>>,
+    resume_dir: Option<PathBuf>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let slots = ConnectionSlots::new(
+            config.connection_limits.clone(),
+            Transports::new(config.transport, config.encryption),
+            Identity::new(&config.client_prefix),
+            Bandwidth::new(
+                config.rate_limits,
+                config.peer_rate_limits,
+                config.rate_schedule,
+            ),
+        );
+        Session {
+            inner: Arc::new(SessionInner {
+                local_discovery: config
+                    .local_discovery
+                    .then(|| Arc::new(LocalDiscovery::new(PORT))),
+                listener: config
+                    .accept_incoming
+                    .then(|| Arc::new(PeerListener::new(slots.clone()))),
+                slots,
+                config,
+                events,
+                torrents: StdMutex::new(HashMap::new()),
+                shutdown: watch::channel(false).0,
+            }),
+        }
+    }
+
+    /// Events of every torrent of the session, from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    /// Start downloading a torrent, or return the existing handle if it was
+    /// already added
+    pub fn add_torrent(&self, torrent_file: TorrentFile) -> TorrentHandle {
+        let infohash = torrent_file.infohash;
+        let name = Some(torrent_file.name.clone());
+        self.add(
+            infohash,
+            name,
+            TorrentSource::Metainfo(Box::new(torrent_file)),
+        )
+    }
+
+    /// Start downloading the torrent encoded in the content of a .torrent file
+    pub fn add_torrent_bytes(&self, bytes: &[u8]) -> Result<TorrentHandle> {
+        Ok(self.add_torrent(TorrentFile::from_bytes(bytes)?))
+    }
+
+    /// Start downloading a magnet link, its metadata is fetched from peers first
+    pub fn add_magnet(&self, uri: &str) -> Result<TorrentHandle> {
+        let magnet = Magnet::parse(uri)?;
+        Ok(self.add(
+            magnet.infohash,
+            magnet.display_name.clone(),
+            TorrentSource::Magnet(magnet),
+        ))
+    }
+
+    pub fn torrent(&self, infohash: &[u8; 20]) -> Option<TorrentHandle> {
+        let torrents = self.inner.torrents.lock().expect("torrents lock poisoned");
+        torrents.get(infohash).map(|shared| self.handle(shared))
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        let torrents = self.inner.torrents.lock().expect("torrents lock poisoned");
+        torrents
+            .values()
+            .map(|shared| self.handle(shared))
+            .collect()
+    }
+
+    /// Limits over every torrent, outside of the schedule
+    pub fn rate_limits(&self) -> RateLimits {
+        self.inner.slots.bandwidth.global_limits()
+    }
+
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.inner.slots.bandwidth.set_global_limits(limits);
+    }
+
+    pub fn set_peer_rate_limits(&self, limits: RateLimits) {
+        self.inner.slots.bandwidth.set_peer_limits(limits);
+    }
+
+    pub fn set_rate_schedule(&self, schedule: Option<RateSchedule>) {
+        self.inner.slots.bandwidth.set_schedule(schedule);
+    }
+
+    /// Serve the files of the session's torrents over HTTP at `addr`, see
+    /// `StreamingServer`
+    pub async fn start_streaming(&self, addr: SocketAddr) -> Result<StreamingServer> {
+        let session = Arc::downgrade(&self.inner);
+        StreamingServer::bind(
+            addr,
+            Arc::new(move |infohash| {
+                let inner = session.upgrade()?;
+                let torrents = inner.torrents.lock().expect("torrents lock poisoned");
+                torrents.get(infohash).map(|shared| TorrentHandle {
+                    shared: shared.clone(),
+                    session: session.clone(),
+                })
+            }),
+        )
+        .await
+    }
+
+    /// Stop every torrent: peers are disconnected, pieces already downloaded
+    /// are written, resume data is saved and trackers are told we leave.
+    /// Returns once all torrents are stopped.
+    pub async fn shutdown(&self) {
+        self.inner.shutdown.send_replace(true);
+        let tasks: Vec<JoinHandle<()>> = {
+            let torrents = self.inner.torrents.lock().expect("torrents lock poisoned");
+            torrents
+                .values()
+                .filter_map(|shared| {
+                    shared
+                        .task
+                        .lock()
+                        .expect("torrent task lock poisoned")
+                        .take()
+                })
+                .collect()
+        };
+        for task in tasks {
+            // Only removed torrents are aborted, and those are no longer listed
+            let _ = task.await;
+        }
+    }
+
+    fn handle(&self, shared: &Arc<TorrentShared>) -> TorrentHandle {
+        TorrentHandle {
+            shared: shared.clone(),
+            session: Arc::downgrade(&self.inner),
+        }
+    }
+
+    fn add(
+        &self,
+        infohash: [u8; 20],
+        display_name: Option<String>,
+        source: TorrentSource,
+    ) -> TorrentHandle {
+        let mut torrents = self.inner.torrents.lock().expect("torrents lock poisoned");
+        if let Some(shared) = torrents.get(&infohash) {
+            return self.handle(shared);
+        }
+
+        let shared = Arc::new(TorrentShared {
+            infohash,
+            display_name,
+            state: StdMutex::new(TorrentState::Downloading),
+            paused: watch::channel(false).0,
+            events: self.inner.events.clone(),
+            download: OnceLock::new(),
+            file_priorities: StdMutex::new(Vec::new()),
+            pick_order: StdMutex::new(PickOrder::default()),
+            task: StdMutex::new(None),
+        });
+        let context = TorrentContext {
+            download_dir: self.inner.config.download_dir.clone(),
+            slots: self.inner.slots.clone(),
+            local_discovery: self.inner.local_discovery.clone(),
+            listener: self.inner.listener.clone(),
+            resume_dir: self.inner.config.resume_dir.clone(),
+            shutdown: self.inner.shutdown.subscribe(),
+        };
+        let task = tokio::spawn(run_torrent(shared.clone(), source, context));
+        *shared.task.lock().expect("torrent task lock poisoned") = Some(task);
+        torrents.insert(infohash, shared.clone());
+
+        shared.send_event(Event::TorrentAdded { infohash });
+        self.handle(&shared)
+    }
+}
+
+/// Control over one torrent of a `Session`
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<TorrentShared>,
+    session: Weak<SessionInner>,
+}
+
+impl TorrentHandle {
+    pub fn infohash(&self) -> [u8; 20] {
+        self.shared.infohash
+    }
+
+    /// Metadata of the torrent, not available yet for magnet links still
+    /// fetching it
+    pub fn torrent_file(&self) -> Option<TorrentFile> {
+        self.shared
+            .download
+            .get()
+            .map(|download| download.torrent_file.clone())
+    }
+
+    /// Priority of each file in torrent order, empty until metadata is known
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.shared
+            .download
+            .get()
+            .map(|download| {
+                self.shared
+                    .file_priorities(download.torrent_file.files.len())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Change how much the file at `index` of the torrent's files is wanted,
+    /// possibly before metadata is known. Finished torrents don't start again
+    /// for files that are no longer skipped.
+    pub async fn set_file_priority(&self, index: usize, priority: FilePriority) -> Result<()> {
+        if let Some(download) = self.shared.download.get() {
+            if index >= download.torrent_file.files.len() {
+                return Err(TorrentError::Metainfo(format!(
+                    "torrent has no file {}",
+                    index
+                )));
+            }
+        }
+        {
+            let mut priorities = self
+                .shared
+                .file_priorities
+                .lock()
+                .expect("file priorities lock poisoned");
+            if priorities.len() <= index {
+                priorities.resize(index + 1, FilePriority::Normal);
+            }
+            priorities[index] = priority;
+        }
+        self.shared.apply_file_priorities().await
+    }
+
+    /// Reader over the file at `index`, see `FileReader`. Magnet links wait
+    /// for their metadata first.
+    pub async fn open_file(&self, index: usize) -> Result<FileReader> {
+        let download = self.download().await?;
+        let file = download
+            .torrent_file
+            .files
+            .get(index)
+            .filter(|file| !file.attributes.padding)
+            .ok_or_else(|| TorrentError::Metainfo(format!("torrent has no file {}", index)))?;
+        Ok(FileReader::new(
+            self.clone(),
+            file,
+            download.torrent_file.piece_length,
+        ))
+    }
+
+    pub fn pick_order(&self) -> PickOrder {
+        self.shared.pick_order()
+    }
+
+    /// Download in order, or the edges of files first, e.g. to play media
+    /// while it downloads
+    pub async fn set_pick_order(&self, order: PickOrder) {
+        *self
+            .shared
+            .pick_order
+            .lock()
+            .expect("pick order lock poisoned") = order;
+        if let Some(download) = self.shared.download.get() {
+            download.picker.lock().await.set_order(order);
+        }
+    }
+
+    /// Disconnect from every peer, progress is kept for `resume`
+    pub fn pause(&self) {
+        if self.is_done() {
+            return;
+        }
+        if !self.shared.paused.send_replace(true) {
+            self.shared.send_event(Event::TorrentPaused {
+                infohash: self.shared.infohash,
+            });
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.is_done() {
+            return;
+        }
+        if self.shared.paused.send_replace(false) {
+            self.shared.send_event(Event::TorrentResumed {
+                infohash: self.shared.infohash,
+            });
+        }
+    }
+
+    /// Stop the torrent and forget it, downloaded data stays on disk
+    pub fn remove(&self) {
+        if let Some(task) = self
+            .shared
+            .task
+            .lock()
+            .expect("torrent task lock poisoned")
+            .take()
+        {
+            task.abort();
+        }
+        if let Some(session) = self.session.upgrade() {
+            if let Some(local_discovery) = &session.local_discovery {
+                self.shared.stop_local_discovery(local_discovery);
+            }
+            if let Some(listener) = &session.listener {
+                self.shared.stop_listening(listener);
+            }
+            session
+                .slots
+                .bandwidth
+                .remove_torrent(&self.shared.infohash);
+            session
+                .torrents
+                .lock()
+                .expect("torrents lock poisoned")
+                .remove(&self.shared.infohash);
+        }
+        self.shared.send_event(Event::TorrentRemoved {
+            infohash: self.shared.infohash,
+        });
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.session
+            .upgrade()
+            .map(|session| {
+                session
+                    .slots
+                    .bandwidth
+                    .torrent_limits(&self.shared.infohash)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Limits of this torrent, on top of the global ones
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        if let Some(session) = self.session.upgrade() {
+            session
+                .slots
+                .bandwidth
+                .set_torrent_limits(self.shared.infohash, limits);
+        }
+    }
+
+    /// Peers connected right now, with the client they run
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.session
+            .upgrade()
+            .map(|session| session.slots.peers(&self.shared.infohash))
+            .unwrap_or_default()
+    }
+
+    /// Download the piece `index` within `deadline` from now, before pieces
+    /// needed later or not needed by anyone
+    pub async fn set_piece_deadline(&self, index: usize, deadline: Duration) {
+        if let Some(download) = self.shared.download.get() {
+            download
+                .picker
+                .lock()
+                .await
+                .set_piece_deadline(index, Instant::now() + deadline);
+        }
+    }
+
+    /// Data of the piece `index`, needed right away and waited for when it is
+    /// missing. Fails if the torrent stops before the piece is verified.
+    pub async fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+        let download = self.download().await?;
+        if index >= download.torrent_file.num_pieces() {
+            return Err(TorrentError::Unavailable(format!(
+                "torrent has no piece {}",
+                index
+            )));
+        }
+        // Subscribed first so that no verification goes unnoticed
+        let mut events = self.shared.events.subscribe();
+        self.set_piece_deadline(index, Duration::ZERO).await;
+        loop {
+            if download.picker.lock().await.is_verified(index) {
+                return download
+                    .storage
+                    .read_piece(index, download.torrent_file.calculate_piece_size(index));
+            }
+            self.wait_for_progress(&mut events, &format!("piece {}", index))
+                .await?;
+        }
+    }
+
+    /// Download state, waited for until metadata is known
+    async fn download(&self) -> Result<&Download> {
+        let mut events = self.shared.events.subscribe();
+        loop {
+            if let Some(download) = self.shared.download.get() {
+                return Ok(download);
+            }
+            self.wait_for_progress(&mut events, "metadata").await?;
+        }
+    }
+
+    /// Wait for the next event, `missing` having not arrived yet. Fails when
+    /// the torrent stopped and it never will.
+    async fn wait_for_progress(
+        &self,
+        events: &mut broadcast::Receiver<Event>,
+        missing: &str,
+    ) -> Result<()> {
+        match self.shared.state() {
+            TorrentState::Stopped | TorrentState::Finished => {
+                return Err(TorrentError::Unavailable(format!(
+                    "{} was not downloaded",
+                    missing
+                )));
+            }
+            TorrentState::Failed(error) => return Err(TorrentError::Unavailable(error)),
+            _ => {}
+        }
+        // Events of any torrent wake us up, the state is checked again
+        match events.recv().await {
+            Ok(Event::TorrentRemoved { infohash }) if infohash == self.shared.infohash => {
+                Err(TorrentError::Unavailable("torrent was removed".to_string()))
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => Ok(()),
+            Err(RecvError::Closed) => Err(TorrentError::Unavailable("session is gone".to_string())),
+        }
+    }
+
+    pub async fn status(&self) -> TorrentStatus {
+        let (name, verified_pieces, total_pieces) = match self.shared.download.get() {
+            Some(download) => (
+                Some(download.torrent_file.name.clone()),
+                download.picker.lock().await.num_verified(),
+                download.torrent_file.num_pieces(),
+            ),
+            None => (self.shared.display_name.clone(), 0, 0),
+        };
+        TorrentStatus {
+            infohash: self.shared.infohash,
+            name,
+            state: self.shared.state(),
+            verified_pieces,
+            total_pieces,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(
+            self.shared.state(),
+            TorrentState::Stopped | TorrentState::Finished | TorrentState::Failed(_)
+        )
+    }
+}
+
+async fn run_torrent(shared: Arc<TorrentShared>, source: TorrentSource, context: TorrentContext) {
+    let result = drive_torrent(&shared, source, &context).await;
+    // Finished torrents are not seeded, nobody should be told about them
+    if let Some(local_discovery) = &context.local_discovery {
+        shared.stop_local_discovery(local_discovery);
+    }
+    if let Some(listener) = &context.listener {
+        shared.stop_listening(listener);
+    }
+    match result {
+        Ok(false) => {
+            shared.set_state(TorrentState::Stopped);
+            shared.send_event(Event::TorrentStopped {
+                infohash: shared.infohash,
+            });
+        }
+        Ok(true) => {
+            shared.set_state(TorrentState::Finished);
+            shared.send_event(Event::TorrentFinished {
+                infohash: shared.infohash,
+            });
+        }
+        Err(e) => {
+            shared.set_state(TorrentState::Failed(e.to_string()));
+            shared.send_event(Event::TorrentFailed {
+                infohash: shared.infohash,
+                error: e.to_string(),
+            });
+        }
+    }
+}
+
+/// Returns whether the download completed, it did not when the session shut
+/// down first
+async fn drive_torrent(
+    shared: &TorrentShared,
+    source: TorrentSource,
+    context: &TorrentContext,
+) -> Result<bool> {
+    let mut shutdown = context.shutdown.clone();
+    let torrent_file = match source {
+        TorrentSource::Metainfo(torrent_file) => *torrent_file,
+        TorrentSource::Magnet(magnet) => {
+            shared.set_state(TorrentState::FetchingMetadata);
+            let torrent_file = tokio::select! {
+                result = fetch_metadata(&magnet, &context.slots) => result?,
+                _ = shutdown_requested(&mut shutdown) => return Ok(false),
+            };
+            torrent_file
+        }
+    };
+    let storage = Storage::new(&context.download_dir, &torrent_file);
+    let mut picker = PiecePicker::new(&torrent_file);
+    if let Some(resume_dir) = &context.resume_dir {
+        if let Some(resume_data) = ResumeData::load(resume_dir, &torrent_file.infohash) {
+            resume_data.restore(&mut picker, &storage, &torrent_file)?;
+        }
+    }
+    let download = shared.download.get_or_init(|| Download {
+        torrent_file,
+        picker: Arc::new(Mutex::new(picker)),
+        storage,
+    });
+    let (torrent_file, picker, storage) =
+        (&download.torrent_file, &download.picker, &download.storage);
+    shared.send_event(Event::MetadataReceived {
+        infohash: shared.infohash,
+        name: torrent_file.name.clone(),
+    });
+    // Set after `download` so changes made meanwhile are not missed
+    shared.apply_file_priorities().await?;
+    picker.lock().await.set_order(shared.pick_order());
+
+    // BEP 27 forbids looking for peers of private torrents anywhere but
+    // their trackers
+    let local_discovery = context
+        .local_discovery
+        .as_ref()
+        .filter(|_| !torrent_file.private);
+    if let Some(local_discovery) = local_discovery {
+        for infohash in torrent_file.swarm_infohashes() {
+            local_discovery.add(infohash);
+        }
+    }
+    let mut incoming = context.listener.as_ref().map(|listener| {
+        let (sender, receiver) = mpsc::channel(INCOMING_CAPACITY);
+        for infohash in torrent_file.swarm_infohashes() {
+            listener.add(infohash, sender.clone());
+        }
+        receiver
+    });
+
+    let mut paused = shared.paused.subscribe();
+    loop {
+        let is_paused = *paused.borrow_and_update();
+        if is_paused {
+            shared.set_state(TorrentState::Paused);
+            tokio::select! {
+                // The sender lives as long as `shared`
+                _ = async { paused.wait_for(|paused| !paused).await.is_ok() } => {}
+                _ = shutdown_requested(&mut shutdown) => return Ok(false),
+            }
+        }
+        shared.set_state(TorrentState::Downloading);
+
+        let stop = async {
+            tokio::select! {
+                _ = async { paused.wait_for(|paused| *paused).await.is_ok() } => {}
+                _ = shutdown_requested(&mut shutdown) => {}
+            }
+        };
+        let result = download_file(
+            torrent_file,
+            picker,
+            storage,
+            &context.slots,
+            &shared.events,
+            PeerFeeds {
+                local: local_discovery.map(|local_discovery| local_discovery.subscribe()),
+                incoming: incoming.as_mut(),
+            },
+            stop,
+        )
+        .await;
+        if let Some(resume_dir) = &context.resume_dir {
+            ResumeData::new(&*picker.lock().await).save(resume_dir, &torrent_file.infohash)?;
+        }
+        result?;
+
+        if picker.lock().await.is_complete() {
+            // Links and permissions only make sense once files are whole
+            storage.apply_attributes()?;
+            return Ok(true);
+        }
+        if *shutdown.borrow() {
+            return Ok(false);
+        }
+    }
+}
+
+/// Resolves once the session shuts down
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+        // The session was dropped without shutting down, torrents go on
+        std::future::pending::<()>().await;
+    }
+}
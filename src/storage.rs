@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as StdMutex,
+    },
+};
+
+use crate::{
+    error::{Result, TorrentError},
+    infohash::hex_encode,
+    picker::FilePriority,
+    torrent_file::{FileInfo, TorrentFile},
+};
+
+struct StorageFile {
+    path: PathBuf,
+    length: usize,
+    offset: usize,
+    padding: bool,
+    executable: bool,
+    /// Link target relative to the directory holding the link
+    symlink: Option<PathBuf>,
+    /// Never created, its share of boundary pieces goes to the part file
+    skipped: AtomicBool,
+}
+
+impl StorageFile {
+    fn is_skipped(&self) -> bool {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Maps pieces to the files they span inside the download directory
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: usize,
+    /// Only torrents being downloaded have one, other storages never skip files
+    part_file: Option<StdMutex<PartFile>>,
+}
+
+impl Storage {
+    pub fn new(download_dir: &Path, torrent_file: &TorrentFile) -> Self {
+        let mut storage =
+            Storage::from_files(download_dir, &torrent_file.files, torrent_file.piece_length);
+        let part_path = download_dir.join(format!(".{}.parts", hex_encode(&torrent_file.infohash)));
+        storage.part_file = Some(StdMutex::new(PartFile::new(
+            part_path,
+            torrent_file.piece_length,
+            torrent_file.num_pieces(),
+        )));
+        storage
+    }
+
+    pub fn from_files(download_dir: &Path, files: &[FileInfo], piece_length: usize) -> Self {
+        Storage {
+            files: files
+                .iter()
+                .map(|file| StorageFile {
+                    path: download_dir.join(&file.path),
+                    length: file.length,
+                    offset: file.offset,
+                    padding: file.attributes.padding,
+                    executable: file.attributes.executable,
+                    symlink: file.attributes.symlink.as_ref().map(|target| {
+                        // Targets start at the torrent's directory, the first
+                        // component of the path
+                        let depth = file.path.components().count().saturating_sub(2);
+                        std::iter::repeat_n(Path::new(".."), depth)
+                            .collect::<PathBuf>()
+                            .join(target)
+                    }),
+                    skipped: AtomicBool::new(false),
+                })
+                .collect(),
+            piece_length,
+            part_file: None,
+        }
+    }
+
+    /// Priority of each file, in torrent order. Pieces kept in the part file
+    /// are written to the files that are no longer skipped.
+    pub fn set_file_priorities(
+        &self,
+        torrent_file: &TorrentFile,
+        priorities: &[FilePriority],
+    ) -> Result<()> {
+        for (file, priority) in self.files.iter().zip(priorities) {
+            file.skipped
+                .store(*priority == FilePriority::Skip, Ordering::Relaxed);
+        }
+        let stored = self.part_file(|part_file| part_file.pieces())?;
+        for index in stored.unwrap_or_default() {
+            let buf = self.read_piece(index, torrent_file.calculate_piece_size(index))?;
+            self.write_piece(index, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the pieces of the part file that no longer match their hash, a
+    /// crash may have left them half written
+    pub fn verify_part_file(&self, torrent_file: &TorrentFile) -> Result<()> {
+        let stored = self.part_file(|part_file| part_file.pieces())?;
+        for index in stored.unwrap_or_default() {
+            let length = torrent_file.calculate_piece_size(index);
+            let buf = self
+                .part_file(|part_file| part_file.read(index, length))
+                .ok()
+                .flatten()
+                .flatten();
+            if !buf.is_some_and(|buf| torrent_file.verify_piece(index, &buf)) {
+                self.part_file(|part_file| part_file.remove(index))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a verified piece. The share of skipped files is left out, the
+    /// whole piece is then kept in the part file.
+    pub fn write_piece(&self, index: usize, buf: &[u8]) -> Result<()> {
+        let piece_start = index * self.piece_length;
+        let mut overlaps_skipped = false;
+        for (file, range_start, buf_range) in self.spans(piece_start, buf.len()) {
+            if file.is_skipped() {
+                overlaps_skipped = true;
+                continue;
+            }
+            let write = || -> std::io::Result<()> {
+                if let Some(parent) = file.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut handle = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&file.path)?;
+                handle.seek(SeekFrom::Start(range_start as u64))?;
+                handle.write_all(&buf[buf_range])
+            };
+            write().map_err(|e| {
+                TorrentError::Storage(format!("writing piece {} to {:?}: {}", index, file.path, e))
+            })?;
+        }
+        if overlaps_skipped {
+            self.part_file(|part_file| part_file.write(index, buf))?;
+        } else {
+            // An older copy would be read instead of the files
+            self.part_file(|part_file| part_file.remove(index))?;
+        }
+        Ok(())
+    }
+
+    pub fn read_piece(&self, index: usize, length: usize) -> Result<Vec<u8>> {
+        if let Some(buf) = self
+            .part_file(|part_file| part_file.read(index, length))?
+            .flatten()
+        {
+            return Ok(buf);
+        }
+        let piece_start = index * self.piece_length;
+        let mut buf = vec![0u8; length];
+        for (file, range_start, buf_range) in self.spans(piece_start, length) {
+            let read = || -> std::io::Result<()> {
+                let mut handle = File::open(&file.path)?;
+                handle.seek(SeekFrom::Start(range_start as u64))?;
+                handle.read_exact(&mut buf[buf_range])
+            };
+            read().map_err(|e| {
+                TorrentError::Storage(format!(
+                    "reading piece {} from {:?}: {}",
+                    index, file.path, e
+                ))
+            })?;
+        }
+        Ok(buf)
+    }
+
+    /// Whether the files a piece spans are on disk and long enough to hold
+    /// it, its data may still be wrong
+    pub fn has_piece(&self, index: usize, length: usize) -> bool {
+        let stored = self.part_file(|part_file| Ok(part_file.pieces()?.contains(&index)));
+        if stored.is_ok_and(|stored| stored == Some(true)) {
+            return true;
+        }
+        let piece_start = index * self.piece_length;
+        self.spans(piece_start, length)
+            .all(|(file, range_start, buf_range)| {
+                fs::metadata(&file.path)
+                    .is_ok_and(|metadata| metadata.len() >= (range_start + buf_range.len()) as u64)
+            })
+    }
+
+    /// Make executable files executable and create symbolic links, once
+    /// every piece is on disk
+    pub fn apply_attributes(&self) -> Result<()> {
+        for file in self.files.iter().filter(|file| !file.is_skipped()) {
+            if let Some(target) = &file.symlink {
+                create_symlink(&file.path, target)?;
+            } else if file.executable {
+                set_executable(&file.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` on the part file, None without one
+    fn part_file<T>(&self, f: impl FnOnce(&mut PartFile) -> io::Result<T>) -> Result<Option<T>> {
+        let Some(part_file) = &self.part_file else {
+            return Ok(None);
+        };
+        let mut part_file = part_file.lock().expect("part file lock poisoned");
+        f(&mut part_file).map(Some).map_err(|e| {
+            TorrentError::Storage(format!("using part file {:?}: {}", part_file.path, e))
+        })
+    }
+
+    /// Files overlapping `length` bytes of torrent data starting at `start`,
+    /// with the position inside the file and the matching range of the data.
+    /// Padding files are left out, their zeroes are never stored.
+    fn spans(
+        &self,
+        start: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (&StorageFile, usize, std::ops::Range<usize>)> {
+        let end = start + length;
+        self.files.iter().filter_map(move |file| {
+            let file_end = file.offset + file.length;
+            if file.padding || file_end <= start || file.offset >= end {
+                return None;
+            }
+            let from = std::cmp::max(start, file.offset);
+            let to = std::cmp::min(end, file_end);
+            Some((file, from - file.offset, from - start..to - start))
+        })
+    }
+}
+
+/// Pieces overlapping skipped files, kept whole outside of the download so
+/// skipped files are never created. Each slot holds a piece index followed
+/// by the piece data.
+struct PartFile {
+    path: PathBuf,
+    piece_length: usize,
+    num_pieces: usize,
+    /// Read from the file on first use
+    slots: Option<Slots>,
+}
+
+/// Index of slots whose piece was dropped, no piece has it
+const FREE_SLOT: u32 = u32::MAX;
+
+#[derive(Default)]
+struct Slots {
+    /// Slot of each stored piece
+    pieces: HashMap<usize, u64>,
+    /// Slots of dropped pieces, filled again before the file grows
+    free: Vec<u64>,
+    /// Whole slots in the file, one cut short by a crash is written again
+    len: u64,
+}
+
+impl PartFile {
+    fn new(path: PathBuf, piece_length: usize, num_pieces: usize) -> Self {
+        PartFile {
+            path,
+            piece_length,
+            num_pieces,
+            slots: None,
+        }
+    }
+
+    fn slot_length(&self) -> u64 {
+        4 + self.piece_length as u64
+    }
+
+    fn slots(&mut self) -> io::Result<&mut Slots> {
+        if self.slots.is_none() {
+            let mut slots = Slots::default();
+            match File::open(&self.path) {
+                Ok(mut handle) => {
+                    slots.len = handle.metadata()?.len() / self.slot_length();
+                    for slot in 0..slots.len {
+                        let mut index = [0u8; 4];
+                        handle.seek(SeekFrom::Start(slot * self.slot_length()))?;
+                        handle.read_exact(&mut index)?;
+                        let index = u32::from_be_bytes(index) as usize;
+                        if index < self.num_pieces {
+                            // The last copy of a piece is the one kept
+                            let replaced = slots.pieces.insert(index, slot);
+                            slots.free.extend(replaced);
+                        } else {
+                            slots.free.push(slot);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            self.slots = Some(slots);
+        }
+        Ok(self.slots.as_mut().expect("slots were just read"))
+    }
+
+    fn pieces(&mut self) -> io::Result<Vec<usize>> {
+        Ok(self.slots()?.pieces.keys().copied().collect())
+    }
+
+    fn open_for_writing(&self) -> io::Result<File> {
+        // Pieces wanted only for reading may come before any file is created
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+    }
+
+    fn write(&mut self, index: usize, buf: &[u8]) -> io::Result<()> {
+        let slot_length = self.slot_length();
+        let slots = self.slots()?;
+        let slot = match slots.pieces.get(&index) {
+            Some(&slot) => slot,
+            None => slots.free.pop().unwrap_or(slots.len),
+        };
+        let mut handle = self.open_for_writing()?;
+        handle.seek(SeekFrom::Start(slot * slot_length))?;
+        handle.write_all(&(index as u32).to_be_bytes())?;
+        handle.write_all(buf)?;
+        // Short pieces fill their slot too, only crashes leave one cut short
+        handle.write_all(&vec![0; self.piece_length.saturating_sub(buf.len())])?;
+        let slots = self.slots()?;
+        slots.pieces.insert(index, slot);
+        slots.len = slots.len.max(slot + 1);
+        Ok(())
+    }
+
+    /// Forget `index` on disk too, its slot is reused
+    fn remove(&mut self, index: usize) -> io::Result<()> {
+        let slot_length = self.slot_length();
+        let Some(slot) = self.slots()?.pieces.remove(&index) else {
+            return Ok(());
+        };
+        let mut handle = self.open_for_writing()?;
+        handle.seek(SeekFrom::Start(slot * slot_length))?;
+        handle.write_all(&FREE_SLOT.to_be_bytes())?;
+        self.slots()?.free.push(slot);
+        Ok(())
+    }
+
+    fn read(&mut self, index: usize, length: usize) -> io::Result<Option<Vec<u8>>> {
+        let slot_length = self.slot_length();
+        let Some(&slot) = self.slots()?.pieces.get(&index) else {
+            return Ok(None);
+        };
+        let mut handle = File::open(&self.path)?;
+        handle.seek(SeekFrom::Start(slot * slot_length + 4))?;
+        let mut buf = vec![0u8; length];
+        handle.read_exact(&mut buf)?;
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let set = || -> std::io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        // Executable by whoever may read it
+        let mode = permissions.mode();
+        permissions.set_mode(mode | (mode & 0o444) >> 2);
+        fs::set_permissions(path, permissions)
+    };
+    set().map_err(|e| TorrentError::Storage(format!("making {:?} executable: {}", path, e)))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn create_symlink(path: &Path, target: &Path) -> Result<()> {
+    let create = || -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Seeding an existing download finds the link already there
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            fs::remove_file(path)?;
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target, path)?;
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_file(target, path)?;
+        Ok(())
+    };
+    create()
+        .map_err(|e| TorrentError::Storage(format!("linking {:?} to {:?}: {}", path, target, e)))
+}
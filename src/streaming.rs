@@ -0,0 +1,262 @@
+use std::{io::SeekFrom, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    error::{Result, TorrentError},
+    infohash::{hex_decode, hex_encode},
+    session::TorrentHandle,
+};
+
+/// Longest request line and headers accepted
+const MAX_HEAD_SIZE: usize = 8192;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Torrent of the session with the given infohash
+pub(crate) type TorrentLookup = Arc<dyn Fn(&[u8; 20]) -> Option<TorrentHandle> + Send + Sync>;
+
+/// HTTP server streaming the files of a session's torrents while they
+/// download, each at `/<hex infohash>/<file index>`. Range requests are
+/// supported, missing pieces they ask for are downloaded first and waited for.
+/// The server stops when dropped.
+pub struct StreamingServer {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl StreamingServer {
+    pub(crate) async fn bind(addr: SocketAddr, lookup: TorrentLookup) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                let lookup = lookup.clone();
+                tokio::spawn(async move {
+                    // The client went away or asked for data that won't come,
+                    // all there is to do is closing the connection
+                    let _ = serve_request(stream, &lookup).await;
+                });
+            }
+        });
+        Ok(StreamingServer { local_addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// URL of the file at `index` of the torrent `infohash`
+    pub fn url(&self, infohash: &[u8; 20], index: usize) -> String {
+        format!(
+            "http://{}/{}/{}",
+            self.local_addr,
+            hex_encode(infohash),
+            index
+        )
+    }
+}
+
+impl Drop for StreamingServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+enum ByteRange {
+    Whole,
+    Part(Range<usize>),
+    Unsatisfiable,
+}
+
+/// Answer a single request, the connection is closed afterwards
+async fn serve_request(mut stream: TcpStream, lookup: &TorrentLookup) -> Result<()> {
+    let head = time::timeout(HEAD_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| TorrentError::Io(std::io::ErrorKind::TimedOut.into()))??;
+    let Some(request) = parse_request(&head) else {
+        return respond_error(&mut stream, "400 Bad Request").await;
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        return respond_error(&mut stream, "405 Method Not Allowed").await;
+    }
+    let Some((infohash, index)) = parse_path(&request.path) else {
+        return respond_error(&mut stream, "404 Not Found").await;
+    };
+    let Some(handle) = lookup(&infohash) else {
+        return respond_error(&mut stream, "404 Not Found").await;
+    };
+    let Some(torrent_file) = handle.torrent_file() else {
+        return respond_error(&mut stream, "503 Service Unavailable").await;
+    };
+    let Some(file) = torrent_file
+        .files
+        .get(index)
+        .filter(|file| !file.attributes.padding)
+    else {
+        return respond_error(&mut stream, "404 Not Found").await;
+    };
+
+    let (status, range) = match byte_range(request.range.as_deref(), file.length) {
+        ByteRange::Whole => ("200 OK", 0..file.length),
+        ByteRange::Part(range) => ("206 Partial Content", range),
+        ByteRange::Unsatisfiable => {
+            let head = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                file.length
+            );
+            stream.write_all(head.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        status,
+        content_type(&file.path.to_string_lossy()),
+        range.len()
+    );
+    if status.starts_with("206") {
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            range.start,
+            range.end - 1,
+            file.length
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    if request.method == "HEAD" {
+        return Ok(());
+    }
+
+    // Missing pieces are waited for as the copy reaches them
+    let mut reader = handle.open_file(index).await?;
+    reader.seek(SeekFrom::Start(range.start as u64)).await?;
+    tokio::io::copy(&mut reader.take(range.len() as u64), &mut stream).await?;
+    Ok(())
+}
+
+/// Request line and headers, up to the empty line ending them
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(TorrentError::Io(std::io::ErrorKind::InvalidData.into()));
+        }
+        // Byte by byte would be slow, requests have no body to read past
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(TorrentError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        head.extend_from_slice(&buf[..read]);
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            head.truncate(end + 4);
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim().to_string());
+    Some(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+/// Infohash and file index of `/<hex infohash>/<file index>`, anything after
+/// the index such as a file name is ignored
+fn parse_path(path: &str) -> Option<([u8; 20], usize)> {
+    let path = path.split('?').next()?;
+    let mut segments = path.trim_start_matches('/').split('/');
+    let infohash = hex_decode(segments.next()?)?.try_into().ok()?;
+    let index = segments.next()?.parse().ok()?;
+    Some((infohash, index))
+}
+
+/// Bytes of a file of `length` bytes asked for by a `Range` header. Only
+/// single ranges are supported, the whole file is sent for the others.
+fn byte_range(header: Option<&str>, length: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|header| header.strip_prefix("bytes=")) else {
+        return ByteRange::Whole;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Whole;
+    };
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // Last bytes of the file
+        (Err(_), Ok(suffix)) if start.is_empty() => length.saturating_sub(suffix)..length,
+        (Ok(start), Err(_)) if end.is_empty() => start..length,
+        // Ends past the file, up to usize::MAX, stop at its last byte
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(length),
+        _ => return ByteRange::Whole,
+    };
+    if range.start >= length || range.is_empty() {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Part(range)
+    }
+}
+
+async fn respond_error(stream: &mut TcpStream, status: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        status.len(),
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Media players rely on it more than on the content itself
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "txt" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::{
+    identity::{decode_peer_id, Client, Identity},
+    peer::Transports,
+    rate_limit::{Bandwidth, PeerBandwidth},
+    tracker::Peer,
+};
+
+/// Delay before the first reconnection attempt, doubled after each failure
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// Consecutive failures after which a peer is dropped for good
+pub const MAX_PEER_FAILURES: u32 = 5;
+/// Score lost per failure, in downloaded bytes
+const FAILURE_PENALTY: i64 = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// Open connections over every torrent
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// Connections still connecting or handshaking over every torrent
+    pub max_half_open: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            max_half_open: 16,
+        }
+    }
+}
+
+/// Status of open connections by torrent and peer address
+type ConnectedPeers = HashMap<([u8; 20], SocketAddr), PeerStatus>;
+
+/// Connection slots, transports, identity and rate limits shared by every
+/// torrent of the process
+#[derive(Clone)]
+pub struct ConnectionSlots {
+    pub limits: ConnectionLimits,
+    pub transports: Transports,
+    pub identity: Identity,
+    pub bandwidth: Bandwidth,
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+    connected: Arc<StdMutex<ConnectedPeers>>,
+}
+
+impl ConnectionSlots {
+    pub fn new(
+        limits: ConnectionLimits,
+        transports: Transports,
+        identity: Identity,
+        bandwidth: Bandwidth,
+    ) -> Self {
+        ConnectionSlots {
+            transports,
+            identity,
+            bandwidth,
+            connections: Arc::new(Semaphore::new(limits.max_connections)),
+            half_open: Arc::new(Semaphore::new(limits.max_half_open)),
+            connected: Arc::new(StdMutex::new(HashMap::new())),
+            limits,
+        }
+    }
+
+    /// Record a connection to `addr` for the torrent `infohash` until the
+    /// returned handle is dropped
+    pub fn register(
+        &self,
+        infohash: [u8; 20],
+        addr: SocketAddr,
+        peer_id: [u8; 20],
+        extension_client: Option<String>,
+    ) -> ConnectedPeer {
+        let status = PeerStatus {
+            addr,
+            peer_id,
+            client: decode_peer_id(&peer_id),
+            extension_client,
+        };
+        self.connected_peers().insert((infohash, addr), status);
+        ConnectedPeer {
+            connected: self.connected.clone(),
+            key: (infohash, addr),
+            bandwidth: self.bandwidth.peer(infohash),
+        }
+    }
+
+    /// Peers the torrent `infohash` is connected to
+    pub fn peers(&self, infohash: &[u8; 20]) -> Vec<PeerStatus> {
+        self.connected_peers()
+            .iter()
+            .filter(|((peer_infohash, _), _)| peer_infohash == infohash)
+            .map(|(_, status)| status.clone())
+            .collect()
+    }
+
+    fn connected_peers(&self) -> std::sync::MutexGuard<'_, ConnectedPeers> {
+        self.connected
+            .lock()
+            .expect("connected peers lock poisoned")
+    }
+
+    /// Held for the whole lifetime of a connection
+    pub fn try_acquire_connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// Held while connecting and handshaking only
+    pub async fn acquire_half_open(&self) -> OwnedSemaphorePermit {
+        self.half_open
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("half open semaphore is never closed")
+    }
+}
+
+/// What is known of a connected peer
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    /// Sent in the BitTorrent handshake
+    pub peer_id: [u8; 20],
+    /// Software decoded from the peer id
+    pub client: Option<Client>,
+    /// Client name and version from the extension handshake (BEP 10)
+    pub extension_client: Option<String>,
+}
+
+/// Connection recorded in `ConnectionSlots`, forgotten once dropped
+pub struct ConnectedPeer {
+    connected: Arc<StdMutex<ConnectedPeers>>,
+    key: ([u8; 20], SocketAddr),
+    bandwidth: PeerBandwidth,
+}
+
+impl ConnectedPeer {
+    pub fn addr(&self) -> SocketAddr {
+        self.key.1
+    }
+
+    pub fn bandwidth(&self) -> &PeerBandwidth {
+        &self.bandwidth
+    }
+
+    /// Extension handshakes may come at any time, and more than once
+    pub fn set_extension_client(&self, extension_client: Option<String>) {
+        if let Some(status) = self
+            .connected
+            .lock()
+            .expect("connected peers lock poisoned")
+            .get_mut(&self.key)
+        {
+            status.extension_client = extension_client;
+        }
+    }
+}
+
+impl Drop for ConnectedPeer {
+    fn drop(&mut self) {
+        self.connected
+            .lock()
+            .expect("connected peers lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+/// Where a peer address was learned from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    /// BEP 14 local service discovery
+    LocalDiscovery,
+    /// BEP 11 peer exchange
+    PeerExchange,
+    /// BEP 5 distributed hash table
+    Dht,
+}
+
+struct Candidate {
+    peer: Peer,
+    source: PeerSource,
+    /// Swarm the peer was found in, hybrid torrents have two
+    infohash: [u8; 20],
+    failures: u32,
+    downloaded: usize,
+    retry_at: Instant,
+    connected: bool,
+}
+
+impl Candidate {
+    fn score(&self) -> i64 {
+        self.downloaded as i64 - self.failures as i64 * FAILURE_PENALTY
+    }
+
+    /// Peers of the local network are tried first, whatever their score
+    fn priority(&self) -> (bool, i64) {
+        (self.source == PeerSource::LocalDiscovery, self.score())
+    }
+
+    fn retired(&self) -> bool {
+        self.failures >= MAX_PEER_FAILURES
+    }
+}
+
+/// Every peer known for a torrent, connected or waiting for a free slot
+pub struct PeerCandidates {
+    candidates: HashMap<SocketAddr, Candidate>,
+    /// BEP 27, only tracker peers are accepted
+    private: bool,
+}
+
+impl PeerCandidates {
+    pub fn new(private: bool) -> Self {
+        PeerCandidates {
+            candidates: HashMap::new(),
+            private,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        peers: impl IntoIterator<Item = Peer>,
+        source: PeerSource,
+        infohash: [u8; 20],
+    ) {
+        if self.private && source != PeerSource::Tracker {
+            return;
+        }
+        let now = Instant::now();
+        for peer in peers {
+            let candidate = self
+                .candidates
+                .entry(peer.addr())
+                .or_insert_with(|| Candidate {
+                    peer,
+                    source,
+                    infohash,
+                    failures: 0,
+                    downloaded: 0,
+                    retry_at: now,
+                    connected: false,
+                });
+            // A peer announced by a tracker may turn out to be a neighbour
+            if source == PeerSource::LocalDiscovery {
+                candidate.source = source;
+            }
+        }
+    }
+
+    /// Best scoring peer that is not connected and not backing off, with the
+    /// infohash to handshake with
+    pub fn pop_best(&mut self) -> Option<(Peer, [u8; 20])> {
+        let now = Instant::now();
+        let candidate = self
+            .candidates
+            .values_mut()
+            .filter(|c| !c.connected && !c.retired() && c.retry_at <= now)
+            .max_by_key(|c| c.priority())?;
+        candidate.connected = true;
+        Some((candidate.peer.clone(), candidate.infohash))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Whether some peer is connected or may be tried again later
+    pub fn has_pending(&self) -> bool {
+        self.candidates
+            .values()
+            .any(|c| c.connected || !c.retired())
+    }
+
+    /// Whether some peer that is not connected may be tried now or later
+    pub fn has_waiting(&self) -> bool {
+        self.candidates
+            .values()
+            .any(|c| !c.connected && !c.retired())
+    }
+
+    pub fn credit(&mut self, addr: SocketAddr, bytes: usize) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.downloaded += bytes;
+            candidate.failures = 0;
+        }
+    }
+
+    pub fn disconnected(&mut self, addr: SocketAddr, failed: bool) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.connected = false;
+        if failed {
+            candidate.failures += 1;
+            candidate.retry_at = Instant::now() + retry_delay(candidate.failures);
+        }
+    }
+}
+
+/// Backoff before the next attempt after `failures` consecutive failures
+pub fn retry_delay(failures: u32) -> Duration {
+    std::cmp::min(
+        RETRY_DELAY.saturating_mul(2u32.saturating_pow(failures - 1)),
+        MAX_RETRY_DELAY,
+    )
+}
@@ -1,55 +1,560 @@
-use std::{fs, io::Read};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
 
 use serde_bencode::de;
 use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
 
-use crate::infohash::{infohash, url_encode};
+use crate::{
+    bencode::dict_value_span,
+    error::{Result, TorrentError},
+    infohash::{infohash, url_encode},
+    merkle::{block_hashes, merkle_root, padding_hash, sha256, MERKLE_BLOCK_SIZE},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetaVersion {
+    V1,
+    /// BEP 52 only, pieces are aligned on files and verified with merkle trees
+    V2,
+    /// Both a v1 and a v2 torrent, with padding files aligning v1 pieces
+    Hybrid,
+}
 
 #[derive(Clone)]
 pub struct TorrentFile {
-    pub announce: String,
+    /// Missing for trackerless torrents
+    pub announce: Option<String>,
     pub name: String,
+    /// SHA-1 of each piece, empty for v2 only torrents
     pub piece_hashes: Vec<[u8; 20]>,
     pub piece_length: usize,
+    /// Sum of the file lengths
     pub length: usize,
+    /// Identifies the torrent in its swarm: the v1 infohash, or the v2 one
+    /// truncated to 20 bytes for v2 only torrents
     pub infohash: [u8; 20],
     pub infohash_encoded: String,
+    pub files: Vec<FileInfo>,
+    pub meta_version: MetaVersion,
+    /// SHA-256 of the info dictionary, for v2 and hybrid torrents
+    pub infohash_v2: Option<[u8; 32]>,
+    /// Merkle root of each piece, for v2 torrents and hybrid ones whose piece
+    /// layers are known
+    pub piece_roots: Vec<PieceRoot>,
+    /// Hashes of the pieces of every file bigger than a piece, by pieces root
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    /// Tracker tiers from `announce-list`, or just `announce`
+    pub trackers: Vec<Vec<String>>,
+    /// BEP 19 `url-list`
+    pub web_seeds: Vec<String>,
+    /// BEP 17 `httpseeds`
+    pub http_seeds: Vec<String>,
+    /// BEP 27, peers may only come from the torrent's trackers
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Unix timestamp
+    pub creation_date: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    /// Relative to the download directory
+    pub path: PathBuf,
+    pub length: usize,
+    /// Position of the file's first byte in the torrent data
+    pub offset: usize,
+    /// Root of the file's merkle tree, for non empty files of v2 torrents
+    pub pieces_root: Option<[u8; 32]>,
+    pub attributes: FileAttributes,
+}
+
+/// BEP 47 file attributes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Filler aligning the next file on a piece boundary, its content is
+    /// zeroes that are never written to disk
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    /// Target of a symbolic link, relative to the torrent's directory
+    pub symlink: Option<PathBuf>,
+}
+
+impl FileAttributes {
+    fn from_bencode(attr: Option<&str>, symlink_path: Option<&[String]>) -> Result<Self> {
+        let attr = attr.unwrap_or_default();
+        // Unknown flags are ignored, as BEP 47 asks
+        let symlink = match symlink_path {
+            Some(components) if attr.contains('l') => {
+                let target: PathBuf = components.iter().collect();
+                if components.is_empty() || !is_safe_path(&target) {
+                    return Err(TorrentError::Metainfo(format!(
+                        "invalid symlink path {:?}",
+                        components
+                    )));
+                }
+                Some(target)
+            }
+            None if attr.contains('l') => {
+                return Err(TorrentError::Metainfo("missing symlink path".to_string()))
+            }
+            _ => None,
+        };
+        Ok(FileAttributes {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink,
+        })
+    }
 }
 
-fn split_hashes(pieces: &ByteBuf) -> Vec<[u8; 20]> {
+/// Expected root of the merkle tree built from the blocks of a v2 piece
+#[derive(Debug, Clone, Copy)]
+pub struct PieceRoot {
+    pub root: [u8; 32],
+    /// Leaves of the tree, missing ones past the end of the file are zeroes
+    pub width: usize,
+}
+
+fn split_hashes(pieces: Option<&ByteBuf>) -> Result<Vec<[u8; 20]>> {
+    let pieces =
+        pieces.ok_or_else(|| TorrentError::Metainfo("missing field pieces".to_string()))?;
     let hash_len = 20;
-    if pieces.len() % hash_len != 0 {
-        eprintln!("received malformed pieces of length {}", pieces.len());
-        std::process::exit(1);
+    if !pieces.len().is_multiple_of(hash_len) {
+        return Err(TorrentError::Metainfo(format!(
+            "received malformed pieces of length {}",
+            pieces.len()
+        )));
     }
     let num_hashes = pieces.len() / hash_len;
     let mut hashes: Vec<[u8; 20]> = vec![[0u8; 20]; num_hashes];
     for (i, chunk) in pieces.chunks_exact(hash_len).enumerate() {
         hashes[i].copy_from_slice(chunk)
     }
-    hashes
+    Ok(hashes)
+}
+
+fn to_length(length: i64) -> Result<usize> {
+    usize::try_from(length).map_err(|_| TorrentError::Metainfo("invalid length".to_string()))
+}
+
+/// Only plain names are allowed so files can't be written outside of the
+/// download directory
+fn is_safe_path(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn build_files(info: &BencodeInfo) -> Result<Vec<FileInfo>> {
+    let root = PathBuf::from(&info.name);
+    if !is_safe_path(&root) {
+        return Err(TorrentError::Metainfo(format!(
+            "invalid torrent name {:?}",
+            info.name
+        )));
+    }
+
+    // Single file mode
+    if let Some(length) = info.length {
+        return Ok(vec![FileInfo {
+            path: root,
+            length: to_length(length)?,
+            offset: 0,
+            pieces_root: None,
+            // Links can't point anywhere without a torrent directory
+            attributes: FileAttributes::from_bencode(info.attr.as_deref(), None)?,
+        }]);
+    }
+
+    // Multi file mode, files live in a directory named after the torrent
+    let bencode_files = info
+        .files
+        .as_ref()
+        .ok_or_else(|| TorrentError::Metainfo("missing field length or files".to_string()))?;
+    let mut files = Vec::with_capacity(bencode_files.len());
+    let mut offset = 0;
+    for file in bencode_files {
+        let path: PathBuf = file.path.iter().collect();
+        if file.path.is_empty() || !is_safe_path(&path) {
+            return Err(TorrentError::Metainfo(format!(
+                "invalid file path {:?}",
+                file.path
+            )));
+        }
+        let length = to_length(file.length)?;
+        let attributes =
+            FileAttributes::from_bencode(file.attr.as_deref(), file.symlink_path.as_deref())?;
+        files.push(FileInfo {
+            path: root.join(path),
+            length,
+            offset,
+            pieces_root: None,
+            attributes,
+        });
+        offset += length;
+    }
+    Ok(files)
+}
+
+/// Files of a v2 `file tree`, each starting on a piece boundary
+fn build_v2_files(info: &BencodeInfo, piece_length: usize) -> Result<Vec<FileInfo>> {
+    let tree = info
+        .file_tree
+        .as_ref()
+        .ok_or_else(|| TorrentError::Metainfo("missing field file tree".to_string()))?;
+    let mut entries = Vec::new();
+    flatten_file_tree(tree, PathBuf::new(), &mut entries)?;
+
+    // A lone file at the root is a single file torrent, otherwise files live
+    // in a directory named after the torrent
+    let single_file = matches!(entries.as_slice(), [(path, _)] if path.components().count() == 1);
+    let root = if single_file {
+        PathBuf::new()
+    } else {
+        PathBuf::from(&info.name)
+    };
+    if !single_file && !is_safe_path(&root) {
+        return Err(TorrentError::Metainfo(format!(
+            "invalid torrent name {:?}",
+            info.name
+        )));
+    }
+
+    let mut files = Vec::with_capacity(entries.len());
+    let mut offset = 0;
+    for (path, file) in entries {
+        let length = to_length(file.length)?;
+        let pieces_root = match &file.pieces_root {
+            Some(root) if length > 0 => {
+                Some(<[u8; 32]>::try_from(root.as_ref()).map_err(|_| {
+                    TorrentError::Metainfo(format!("invalid pieces root for {:?}", path))
+                })?)
+            }
+            None if length > 0 => {
+                return Err(TorrentError::Metainfo(format!(
+                    "missing pieces root for {:?}",
+                    path
+                )))
+            }
+            _ => None,
+        };
+        let attributes =
+            FileAttributes::from_bencode(file.attr.as_deref(), file.symlink_path.as_deref())?;
+        files.push(FileInfo {
+            path: root.join(path),
+            length,
+            offset,
+            pieces_root,
+            attributes,
+        });
+        offset += length.div_ceil(piece_length) * piece_length;
+    }
+    Ok(files)
+}
+
+fn flatten_file_tree(
+    tree: &BTreeMap<String, FileTreeNode>,
+    path: PathBuf,
+    entries: &mut Vec<(PathBuf, BencodeV2File)>,
+) -> Result<()> {
+    for (name, node) in tree {
+        let path = path.join(name);
+        if name.is_empty() || !is_safe_path(Path::new(name)) {
+            return Err(TorrentError::Metainfo(format!(
+                "invalid file path {:?}",
+                path
+            )));
+        }
+        match node {
+            FileTreeNode::File { file } => entries.push((path, file.clone())),
+            FileTreeNode::Directory(children) => flatten_file_tree(children, path, entries)?,
+        }
+    }
+    Ok(())
+}
+
+/// Expected root of every piece of `files`, checking piece layers against
+/// the roots of their files
+fn build_piece_roots(
+    files: &[FileInfo],
+    piece_length: usize,
+    piece_layers: &HashMap<[u8; 32], Vec<[u8; 32]>>,
+) -> Result<Vec<PieceRoot>> {
+    let piece_width = piece_length / MERKLE_BLOCK_SIZE;
+    let mut piece_roots = Vec::new();
+    for file in files {
+        let Some(pieces_root) = file.pieces_root else {
+            continue;
+        };
+        if file.length <= piece_length {
+            // The whole file is one piece, its tree is as small as possible
+            piece_roots.push(PieceRoot {
+                root: pieces_root,
+                width: file.length.div_ceil(MERKLE_BLOCK_SIZE).next_power_of_two(),
+            });
+            continue;
+        }
+
+        let num_pieces = file.length.div_ceil(piece_length);
+        let layer = piece_layers
+            .get(&pieces_root)
+            .filter(|layer| layer.len() == num_pieces)
+            .ok_or_else(|| {
+                TorrentError::Metainfo(format!("missing piece layer for {:?}", file.path))
+            })?;
+        let root = merkle_root(
+            layer,
+            num_pieces.next_power_of_two(),
+            padding_hash(piece_width),
+        );
+        if root != pieces_root {
+            return Err(TorrentError::Metainfo(format!(
+                "piece layer of {:?} does not match its root",
+                file.path
+            )));
+        }
+        piece_roots.extend(layer.iter().map(|root| PieceRoot {
+            root: *root,
+            width: piece_width,
+        }));
+    }
+    Ok(piece_roots)
+}
+
+fn split_layers(
+    piece_layers: &BTreeMap<ByteBuf, ByteBuf>,
+) -> Result<HashMap<[u8; 32], Vec<[u8; 32]>>> {
+    piece_layers
+        .iter()
+        .map(|(root, hashes)| {
+            let root = <[u8; 32]>::try_from(root.as_ref())
+                .map_err(|_| TorrentError::Metainfo("invalid piece layer root".to_string()))?;
+            if !hashes.len().is_multiple_of(32) {
+                return Err(TorrentError::Metainfo(
+                    "received malformed piece layer".to_string(),
+                ));
+            }
+            let hashes = hashes
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+                .collect();
+            Ok((root, hashes))
+        })
+        .collect()
 }
 
 impl TorrentFile {
-    pub fn from_bencode(torrent: &BencodeTorrent, info: &BencodeInfo) -> Self {
-        let infohash = infohash(info);
-        TorrentFile {
+    pub fn from_bencode(
+        torrent: &BencodeTorrent,
+        info: &BencodeInfo,
+        info_bytes: &[u8],
+    ) -> Result<Self> {
+        let piece_length = usize::try_from(info.piece_length)
+            .ok()
+            .filter(|piece_length| *piece_length > 0)
+            .ok_or_else(|| TorrentError::Metainfo("invalid piece length".to_string()))?;
+        let meta_version = match (info.meta_version, &info.pieces) {
+            (None | Some(1), _) => MetaVersion::V1,
+            (Some(2), None) => MetaVersion::V2,
+            (Some(2), Some(_)) => MetaVersion::Hybrid,
+            (Some(version), _) => {
+                return Err(TorrentError::Metainfo(format!(
+                    "unsupported meta version {}",
+                    version
+                )))
+            }
+        };
+        if meta_version != MetaVersion::V1
+            && (piece_length < MERKLE_BLOCK_SIZE || !piece_length.is_power_of_two())
+        {
+            return Err(TorrentError::Metainfo(format!(
+                "invalid piece length {} for a v2 torrent",
+                piece_length
+            )));
+        }
+
+        let infohash_v2 = (meta_version != MetaVersion::V1).then(|| sha256(info_bytes));
+        let piece_layers = match &torrent.piece_layers {
+            Some(piece_layers) => split_layers(piece_layers)?,
+            None => HashMap::new(),
+        };
+        let (infohash, piece_hashes, files) = match meta_version {
+            MetaVersion::V2 => {
+                let infohash_v2 = infohash_v2.expect("v2 torrents have a v2 infohash");
+                let infohash = infohash_v2[..20].try_into().expect("infohash is 32 bytes");
+                (infohash, Vec::new(), build_v2_files(info, piece_length)?)
+            }
+            // Hybrid torrents are downloaded as v1 ones, padding files keep
+            // pieces aligned the same way in both swarms
+            MetaVersion::V1 | MetaVersion::Hybrid => (
+                infohash(info_bytes),
+                split_hashes(info.pieces.as_ref())?,
+                build_files(info)?,
+            ),
+        };
+        let piece_roots = match meta_version {
+            MetaVersion::V1 => Vec::new(),
+            MetaVersion::V2 => build_piece_roots(&files, piece_length, &piece_layers)?,
+            // Magnets of hybrid torrents resolve without piece layers when
+            // peers only speak v1, SHA-1 alone checks their pieces then
+            MetaVersion::Hybrid if torrent.piece_layers.is_none() => Vec::new(),
+            MetaVersion::Hybrid => {
+                let v2_files = build_v2_files(info, piece_length)?;
+                let piece_roots = build_piece_roots(&v2_files, piece_length, &piece_layers)?;
+                if piece_roots.len() != piece_hashes.len() {
+                    return Err(TorrentError::Metainfo(
+                        "v1 and v2 pieces of hybrid torrent differ".to_string(),
+                    ));
+                }
+                piece_roots
+            }
+        };
+        let length = files.iter().map(|file| file.length).sum::<usize>();
+        // Pieces past the end of the data would have no bounds
+        if meta_version != MetaVersion::V2 && piece_hashes.len() != length.div_ceil(piece_length) {
+            return Err(TorrentError::Metainfo(format!(
+                "{} piece hashes for {} pieces",
+                piece_hashes.len(),
+                length.div_ceil(piece_length)
+            )));
+        }
+        let trackers: Vec<Vec<String>> = match &torrent.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            _ => torrent
+                .announce
+                .iter()
+                .map(|url| vec![url.clone()])
+                .collect(),
+        };
+        Ok(TorrentFile {
             announce: torrent
                 .announce
                 .clone()
-                .expect("missing field in torrent file: announce"),
+                .or_else(|| trackers.iter().flatten().next().cloned()),
             name: info.name.clone(),
-            piece_hashes: split_hashes(&info.pieces),
-            piece_length: usize::try_from(info.piece_length).expect("piece_length is too big"),
-            length: usize::try_from(info.length.expect(
-                "missing field in torrent file: length (multi file mode is not yet implemented)",
-            ))
-            .expect("length is too big"),
+            piece_hashes,
+            piece_length,
+            length,
             infohash,
             infohash_encoded: url_encode(&infohash),
+            files,
+            meta_version,
+            infohash_v2,
+            piece_roots,
+            piece_layers,
+            trackers,
+            web_seeds: match &torrent.url_list {
+                Some(UrlList::One(url)) => vec![url.clone()],
+                Some(UrlList::Many(urls)) => urls.clone(),
+                None => Vec::new(),
+            },
+            http_seeds: torrent.httpseeds.clone().unwrap_or_default(),
+            private: info.private == Some(1),
+            comment: torrent.comment.clone(),
+            created_by: torrent.created_by.clone(),
+            creation_date: torrent.creation_date,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let info_span = dict_value_span(bytes, b"info")?;
+        let torrent = de::from_bytes::<BencodeTorrent>(bytes)
+            .map_err(|e| TorrentError::Metainfo(format!("error decoding torrent file: {}", e)))?;
+        TorrentFile::from_bencode(&torrent, &torrent.info, &bytes[info_span])
+    }
+
+    /// Build from an info dictionary received from peers (BEP 9), with the
+    /// piece layers they sent for v2 and hybrid torrents
+    pub fn from_metadata(
+        info_bytes: &[u8],
+        announce: String,
+        piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    ) -> Result<Self> {
+        let info = de::from_bytes::<BencodeInfo>(info_bytes)
+            .map_err(|e| TorrentError::Metainfo(format!("error decoding metadata: {}", e)))?;
+        let torrent = BencodeTorrent {
+            info,
+            announce: Some(announce),
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            httpseeds: None,
+            piece_layers,
+        };
+        TorrentFile::from_bencode(&torrent, &torrent.info, info_bytes)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        match self.meta_version {
+            MetaVersion::V2 => self.piece_roots.len(),
+            MetaVersion::V1 | MetaVersion::Hybrid => self.piece_hashes.len(),
+        }
+    }
+
+    /// Infohashes of the swarms the torrent is shared in, hybrid torrents are
+    /// in both the v1 and the v2 one
+    pub fn swarm_infohashes(&self) -> Vec<[u8; 20]> {
+        match (self.meta_version, self.infohash_v2) {
+            (MetaVersion::Hybrid, Some(infohash_v2)) => vec![
+                self.infohash,
+                infohash_v2[..20].try_into().expect("infohash is 32 bytes"),
+            ],
+            _ => vec![self.infohash],
         }
     }
 
+    pub fn verify_piece(&self, index: usize, buf: &[u8]) -> bool {
+        let sha1_matches = || <Sha1 as Digest>::digest(buf).as_slice() == self.piece_hashes[index];
+        match self.meta_version {
+            MetaVersion::V1 => sha1_matches(),
+            MetaVersion::V2 => self.piece_root_matches(index, buf),
+            // Peers of either swarm may send the piece, it must match both
+            // hashes. The merkle tree stops at the end of the file, before
+            // any padding.
+            MetaVersion::Hybrid if self.piece_roots.is_empty() => sha1_matches(),
+            MetaVersion::Hybrid => {
+                let (start, end) = self.calculate_bound_for_piece(index);
+                let data_end = self
+                    .file_data_end(start)
+                    .map_or(start, |file_end| file_end.min(end));
+                sha1_matches()
+                    && buf.len() == end - start
+                    && self.piece_root_matches(index, &buf[..data_end - start])
+            }
+        }
+    }
+
+    fn piece_root_matches(&self, index: usize, data: &[u8]) -> bool {
+        let piece = &self.piece_roots[index];
+        merkle_root(&block_hashes(data), piece.width, [0u8; 32]) == piece.root
+    }
+
+    /// End of the file holding byte `position`, padding files left out
+    fn file_data_end(&self, position: usize) -> Option<usize> {
+        self.files
+            .iter()
+            .find(|file| {
+                !file.attributes.padding
+                    && file.offset <= position
+                    && position < file.offset + file.length
+            })
+            .map(|file| file.offset + file.length)
+    }
+
     pub fn calculate_piece_size(&self, index: usize) -> usize {
         let (start, end) = self.calculate_bound_for_piece(index);
         end - start
@@ -57,7 +562,12 @@ impl TorrentFile {
 
     pub fn calculate_bound_for_piece(&self, index: usize) -> (usize, usize) {
         let start = index * self.piece_length;
-        let end = std::cmp::min(start + self.piece_length, self.length);
+        let data_end = match self.meta_version {
+            // The last piece of a v2 file is cut at the end of the file
+            MetaVersion::V2 => self.file_data_end(start).unwrap_or(start),
+            MetaVersion::V1 | MetaVersion::Hybrid => self.length,
+        };
+        let end = std::cmp::min(start + self.piece_length, data_end);
         (start, end)
     }
 }
@@ -66,11 +576,79 @@ impl TorrentFile {
 #[allow(dead_code)]
 pub struct BencodeInfo {
     pub name: String,
-    pub pieces: ByteBuf,
+    /// Missing from v2 only torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<ByteBuf>,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
     #[serde(default)]
     pub length: Option<i64>,
+    /// Multi file mode, instead of length
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<BencodeFile>>,
+    /// BEP 27, 1 when peers may only come from trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    /// BEP 52, 2 for v2 and hybrid torrents
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<i64>,
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTreeNode>>,
+    /// BEP 47 attributes of the file in single file mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+/// Entry of a v2 `file tree`, files are dictionaries with an empty key
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file: BencodeV2File,
+    },
+    Directory(BTreeMap<String, FileTreeNode>),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BencodeV2File {
+    pub length: i64,
+    /// Missing for empty files
+    #[serde(
+        default,
+        rename = "pieces root",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    #[serde(
+        default,
+        rename = "symlink path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BencodeFile {
+    pub length: i64,
+    /// Path components, the last one being the file name
+    pub path: Vec<String>,
+    /// BEP 47 flags: `p`adding, e`x`ecutable, `h`idden and sym`l`ink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    /// Target of symlinks, as path components from the torrent's directory
+    #[serde(
+        default,
+        rename = "symlink path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
 }
 
 #[allow(dead_code)]
@@ -79,33 +657,67 @@ pub struct BencodeTorrent {
     pub info: BencodeInfo,
     #[serde(default)]
     pub announce: Option<String>,
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default, rename = "created by")]
+    pub created_by: Option<String>,
+    #[serde(default, rename = "creation date")]
+    pub creation_date: Option<i64>,
+    #[serde(default, rename = "url-list")]
+    pub url_list: Option<UrlList>,
+    #[serde(default)]
+    pub httpseeds: Option<Vec<String>>,
+    /// BEP 52, concatenated piece hashes of each file by pieces root
+    #[serde(default, rename = "piece layers")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
 }
 
-pub fn read_and_decode(file_name: &String) -> TorrentFile {
-    // Open file
-    let mut file = match fs::File::open(file_name) {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("error opening file:\n{}", e);
-            std::process::exit(1);
-        }
+/// `url-list` is either a single URL or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Piece layer of a file, needed to check the pieces of the file
+pub struct RequiredLayer {
+    pub pieces_root: [u8; 32],
+    pub num_pieces: usize,
+}
+
+/// Piece layers that must come with a v2 or hybrid info dictionary, for its
+/// files bigger than a piece, along with the piece length. Empty for v1
+/// ones.
+pub fn required_piece_layers(info_bytes: &[u8]) -> Result<(usize, Vec<RequiredLayer>)> {
+    let info = de::from_bytes::<BencodeInfo>(info_bytes)
+        .map_err(|e| TorrentError::Metainfo(format!("error decoding metadata: {}", e)))?;
+    let piece_length = usize::try_from(info.piece_length)
+        .ok()
+        .filter(|length| *length >= MERKLE_BLOCK_SIZE && length.is_power_of_two());
+    let (Some(2), Some(piece_length)) = (info.meta_version, piece_length) else {
+        return Ok((0, Vec::new()));
     };
+    let layers = build_v2_files(&info, piece_length)?
+        .iter()
+        .filter(|file| file.length > piece_length)
+        .filter_map(|file| {
+            Some(RequiredLayer {
+                pieces_root: file.pieces_root?,
+                num_pieces: file.length.div_ceil(piece_length),
+            })
+        })
+        .collect();
+    Ok((piece_length, layers))
+}
 
-    // Read file
+pub fn read_and_decode(file_name: impl AsRef<Path>) -> Result<TorrentFile> {
+    // Open and read file
+    let mut file = fs::File::open(file_name)?;
     let mut bytes = Vec::new();
-    match file.read_to_end(&mut bytes) {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("error reading file:\n{}", e);
-            std::process::exit(1);
-        }
-    }
+    file.read_to_end(&mut bytes)?;
 
-    match de::from_bytes::<BencodeTorrent>(&bytes) {
-        Ok(torrent) => TorrentFile::from_bencode(&torrent, &torrent.info),
-        Err(e) => {
-            eprintln!("error decoding torrent file:\n{}", e);
-            std::process::exit(1);
-        }
-    }
+    TorrentFile::from_bytes(&bytes)
 }
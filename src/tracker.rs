@@ -1,86 +1,197 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
-use crate::torrent_file::TorrentFile;
-use crate::{CLIENT_ID, PORT};
+use crate::bencode::value_end;
+use crate::error::{Result, TorrentError};
+use crate::identity::Identity;
+use crate::infohash::url_encode;
+use crate::PORT;
+use rand::seq::SliceRandom;
 use serde_bencode::de;
 use serde_bytes::ByteBuf;
 
 #[derive(Deserialize, Debug)]
-#[allow(dead_code)]
 pub struct TrackerResponse {
     peers: ByteBuf,
     interval: u32,
+    #[serde(default, rename = "min interval")]
+    min_interval: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
 }
 
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::from((self.ip, self.port))
+    }
+}
+
 impl Default for Peer {
     fn default() -> Self {
         Peer {
-            ip: Ipv4Addr::new(0, 0, 0, 0),
+            ip: Ipv4Addr::UNSPECIFIED.into(),
             port: Default::default(),
         }
     }
 }
 
-pub async fn fetch_peers(torrent: &TorrentFile) -> Vec<Peer> {
+/// Lifecycle announces, regular ones carry no event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    /// Every wanted piece is verified
+    Completed,
+    /// We leave the swarm, the tracker may forget us
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+/// Transfer totals reported to trackers, counted since the `started`
+/// announce
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AnnounceStats {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    /// Bytes still wanted, skipped files don't count
+    pub left: usize,
+}
+
+/// Reply of the tracker that answered an announce
+#[derive(Debug, Clone)]
+pub struct Announced {
+    pub tracker: String,
+    pub peers: Vec<Peer>,
+    /// Time the tracker wants between regular announces
+    pub interval: Duration,
+    /// Announces sent sooner than this may be refused
+    pub min_interval: Option<Duration>,
+}
+
+/// BEP 12 tracker tiers, tried in order. The trackers of a tier are tried in
+/// a random order picked once, and the first one to reply moves to the front
+/// of its tier so later announces go to it first.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: &[Vec<String>]) -> Self {
+        let mut tiers = tiers.to_vec();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        TrackerTiers { tiers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Peers of the swarm `infohash`, from the first tracker that replies
+    pub async fn fetch_peers(
+        &mut self,
+        infohash: &[u8; 20],
+        identity: &Identity,
+        stats: AnnounceStats,
+        event: Option<AnnounceEvent>,
+    ) -> Result<Announced> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for position in 0..tier.len() {
+                match announce(&tier[position], infohash, stats, identity, event).await {
+                    Ok(announced) => {
+                        let tracker = tier.remove(position);
+                        tier.insert(0, tracker);
+                        return Ok(announced);
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| TorrentError::Tracker("torrent has no tracker".to_string())))
+    }
+}
+
+/// Ask the tracker at `announce_url` for peers of the torrent `infohash`
+pub async fn announce(
+    announce_url: &str,
+    infohash: &[u8; 20],
+    stats: AnnounceStats,
+    identity: &Identity,
+    event: Option<AnnounceEvent>,
+) -> Result<Announced> {
     // Fetch peers from tracker
     let mut params = HashMap::new();
-    params.insert("peer_id", CLIENT_ID.to_string());
     params.insert("port", PORT.to_string());
-    params.insert("uploaded", "0".to_string());
-    params.insert("downloaded", "0".to_string());
+    params.insert("key", identity.tracker_key.clone());
+    params.insert("uploaded", stats.uploaded.to_string());
+    params.insert("downloaded", stats.downloaded.to_string());
     params.insert("compact", "1".to_string());
-    params.insert("left", torrent.length.to_string());
+    params.insert("left", stats.left.to_string());
+    if let Some(event) = event {
+        params.insert("event", event.as_str().to_string());
+    }
 
     let http_client = reqwest::Client::new();
 
     // Fetch and decode
-    let data = match http_client
+    let response = http_client
         .get(format!(
-            "{}?info_hash={}",
-            torrent.announce, torrent.infohash_encoded
+            "{}?info_hash={}&peer_id={}",
+            announce_url,
+            url_encode(infohash),
+            url_encode(&identity.peer_id)
         ))
         .query(&params)
         .send()
         .await
-    {
-        Ok(response) => match response.bytes().await {
-            Ok(body) => match de::from_bytes::<TrackerResponse>(&body) {
-                Ok(data) => data,
-                Err(e) => {
-                    eprintln!("error decoding response:\n{}", e);
-                    std::process::exit(1)
-                }
-            },
-            Err(e) => {
-                eprintln!("error while reading response:\n{}", e);
-                std::process::exit(1);
-            }
-        },
-        Err(e) => {
-            eprintln!("error while querying tracker:\n{}", e);
-            std::process::exit(1);
-        }
-    };
+        .map_err(|e| TorrentError::Tracker(format!("error while querying tracker: {}", e)))?;
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| TorrentError::Tracker(format!("error while reading response: {}", e)))?;
+    // Rejects nesting deep enough to exhaust the decoder's stack
+    value_end(&body, 0).map_err(|e| TorrentError::Tracker(e.to_string()))?;
+    let data = de::from_bytes::<TrackerResponse>(&body)
+        .map_err(|e| TorrentError::Tracker(format!("error decoding response: {}", e)))?;
 
     // Formatting information
     let peer_size = 6;
     if data.peers.len() % peer_size != 0 {
-        eprintln!("received malformed peers from tracker");
-        std::process::exit(1);
+        return Err(TorrentError::Tracker(
+            "received malformed peers from tracker".to_string(),
+        ));
     }
     let num_peers = data.peers.len() / peer_size;
     let mut peers: Vec<Peer> = vec![Default::default(); num_peers];
     for (i, chunk) in data.peers.chunks(6).enumerate() {
-        peers[i].ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+        peers[i].ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]).into();
         peers[i].port = u16::from_be_bytes([chunk[4], chunk[5]])
     }
-    peers
+    Ok(Announced {
+        tracker: announce_url.to_string(),
+        peers,
+        interval: Duration::from_secs(data.interval.into()),
+        min_interval: data
+            .min_interval
+            .map(|secs| Duration::from_secs(secs.into())),
+    })
 }
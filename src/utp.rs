@@ -0,0 +1,887 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+    time,
+};
+
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+/// Payload of data packets, small enough for the IPv6 minimum MTU
+const MAX_PAYLOAD: usize = 1200;
+/// LEDBAT target for the queuing delay our packets add
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = 4.0 * 1024.0 * 1024.0;
+/// Base delay is the minimum seen over this many minutes
+const BASE_DELAY_MINUTES: usize = 2;
+/// Data received but not read yet, advertised as our window
+const RECV_BUFFER: usize = 1024 * 1024;
+/// Data written but not sent yet
+const SEND_BUFFER: usize = 256 * 1024;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+const MAX_TRANSMISSIONS: u32 = 6;
+const DUPLICATE_ACKS_BEFORE_RESEND: u32 = 3;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Period of retransmission and keepalive checks
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+const ACCEPT_BACKLOG: usize = 16;
+const SOCKET_BUFFER: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    /// Delay the sender measured on our last packet, in microseconds
+    timestamp_diff: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.push((self.kind as u8) << 4 | VERSION);
+        // No extension, selective acks are not sent
+        buf.push(0);
+        buf.extend_from_slice(&self.connection_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf.extend_from_slice(&self.window.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Header and payload of a datagram, extensions are skipped
+    fn decode(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let header = Header {
+            kind: PacketType::from_u8(buf[0] >> 4)?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        let mut extension = buf[1];
+        let mut offset = HEADER_LEN;
+        while extension != 0 {
+            let [next, length] = *buf.get(offset..offset + 2)? else {
+                return None;
+            };
+            extension = next;
+            offset += 2 + length as usize;
+        }
+        Some((header, buf.get(offset..)?))
+    }
+}
+
+/// Whether `a` comes before `b`, sequence numbers wrapping around
+fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// State of one uTP connection, shared between its stream and the socket task
+struct Connection {
+    addr: SocketAddr,
+    state: ConnectionState,
+    /// Id of the packets we receive
+    recv_id: u16,
+    /// Id of the packets we send
+    send_id: u16,
+    /// Next sequence number to send
+    seq_nr: u16,
+    /// Last sequence number received in order
+    ack_nr: u16,
+    in_flight: VecDeque<SentPacket>,
+    /// Payload bytes of `in_flight`
+    bytes_in_flight: usize,
+    /// Last sequence number sent when a loss was detected. Until it is acked,
+    /// acks still missing packets point at the next lost one.
+    recovery: Option<u16>,
+    send_buffer: VecDeque<u8>,
+    /// Shutdown asked, a FIN follows the buffered data
+    closing: bool,
+    fin_sent: bool,
+    received: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    /// Payload bytes of `out_of_order`
+    out_of_order_bytes: usize,
+    fin_seq_nr: Option<u16>,
+    eof: bool,
+    error: Option<io::ErrorKind>,
+    /// Stream dropped, the connection only lives to deliver its FIN
+    dropped: bool,
+    peer_window: u32,
+    advertised_window: u32,
+    /// LEDBAT congestion window in bytes
+    max_window: f64,
+    /// Minimum delay of the current minute, then of the previous ones
+    delay_minima: VecDeque<u32>,
+    delay_minute_start: Instant,
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    /// Delay measured on the last packet received, sent back to the peer
+    reply_micros: u32,
+    duplicate_acks: u32,
+    last_received: Instant,
+    last_sent: Instant,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr, state: ConnectionState, recv_id: u16, send_id: u16) -> Self {
+        let now = Instant::now();
+        Connection {
+            addr,
+            state,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            recovery: None,
+            send_buffer: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            fin_seq_nr: None,
+            eof: false,
+            error: None,
+            dropped: false,
+            peer_window: RECV_BUFFER as u32,
+            advertised_window: RECV_BUFFER as u32,
+            max_window: 2.0 * MIN_WINDOW,
+            delay_minima: VecDeque::from([u32::MAX]),
+            delay_minute_start: now,
+            rtt: None,
+            rto: INITIAL_RTO,
+            reply_micros: 0,
+            duplicate_acks: 0,
+            last_received: now,
+            last_sent: now,
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    fn header(&mut self, kind: PacketType, seq_nr: u16, clock: &Clock) -> Header {
+        self.advertised_window = RECV_BUFFER.saturating_sub(self.received.len()) as u32;
+        Header {
+            kind,
+            // The SYN carries the id the peer will answer to
+            connection_id: if kind == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: clock.micros(),
+            timestamp_diff: self.reply_micros,
+            window: self.advertised_window,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    fn ack(&mut self, clock: &Clock) -> Vec<u8> {
+        self.last_sent = Instant::now();
+        self.header(PacketType::State, self.seq_nr, clock)
+            .encode(&[])
+    }
+
+    /// Send a packet consuming a sequence number, kept until acked
+    fn send_tracked(&mut self, kind: PacketType, payload: Vec<u8>, clock: &Clock) -> Vec<u8> {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        let datagram = self.header(kind, seq_nr, clock).encode(&payload);
+        let now = Instant::now();
+        self.last_sent = now;
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(SentPacket {
+            kind,
+            seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+        });
+        datagram
+    }
+
+    fn resend_oldest(&mut self, clock: &Clock) -> Option<Vec<u8>> {
+        let (kind, seq_nr) = {
+            let packet = self.in_flight.front()?;
+            (packet.kind, packet.seq_nr)
+        };
+        let header = self.header(kind, seq_nr, clock);
+        let now = Instant::now();
+        self.last_sent = now;
+        let packet = self.in_flight.front_mut()?;
+        packet.sent_at = now;
+        packet.transmissions += 1;
+        Some(header.encode(&packet.payload))
+    }
+
+    fn send_window(&self) -> usize {
+        f64::min(self.max_window, self.peer_window as f64) as usize
+    }
+
+    /// Packets of buffered data the windows allow, then the FIN
+    fn transmit(&mut self, clock: &Clock) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        if self.state != ConnectionState::Connected || self.error.is_some() {
+            return datagrams;
+        }
+        while !self.send_buffer.is_empty() {
+            let in_flight = self.bytes_in_flight;
+            let length = std::cmp::min(MAX_PAYLOAD, self.send_buffer.len());
+            // A packet always fits in an empty window, or nothing would move
+            if in_flight > 0 && in_flight + length > self.send_window() {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buffer.drain(..length).collect();
+            datagrams.push(self.send_tracked(PacketType::Data, payload, clock));
+        }
+        if self.closing && !self.fin_sent && self.send_buffer.is_empty() {
+            self.fin_sent = true;
+            datagrams.push(self.send_tracked(PacketType::Fin, Vec::new(), clock));
+        }
+        if self.send_buffer.len() < SEND_BUFFER {
+            wake(&mut self.write_waker);
+        }
+        datagrams
+    }
+
+    fn on_packet(&mut self, header: &Header, payload: &[u8], clock: &Clock) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        self.last_received = now;
+        self.reply_micros = clock.micros().wrapping_sub(header.timestamp);
+
+        if header.kind == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return Vec::new();
+        }
+        if self.state == ConnectionState::SynSent {
+            if header.kind != PacketType::State {
+                return Vec::new();
+            }
+            self.state = ConnectionState::Connected;
+            // The peer's first data packet reuses the sequence number of its ack
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+            wake(&mut self.connect_waker);
+        }
+
+        self.peer_window = header.window;
+        let partial_ack = self.process_ack(header, payload.is_empty(), now);
+
+        let mut datagrams = Vec::new();
+        if partial_ack {
+            datagrams.extend(self.resend_oldest(clock));
+        }
+        match header.kind {
+            PacketType::Data | PacketType::Fin => {
+                if header.kind == PacketType::Fin {
+                    self.fin_seq_nr = Some(header.seq_nr);
+                }
+                self.receive(header.seq_nr, payload);
+                datagrams.push(self.ack(clock));
+            }
+            PacketType::Syn => datagrams.push(self.ack(clock)),
+            PacketType::State | PacketType::Reset => {}
+        }
+        if self.duplicate_acks == DUPLICATE_ACKS_BEFORE_RESEND && self.recovery.is_none() {
+            // Fast retransmit, the packet after the acked one was lost
+            self.max_window = f64::max(self.max_window / 2.0, MIN_WINDOW);
+            datagrams.extend(self.recover(clock));
+        }
+        datagrams.extend(self.transmit(clock));
+        datagrams
+    }
+
+    /// Returns whether the ack is partial: new data was acked but not
+    /// everything sent before the last loss
+    fn process_ack(&mut self, header: &Header, is_state: bool, now: Instant) -> bool {
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+        while let Some(packet) = self.in_flight.front() {
+            if seq_before(header.ack_nr, packet.seq_nr) {
+                break;
+            }
+            let packet = self.in_flight.pop_front().expect("front exists");
+            self.bytes_in_flight -= packet.payload.len();
+            // Only first transmissions tell which packet the ack is for
+            if packet.transmissions == 1 {
+                self.update_rtt(now.duration_since(packet.sent_at));
+            }
+            acked_bytes += packet.payload.len();
+            acked_any = true;
+        }
+        if !acked_any {
+            if is_state && header.kind == PacketType::State && !self.in_flight.is_empty() {
+                self.duplicate_acks += 1;
+            }
+            return false;
+        }
+        self.duplicate_acks = 0;
+        if acked_bytes > 0 {
+            self.update_window(header.timestamp_diff, acked_bytes, now);
+        }
+        match self.recovery {
+            Some(end) if seq_before(header.ack_nr, end) => true,
+            _ => {
+                self.recovery = None;
+                false
+            }
+        }
+    }
+
+    /// Resend the oldest packet, which was lost
+    fn recover(&mut self, clock: &Clock) -> Option<Vec<u8>> {
+        self.recovery = Some(self.seq_nr.wrapping_sub(1));
+        self.resend_oldest(clock)
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => {
+                let deviation = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, variance * 3 / 4 + deviation / 4)
+            }
+        };
+        self.rtt = Some((rtt, variance));
+        self.rto = (rtt + variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// LEDBAT: grow the window while the delay our packets add is under the
+    /// target, shrink it past the target
+    fn update_window(&mut self, delay_micros: u32, acked_bytes: usize, now: Instant) {
+        if delay_micros == 0 {
+            return;
+        }
+        if now.duration_since(self.delay_minute_start) >= Duration::from_secs(60) {
+            self.delay_minima.push_front(u32::MAX);
+            self.delay_minima.truncate(BASE_DELAY_MINUTES);
+            self.delay_minute_start = now;
+        }
+        let current = self.delay_minima.front_mut().expect("never empty");
+        *current = std::cmp::min(*current, delay_micros);
+        let base_delay = *self.delay_minima.iter().min().expect("never empty");
+
+        let queuing_delay = delay_micros.saturating_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queuing_delay) / TARGET_DELAY_MICROS;
+        let window_factor = f64::min(acked_bytes as f64, self.max_window) / self.max_window;
+        let gain = MAX_WINDOW_INCREASE_PER_RTT * off_target * window_factor;
+        self.max_window = (self.max_window + gain).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// Data past our buffer is dropped unacked, the peer ignored the window
+    /// we advertised and sends it again later
+    fn receive(&mut self, seq_nr: u16, payload: &[u8]) {
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq_nr == expected {
+            if self.received.len() + payload.len() > RECV_BUFFER {
+                return;
+            }
+            self.received.extend(payload);
+            self.ack_nr = seq_nr;
+            while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.out_of_order_bytes -= payload.len();
+                self.received.extend(payload);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+            if self.fin_seq_nr == Some(self.ack_nr) {
+                self.eof = true;
+            }
+            wake(&mut self.read_waker);
+        } else if seq_before(expected, seq_nr)
+            && self.received.len() + self.out_of_order_bytes + payload.len() <= RECV_BUFFER
+        {
+            if let Some(replaced) = self.out_of_order.insert(seq_nr, payload.to_vec()) {
+                self.out_of_order_bytes -= replaced.len();
+            }
+            self.out_of_order_bytes += payload.len();
+        }
+    }
+
+    fn on_tick(&mut self, clock: &Clock) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        if self.error.is_some() {
+            return Vec::new();
+        }
+        if now.duration_since(self.last_received) >= IDLE_TIMEOUT {
+            self.fail(io::ErrorKind::TimedOut);
+            return Vec::new();
+        }
+
+        let mut datagrams = Vec::new();
+        if let Some(oldest) = self.in_flight.front() {
+            if now.duration_since(oldest.sent_at) >= self.rto {
+                let max_transmissions = match self.state {
+                    ConnectionState::SynSent => MAX_SYN_TRANSMISSIONS,
+                    ConnectionState::Connected => MAX_TRANSMISSIONS,
+                };
+                if oldest.transmissions >= max_transmissions {
+                    self.fail(io::ErrorKind::TimedOut);
+                    return Vec::new();
+                }
+                // Unanswered SYNs are retried at a steady pace to fail fast
+                if self.state == ConnectionState::Connected {
+                    self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+                    self.max_window = MIN_WINDOW;
+                }
+                datagrams.extend(self.recover(clock));
+            }
+        } else if self.state == ConnectionState::Connected
+            && now.duration_since(self.last_sent) >= KEEPALIVE_INTERVAL
+        {
+            datagrams.push(self.ack(clock));
+        }
+        datagrams.extend(self.transmit(clock));
+        datagrams
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.error = Some(kind);
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+        wake(&mut self.connect_waker);
+    }
+
+    /// Nothing left to deliver in either direction
+    fn is_done(&self) -> bool {
+        self.error.is_some()
+            || (self.fin_sent && self.in_flight.is_empty() && (self.eof || self.dropped))
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// Microsecond timestamps relative to the socket creation
+struct Clock {
+    start: Instant,
+}
+
+impl Clock {
+    fn micros(&self) -> u32 {
+        // Only differences matter, wrapping is expected
+        self.start.elapsed().as_micros() as u32
+    }
+}
+
+type ConnectionKey = (SocketAddr, u16);
+
+struct SocketShared {
+    udp: Arc<UdpSocket>,
+    clock: Clock,
+    connections: StdMutex<HashMap<ConnectionKey, Arc<StdMutex<Connection>>>>,
+    accepting: AtomicBool,
+    accepted: mpsc::Sender<UtpStream>,
+}
+
+impl SocketShared {
+    fn send(&self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        for datagram in datagrams {
+            // A full socket buffer is a lost packet, retransmission covers it
+            let _ = self.udp.try_send_to(&datagram, addr);
+        }
+    }
+
+    fn connections(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<ConnectionKey, Arc<StdMutex<Connection>>>> {
+        self.connections
+            .lock()
+            .expect("utp connections lock poisoned")
+    }
+}
+
+/// UDP socket carrying any number of uTP connections (BEP 29)
+pub struct UtpSocket {
+    shared: Arc<SocketShared>,
+    accepted: Mutex<mpsc::Receiver<UtpStream>>,
+    task: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let udp = UdpSocket::bind(addr).await?;
+        // Every connection shares the socket, the default buffers overflow
+        // with a few large windows. Systems may cap the size, that is fine.
+        let socket = SockRef::from(&udp);
+        let _ = socket.set_recv_buffer_size(SOCKET_BUFFER);
+        let _ = socket.set_send_buffer_size(SOCKET_BUFFER);
+        let udp = Arc::new(udp);
+        let (accepted_sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(SocketShared {
+            udp: udp.clone(),
+            clock: Clock {
+                start: Instant::now(),
+            },
+            connections: StdMutex::new(HashMap::new()),
+            accepting: AtomicBool::new(false),
+            accepted: accepted_sender,
+        });
+        let task = tokio::spawn(run(udp, Arc::downgrade(&shared)));
+        Ok(UtpSocket {
+            shared,
+            accepted: Mutex::new(accepted),
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.udp.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (key, connection) = {
+            let mut connections = self.shared.connections();
+            // Both ids must be free, the peer answers to one and we send the other
+            let recv_id = loop {
+                let id: u16 = rand::thread_rng().gen();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut connection = Connection::new(
+                addr,
+                ConnectionState::SynSent,
+                recv_id,
+                recv_id.wrapping_add(1),
+            );
+            let syn = connection.send_tracked(PacketType::Syn, Vec::new(), &self.shared.clock);
+            self.shared.send(addr, vec![syn]);
+            let connection = Arc::new(StdMutex::new(connection));
+            connections.insert((addr, recv_id), connection.clone());
+            ((addr, recv_id), connection)
+        };
+
+        let stream = UtpStream {
+            shared: self.shared.clone(),
+            connection,
+            key,
+        };
+        poll_fn(|cx| {
+            let mut connection = stream.lock();
+            if let Some(kind) = connection.error {
+                return Poll::Ready(Err(io::Error::from(kind)));
+            }
+            if connection.state == ConnectionState::Connected {
+                return Poll::Ready(Ok(()));
+            }
+            connection.connect_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Next connection opened by a peer
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.shared.accepting.store(true, Ordering::Relaxed);
+        self.accepted
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Receive datagrams and drive timers of every connection of the socket
+async fn run(udp: Arc<UdpSocket>, shared: Weak<SocketShared>) {
+    let mut buf = vec![0u8; 65536];
+    let mut tick = time::interval(TICK_INTERVAL);
+    tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            received = udp.recv_from(&mut buf) => {
+                let Some(shared) = shared.upgrade() else {
+                    return;
+                };
+                // Errors like ICMP port unreachable are per datagram
+                if let Ok((length, addr)) = received {
+                    handle_datagram(&shared, &buf[..length], addr);
+                }
+            }
+            _ = tick.tick() => {
+                let Some(shared) = shared.upgrade() else {
+                    return;
+                };
+                tick_connections(&shared);
+            }
+        }
+    }
+}
+
+fn handle_datagram(shared: &Arc<SocketShared>, datagram: &[u8], addr: SocketAddr) {
+    let Some((header, payload)) = Header::decode(datagram) else {
+        return;
+    };
+    if header.kind == PacketType::Syn {
+        accept_syn(shared, &header, addr);
+        return;
+    }
+    let connection = shared
+        .connections()
+        .get(&(addr, header.connection_id))
+        .cloned();
+    let Some(connection) = connection else {
+        if header.kind != PacketType::Reset {
+            let reset = Header {
+                kind: PacketType::Reset,
+                connection_id: header.connection_id,
+                timestamp: shared.clock.micros(),
+                timestamp_diff: 0,
+                window: 0,
+                seq_nr: 0,
+                ack_nr: header.seq_nr,
+            };
+            shared.send(addr, vec![reset.encode(&[])]);
+        }
+        return;
+    };
+    let datagrams = connection
+        .lock()
+        .expect("utp connection lock poisoned")
+        .on_packet(&header, payload, &shared.clock);
+    shared.send(addr, datagrams);
+}
+
+fn accept_syn(shared: &Arc<SocketShared>, header: &Header, addr: SocketAddr) {
+    let recv_id = header.connection_id.wrapping_add(1);
+    let mut connections = shared.connections();
+    // Our ack was lost, the SYN is sent again
+    if let Some(connection) = connections.get(&(addr, recv_id)) {
+        let ack = connection
+            .lock()
+            .expect("utp connection lock poisoned")
+            .ack(&shared.clock);
+        shared.send(addr, vec![ack]);
+        return;
+    }
+    if !shared.accepting.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(permit) = shared.accepted.try_reserve() else {
+        return;
+    };
+
+    let mut connection = Connection::new(
+        addr,
+        ConnectionState::Connected,
+        recv_id,
+        header.connection_id,
+    );
+    connection.seq_nr = rand::thread_rng().gen();
+    connection.ack_nr = header.seq_nr;
+    connection.reply_micros = shared.clock.micros().wrapping_sub(header.timestamp);
+    connection.peer_window = header.window;
+    let ack = connection.ack(&shared.clock);
+    shared.send(addr, vec![ack]);
+
+    let connection = Arc::new(StdMutex::new(connection));
+    connections.insert((addr, recv_id), connection.clone());
+    permit.send(UtpStream {
+        shared: shared.clone(),
+        connection,
+        key: (addr, recv_id),
+    });
+}
+
+fn tick_connections(shared: &SocketShared) {
+    let mut connections = shared.connections();
+    connections.retain(|_, connection| {
+        let mut connection = connection.lock().expect("utp connection lock poisoned");
+        let datagrams = connection.on_tick(&shared.clock);
+        shared.send(connection.addr, datagrams);
+        !connection.is_done()
+    });
+}
+
+/// Reliable, ordered byte stream over uTP
+pub struct UtpStream {
+    shared: Arc<SocketShared>,
+    connection: Arc<StdMutex<Connection>>,
+    key: ConnectionKey,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .expect("utp connection lock poisoned")
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.lock();
+        if !connection.received.is_empty() {
+            let length = std::cmp::min(buf.remaining(), connection.received.len());
+            let (front, back) = connection.received.as_slices();
+            let from_front = std::cmp::min(length, front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..length - from_front]);
+            connection.received.drain(..length);
+
+            // Tell a peer stalled on a small window that there is room again
+            let available = RECV_BUFFER.saturating_sub(connection.received.len());
+            if (connection.advertised_window as usize) < RECV_BUFFER / 2
+                && available >= RECV_BUFFER / 2
+            {
+                let ack = connection.ack(&self.shared.clock);
+                self.shared.send(connection.addr, vec![ack]);
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if connection.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(io::Error::from(kind)));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut connection = self.lock();
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(io::Error::from(kind)));
+        }
+        if connection.closing {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        let room = SEND_BUFFER.saturating_sub(connection.send_buffer.len());
+        if room == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = std::cmp::min(room, buf.len());
+        connection.send_buffer.extend(&buf[..length]);
+        let datagrams = connection.transmit(&self.shared.clock);
+        self.shared.send(connection.addr, datagrams);
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is sent as soon as the window allows
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.lock();
+        connection.closing = true;
+        let datagrams = connection.transmit(&self.shared.clock);
+        self.shared.send(connection.addr, datagrams);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut connection = self.lock();
+        connection.dropped = true;
+        connection.closing = true;
+        let datagrams = connection.transmit(&self.shared.clock);
+        self.shared.send(connection.addr, datagrams);
+    }
+}
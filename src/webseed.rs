@@ -0,0 +1,365 @@
+use std::{sync::Arc, time::Duration};
+
+use reqwest::{
+    header::{RANGE, RETRY_AFTER},
+    Client, Response, StatusCode,
+};
+use tokio::{
+    sync::{broadcast, mpsc::Sender, Mutex},
+    time,
+};
+use url::Url;
+
+use crate::{
+    controller::PieceResult,
+    error::{Result, TorrentError},
+    event::{send_event, Event},
+    ftp::FtpConnection,
+    picker::PiecePicker,
+    rate_limit::PeerBandwidth,
+    swarm::{retry_delay, MAX_PEER_FAILURES},
+    torrent_file::{FileInfo, TorrentFile},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Wait before looking again when every missing piece is taken by others
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Server delivering torrent data over HTTP, or FTP for `url-list` mirrors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSeed {
+    /// BEP 19 `url-list`, serving the torrent's files as they are
+    UrlList(String),
+    /// BEP 17 `httpseeds`, serving pieces through a script
+    HttpSeed(String),
+}
+
+impl WebSeed {
+    /// Web seeds of both kinds listed in `torrent_file`
+    pub fn all(torrent_file: &TorrentFile) -> Vec<WebSeed> {
+        let url_list = torrent_file.web_seeds.iter().cloned().map(WebSeed::UrlList);
+        let http_seeds = torrent_file
+            .http_seeds
+            .iter()
+            .cloned()
+            .map(WebSeed::HttpSeed);
+        url_list.chain(http_seeds).collect()
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            WebSeed::UrlList(url) | WebSeed::HttpSeed(url) => url,
+        }
+    }
+}
+
+/// Connection to the server of a web seed
+enum Mirror {
+    Http(Client),
+    /// Logged in on first use, and again after a failure
+    Ftp(Option<FtpConnection>),
+}
+
+impl Mirror {
+    fn new(web_seed: &WebSeed, base: &Url) -> Result<Self> {
+        match (web_seed, base.scheme()) {
+            (_, "http" | "https") => Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map(Mirror::Http)
+                .map_err(|e| TorrentError::WebSeed(e.to_string())),
+            // BEP 17 seeds are scripts, only reachable over HTTP
+            (WebSeed::UrlList(_), "ftp") => Ok(Mirror::Ftp(None)),
+            _ => Err(TorrentError::WebSeed(format!(
+                "unsupported scheme for {}",
+                base
+            ))),
+        }
+    }
+
+    /// Bytes `from..to` of the file at `url`, charged to `bandwidth`
+    async fn fetch_range(
+        &mut self,
+        url: &Url,
+        from: usize,
+        to: usize,
+        file_length: usize,
+        bandwidth: &PeerBandwidth,
+    ) -> std::result::Result<Vec<u8>, FetchError> {
+        let body = match self {
+            Mirror::Http(client) => fetch_range(client, url, from, to, file_length).await,
+            Mirror::Ftp(connection) => {
+                let fetch = async {
+                    let ftp = match connection {
+                        Some(ftp) => ftp,
+                        None => connection.insert(FtpConnection::connect(url).await?),
+                    };
+                    ftp.read_range(url, from, to).await
+                };
+                let result = time::timeout(REQUEST_TIMEOUT, fetch)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(TorrentError::WebSeed(format!("timed out reading {}", url)))
+                    });
+                if result.is_err() {
+                    // The control connection is in an unknown state
+                    *connection = None;
+                }
+                Ok(result?)
+            }
+        }?;
+        bandwidth.download(body.len()).await;
+        Ok(body)
+    }
+}
+
+enum FetchError {
+    /// Server is overloaded and asked to come back later
+    Busy(Duration),
+    Failed(TorrentError),
+}
+
+impl From<TorrentError> for FetchError {
+    fn from(error: TorrentError) -> Self {
+        FetchError::Failed(error)
+    }
+}
+
+/// Download whole pieces from a web seed until the torrent is complete,
+/// within the limits of `bandwidth`. Fails once the server failed too many
+/// times in a row.
+pub async fn start_web_seed_worker(
+    web_seed: &WebSeed,
+    torrent_file: &TorrentFile,
+    picker: &Arc<Mutex<PiecePicker>>,
+    bandwidth: &PeerBandwidth,
+    result_sender: &Sender<PieceResult>,
+    events: &broadcast::Sender<Event>,
+) -> Result<()> {
+    let url = web_seed.url();
+    let mirror = Url::parse(url)
+        .map_err(|e| TorrentError::WebSeed(format!("invalid url {}: {}", url, e)))
+        .and_then(|base| Ok((Mirror::new(web_seed, &base)?, base)));
+    let (mut mirror, base) = match mirror {
+        Ok(mirror) => mirror,
+        Err(e) => {
+            send_event(
+                events,
+                Event::WebSeedFailed {
+                    infohash: torrent_file.infohash,
+                    url: url.to_string(),
+                    error: e.to_string(),
+                },
+            );
+            return Err(e);
+        }
+    };
+
+    let mut failures = 0;
+    loop {
+        let Some(index) = picker.lock().await.pick_piece() else {
+            if picker.lock().await.is_complete() {
+                return Ok(());
+            }
+            time::sleep(IDLE_DELAY).await;
+            continue;
+        };
+
+        let fetched = match (web_seed, &mut mirror) {
+            (WebSeed::HttpSeed(_), Mirror::Http(client)) => {
+                fetch_http_seed_piece(client, &base, torrent_file, index, bandwidth).await
+            }
+            _ => fetch_piece(&mut mirror, &base, torrent_file, index, bandwidth).await,
+        };
+        match fetched {
+            Ok(buf) => {
+                failures = 0;
+                // The controller only stops listening once the download is over
+                let _ = result_sender
+                    .send(PieceResult {
+                        index,
+                        peer: None,
+                        buf,
+                    })
+                    .await;
+            }
+            Err(FetchError::Busy(delay)) => {
+                picker.lock().await.piece_failed(index);
+                time::sleep(delay).await;
+            }
+            Err(FetchError::Failed(e)) => {
+                picker.lock().await.piece_failed(index);
+                send_event(
+                    events,
+                    Event::WebSeedFailed {
+                        infohash: torrent_file.infohash,
+                        url: url.to_string(),
+                        error: e.to_string(),
+                    },
+                );
+                failures += 1;
+                if failures >= MAX_PEER_FAILURES {
+                    return Err(e);
+                }
+                time::sleep(retry_delay(failures)).await;
+            }
+        }
+    }
+}
+
+async fn fetch_piece(
+    mirror: &mut Mirror,
+    base: &Url,
+    torrent_file: &TorrentFile,
+    index: usize,
+    bandwidth: &PeerBandwidth,
+) -> std::result::Result<Vec<u8>, FetchError> {
+    let (start, end) = torrent_file.calculate_bound_for_piece(index);
+    let mut buf = Vec::with_capacity(end - start);
+    for file in torrent_file.files.iter() {
+        let file_end = file.offset + file.length;
+        if file.length == 0 || file_end <= start || file.offset >= end {
+            continue;
+        }
+        let from = std::cmp::max(start, file.offset) - file.offset;
+        let to = std::cmp::min(end, file_end) - file.offset;
+        if file.attributes.padding {
+            // Padding files don't exist on the server
+            buf.resize(buf.len() + to - from, 0);
+            continue;
+        }
+        let url = file_url(base, file);
+        buf.extend(
+            mirror
+                .fetch_range(&url, from, to, file.length, bandwidth)
+                .await?,
+        );
+    }
+    check_integrity(index, buf, torrent_file)
+}
+
+/// Whole piece `index` from a BEP 17 seed, leaving `ranges` out
+async fn fetch_http_seed_piece(
+    client: &Client,
+    base: &Url,
+    torrent_file: &TorrentFile,
+    index: usize,
+    bandwidth: &PeerBandwidth,
+) -> std::result::Result<Vec<u8>, FetchError> {
+    // The infohash is already percent-encoded, the url crate would encode it twice
+    let separator = if base.query().is_some() { '&' } else { '?' };
+    let url = format!(
+        "{}{}info_hash={}&piece={}",
+        base, separator, torrent_file.infohash_encoded, index
+    );
+    let error = |e: reqwest::Error| TorrentError::WebSeed(format!("requesting {}: {}", url, e));
+    let response = client.get(&url).send().await.map_err(error)?;
+
+    let status = response.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        // The delay is in the header or, as BEP 17 has it, the body
+        let header_delay = retry_after(&response);
+        let body = response.text().await.unwrap_or_default();
+        let delay = header_delay.or_else(|| body.trim().parse().ok().map(Duration::from_secs));
+        return Err(match delay {
+            Some(delay) => FetchError::Busy(delay),
+            None => TorrentError::WebSeed(format!("{} is unavailable", url)).into(),
+        });
+    }
+    if status != StatusCode::OK {
+        return Err(
+            TorrentError::WebSeed(format!("unexpected status {} from {}", status, url)).into(),
+        );
+    }
+    let body = response.bytes().await.map_err(error)?;
+    bandwidth.download(body.len()).await;
+    if body.len() != torrent_file.calculate_piece_size(index) {
+        return Err(TorrentError::WebSeed(format!(
+            "expected piece {} from {}, got {} bytes",
+            index,
+            url,
+            body.len()
+        ))
+        .into());
+    }
+    check_integrity(index, body.to_vec(), torrent_file)
+}
+
+fn check_integrity(
+    index: usize,
+    buf: Vec<u8>,
+    torrent_file: &TorrentFile,
+) -> std::result::Result<Vec<u8>, FetchError> {
+    if !torrent_file.verify_piece(index, &buf) {
+        return Err(TorrentError::WebSeed(format!("wrong hash for piece {}", index)).into());
+    }
+    Ok(buf)
+}
+
+/// `Retry-After` header in seconds, HTTP dates are not supported
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Bytes `from..to` of the file at `url`
+async fn fetch_range(
+    client: &Client,
+    url: &Url,
+    from: usize,
+    to: usize,
+    file_length: usize,
+) -> std::result::Result<Vec<u8>, FetchError> {
+    let error = |e: reqwest::Error| TorrentError::WebSeed(format!("requesting {}: {}", url, e));
+    let response = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", from, to - 1))
+        .send()
+        .await
+        .map_err(error)?;
+
+    let status = response.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        if let Some(delay) = retry_after(&response) {
+            return Err(FetchError::Busy(delay));
+        }
+    }
+    // Servers ignoring ranges send the whole file, only fine if that's what we asked
+    let whole_file = status == StatusCode::OK && from == 0 && to == file_length;
+    if status != StatusCode::PARTIAL_CONTENT && !whole_file {
+        return Err(
+            TorrentError::WebSeed(format!("unexpected status {} from {}", status, url)).into(),
+        );
+    }
+    let body = response.bytes().await.map_err(error)?;
+    if body.len() != to - from {
+        return Err(TorrentError::WebSeed(format!(
+            "expected {} bytes from {}, got {}",
+            to - from,
+            url,
+            body.len()
+        ))
+        .into());
+    }
+    Ok(body.to_vec())
+}
+
+/// A URL ending with a slash is the directory holding the torrent's files,
+/// otherwise it is the file itself for single file torrents
+fn file_url(base: &Url, file: &FileInfo) -> Url {
+    // Multi file paths are nested in a directory named after the torrent
+    let is_multi_file = file.path.components().count() > 1;
+    if !base.path().ends_with('/') && !is_multi_file {
+        return base.clone();
+    }
+    let mut url = base.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty();
+        segments.extend(
+            file.path
+                .iter()
+                .map(|component| component.to_string_lossy().into_owned()),
+        );
+    }
+    url
+}
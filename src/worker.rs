@@ -1,50 +1,51 @@
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
-use anyhow::{anyhow, Result};
-use sha1::{Digest, Sha1};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::{mpsc::Sender, Mutex},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc::Sender, Mutex},
     time,
 };
 
 use crate::{
-    bitfield::bitfield_has_piece,
-    controller::{PieceResult, PieceWork, WorkerStatusMessage},
-    message::Message,
-    peer::{handshake, init_connection},
+    bitfield::bitfield_set_piece,
+    controller::PieceResult,
+    error::{Result, TorrentError},
+    event::{send_event, Event},
+    extension::HANDSHAKE_ID,
+    listener::IncomingPeer,
+    merkle::{hashes_with_proof, padding_hash, MERKLE_BLOCK_SIZE},
+    message::{HashRequest, Message},
+    peer::{extension_client_of, handshake, init_connection, PeerStream},
+    picker::{BlockRequest, PiecePicker},
+    rate_limit::RateLimited,
+    swarm::{ConnectedPeer, ConnectionSlots},
     torrent_file::TorrentFile,
     tracker::Peer,
 };
 
 pub struct State {
-    pub piece_progress: PieceProgress,
     pub bitfield: Vec<u8>,
     pub peer_choking: bool,
+    /// Nothing is uploaded, peers stay choked whatever their interest
+    pub peer_interested: bool,
+    pub requests: Vec<BlockRequest>,
 }
 
-#[derive(Default)]
-pub struct PieceProgress {
-    pub buf: Vec<u8>,
-    pub num_downloaded_bytes: usize,
-    num_requested_bytes: usize,
-    backlog_length: usize,
-}
-
-const MAX_BLOCK_SIZE: usize = 16384;
-const MAX_BACKLOG: usize = 5;
+const MAX_BACKLOG: usize = 10;
 
-pub async fn read_message(tcp_stream: &mut TcpStream) -> Result<Message> {
+pub async fn read_message<S>(stream: &mut S) -> Result<Message>
+where
+    S: AsyncRead + Unpin,
+{
     let mut len_buf = [0u8; 4];
-    tcp_stream.read_exact(&mut len_buf).await?;
+    stream.read_exact(&mut len_buf).await?;
 
     let len = u32::from_be_bytes(len_buf) as usize;
     if len == 0 {
         return Ok(Message::KeepAlive);
     }
     let mut payload_buf = vec![0u8; len];
-    tcp_stream.read_exact(&mut payload_buf).await?;
+    stream.read_exact(&mut payload_buf).await?;
 
     let mut whole_msg_bytes: Vec<u8> = Vec::with_capacity(len_buf.len() + payload_buf.len());
     whole_msg_bytes.extend_from_slice(&len_buf);
@@ -52,9 +53,12 @@ pub async fn read_message(tcp_stream: &mut TcpStream) -> Result<Message> {
     Message::from_bytes(&whole_msg_bytes)
 }
 
-pub async fn write_message(tcp_stream: &mut TcpStream, message: &Message) -> Result<()> {
+pub async fn write_message<S>(stream: &mut S, message: &Message) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
     let bytes = message.to_bytes()?;
-    tcp_stream.write_all(&bytes).await?;
+    stream.write_all(&bytes).await?;
     Ok(())
 }
 
@@ -62,192 +66,355 @@ pub const TIMEOUT: u64 = 10;
 
 pub async fn start_download_worker(
     peer: &Peer,
+    infohash: &[u8; 20],
     torrent_file: &TorrentFile,
-    work_queue: &Arc<Mutex<VecDeque<PieceWork>>>,
+    picker: &Arc<Mutex<PiecePicker>>,
+    slots: &ConnectionSlots,
     result_sender: &Sender<PieceResult>,
-    status_sender: &Sender<WorkerStatusMessage>,
+    events: &broadcast::Sender<Event>,
 ) -> Result<()> {
-    // Open connection and handshake with peer
-    let mut tcp_stream =
-        match time::timeout(Duration::new(TIMEOUT, 0), handshake(peer, torrent_file)).await {
-            Ok(Ok(tcp_stream)) => tcp_stream,
-            Ok(Err(_)) => {
-                // eprintln!("could not open connection with peer {}", peer.ip);
-                // dbg!(e);
-                return Err(anyhow!("handshake"));
-            }
-            Err(_) => {
-                // eprintln!("timed out opening connection with peer {}", peer.ip);
-                return Err(anyhow!("handshake"));
-            }
-        };
+    let (stream, peer_id, state) = connect(peer, infohash, picker, slots).await?;
+    let connection = Connection {
+        stream,
+        addr: peer.addr(),
+        peer_id,
+        state,
+    };
+    run_connection(
+        connection,
+        torrent_file,
+        picker,
+        slots,
+        result_sender,
+        events,
+    )
+    .await
+}
 
-    let mut state =
-        match time::timeout(Duration::new(TIMEOUT, 0), init_connection(&mut tcp_stream)).await {
-            Ok(Ok(state)) => state,
-            _ => {
-                // eprint!("error reading bitfield from peer");
-                return Err(anyhow!("init"));
-            }
-        };
+/// Download from a peer that connected to us
+pub async fn start_incoming_worker(
+    incoming: IncomingPeer,
+    torrent_file: &TorrentFile,
+    picker: &Arc<Mutex<PiecePicker>>,
+    slots: &ConnectionSlots,
+    result_sender: &Sender<PieceResult>,
+    events: &broadcast::Sender<Event>,
+) -> Result<()> {
+    let mut stream = incoming.stream;
+    let state = start_exchange(&mut stream, picker).await?;
+    let connection = Connection {
+        stream,
+        addr: incoming.addr,
+        peer_id: incoming.remote.peer_id,
+        state,
+    };
+    run_connection(
+        connection,
+        torrent_file,
+        picker,
+        slots,
+        result_sender,
+        events,
+    )
+    .await
+}
 
-    while let Some(piece_work) = {
-        let mut guard = work_queue.lock().await;
-        guard.pop_front()
-    } {
-        // Check if peer has piece
-        if !bitfield_has_piece(&state.bitfield, piece_work.index) {
-            work_queue.lock().await.push_back(piece_work);
-            continue;
-        }
+/// Peer connection past the handshakes, either way
+struct Connection {
+    stream: PeerStream,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    state: State,
+}
+
+async fn run_connection(
+    connection: Connection,
+    torrent_file: &TorrentFile,
+    picker: &Arc<Mutex<PiecePicker>>,
+    slots: &ConnectionSlots,
+    result_sender: &Sender<PieceResult>,
+    events: &broadcast::Sender<Event>,
+) -> Result<()> {
+    let Connection {
+        stream,
+        addr,
+        peer_id,
+        state,
+    } = connection;
+    // The client name comes with the extension handshake, if ever
+    let connected = slots.register(torrent_file.infohash, addr, peer_id, None);
+    send_event(
+        events,
+        Event::PeerConnected {
+            infohash: torrent_file.infohash,
+            peer: addr,
+        },
+    );
 
-        state.piece_progress = PieceProgress::default();
-        state.piece_progress.buf.resize(piece_work.length, 0u8);
+    // Every byte read counts, not only blocks
+    let mut stream = RateLimited::new(stream, connected.bandwidth());
+    let result = download(
+        &connected,
+        &mut stream,
+        state,
+        torrent_file,
+        picker,
+        result_sender,
+        events,
+    )
+    .await;
+    // Blocks requested from this peer will never arrive, let other workers pick them
+    picker.lock().await.release_peer(addr);
+    send_event(
+        events,
+        Event::PeerDisconnected {
+            infohash: torrent_file.infohash,
+            peer: addr,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        },
+    );
+    result
+}
+
+/// Open connection and handshake with peer, up to our bitfield
+async fn connect(
+    peer: &Peer,
+    infohash: &[u8; 20],
+    picker: &Arc<Mutex<PiecePicker>>,
+    slots: &ConnectionSlots,
+) -> Result<(PeerStream, [u8; 20], State)> {
+    let half_open = slots.acquire_half_open().await;
+    let (mut stream, remote) =
+        handshake(peer, infohash, &slots.transports, &slots.identity.peer_id).await?;
+    drop(half_open);
+
+    let state = start_exchange(&mut stream, picker).await?;
+    Ok((stream, remote.peer_id, state))
+}
+
+async fn start_exchange(
+    stream: &mut PeerStream,
+    picker: &Arc<Mutex<PiecePicker>>,
+) -> Result<State> {
+    let bitfield = picker.lock().await.verified_bitfield();
+    match time::timeout(
+        Duration::new(TIMEOUT, 0),
+        init_connection(stream, &bitfield),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(timed_out("sending bitfield")),
+    }
+}
 
-        status_sender
-            .send(WorkerStatusMessage {
-                connected: true,
-                id: peer.ip,
-            })
+async fn download(
+    peer: &ConnectedPeer,
+    stream: &mut RateLimited<'_, PeerStream>,
+    mut state: State,
+    torrent_file: &TorrentFile,
+    picker: &Arc<Mutex<PiecePicker>>,
+    result_sender: &Sender<PieceResult>,
+    events: &broadcast::Sender<Event>,
+) -> Result<()> {
+    loop {
+        // Send Request messages until backlog is full, possibly spanning several pieces
+        while !state.peer_choking && state.requests.len() < MAX_BACKLOG {
+            let Some(request) = picker.lock().await.pick_block(peer.addr(), &state.bitfield) else {
+                break;
+            };
+            send_message(
+                peer,
+                stream,
+                &Message::Request(
+                    u32::try_from(request.index).expect("pieces are too big"),
+                    u32::try_from(request.begin).expect("pieces are too big"),
+                    u32::try_from(request.length).expect("pieces are too big"),
+                ),
+                "sending request",
+            )
             .await?;
+            state.requests.push(request);
+        }
 
-        while state.piece_progress.num_downloaded_bytes < piece_work.length {
-            // Send Request messages until backlog is full
-            while state.piece_progress.backlog_length < MAX_BACKLOG
-                && state.piece_progress.num_requested_bytes < piece_work.length
-            {
-                let block_size = std::cmp::min(
-                    MAX_BLOCK_SIZE,
-                    piece_work.length - state.piece_progress.num_requested_bytes,
-                );
-                match time::timeout(
-                    Duration::new(TIMEOUT, 0),
-                    write_message(
-                        &mut tcp_stream,
-                        &Message::Request(
-                            u32::try_from(piece_work.index).expect("pieces are to big"),
-                            u32::try_from(state.piece_progress.num_requested_bytes)
-                                .expect("pieces are too big"),
-                            u32::try_from(block_size).expect("pieces are too big"),
-                        ),
-                    ),
-                )
-                .await
-                {
-                    Ok(Ok(_)) => {
-                        state.piece_progress.backlog_length += 1;
-                        state.piece_progress.num_requested_bytes += block_size;
-                    }
-                    _ => {
-                        work_queue.lock().await.push_back(piece_work);
-                        return Err(anyhow!("request"));
-                    }
+        if state.requests.is_empty() && picker.lock().await.is_complete() {
+            return Ok(());
+        }
+
+        // Slow limits must not make healthy peers time out
+        stream.throttle().await;
+        stream.set_read_timeout(Some(Duration::new(TIMEOUT, 0)));
+        let read = read_message(stream).await;
+        stream.set_read_timeout(None);
+        let message = match read {
+            Ok(message) => message,
+            Err(TorrentError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                // Peer has nothing we need right now, keep the connection open
+                if state.requests.is_empty() {
+                    write_message(stream, &Message::KeepAlive).await?;
+                    continue;
                 }
+                return Err(timed_out("waiting for blocks"));
             }
+            Err(e) => return Err(e),
+        };
 
-            let message =
-                match time::timeout(Duration::new(10, 0), read_message(&mut tcp_stream)).await {
-                    Ok(Ok(message)) => message,
-                    e => {
-                        work_queue.lock().await.push_back(piece_work);
-                        return Err(anyhow!("reading message {:?}", e));
-                    }
+        match message {
+            Message::Piece(received_piece_index, received_block_index, payload) => {
+                let Some(position) = state.requests.iter().position(|request| {
+                    request.index == received_piece_index as usize
+                        && request.begin == received_block_index as usize
+                        && request.length == payload.len()
+                }) else {
+                    // Not requested, or requested before being choked
+                    continue;
                 };
+                let request = state.requests.swap_remove(position);
 
-            match message {
-                Message::Piece(received_piece_index, received_block_index, payload) => {
-                    if let Err(e) = validate_piece_message(
-                        &piece_work,
-                        &state,
-                        received_piece_index,
-                        received_block_index,
-                        &payload,
-                    ) {
-                        work_queue.lock().await.push_back(piece_work);
+                let piece =
+                    picker
+                        .lock()
+                        .await
+                        .block_received(request.index, request.begin, &payload);
+                if let Some(buf) = piece {
+                    if let Err(e) = end_download(
+                        peer,
+                        request.index,
+                        buf,
+                        torrent_file,
+                        stream,
+                        result_sender,
+                        events,
+                    )
+                    .await
+                    {
+                        picker.lock().await.piece_failed(request.index);
                         return Err(e);
                     }
-
-                    // FIX: remove all as
-                    state.piece_progress.buf[received_block_index as usize
-                        ..received_block_index as usize + payload.len()]
-                        .copy_from_slice(&payload);
-                    state.piece_progress.num_downloaded_bytes += payload.len();
-                    state.piece_progress.backlog_length -= 1;
-                }
-                Message::Choke => {
-                    state.peer_choking = true;
-                }
-                Message::Unchoke => {
-                    state.peer_choking = false;
                 }
-                Message::KeepAlive => {}
-
-                // other cases
-                message => {
-                    work_queue.lock().await.push_back(piece_work);
-                    return Err(anyhow!("unsupported behaviour from peer {:?}", message));
+            }
+            Message::Choke => {
+                // Pending requests are discarded by the peer when choking
+                state.peer_choking = true;
+                state.requests.clear();
+                picker.lock().await.release_peer(peer.addr());
+            }
+            Message::Unchoke => {
+                state.peer_choking = false;
+            }
+            Message::Have(index) => {
+                bitfield_set_piece(&mut state.bitfield, index as usize);
+            }
+            // Usually the first message, peers without pieces leave it out
+            Message::Bitfield(bitfield) => {
+                if bitfield.len() != state.bitfield.len() {
+                    return Err(TorrentError::PeerProtocol(format!(
+                        "bitfield of {} bytes for {} pieces",
+                        bitfield.len(),
+                        torrent_file.num_pieces()
+                    )));
                 }
+                state.bitfield = bitfield;
             }
-        }
-
-        // Piece is complete
-        if (end_download(&piece_work, &state, &mut tcp_stream, result_sender).await).is_err() {
-            work_queue.lock().await.push_back(piece_work);
-            return Err(anyhow!("ending download"));
+            Message::Interested => state.peer_interested = true,
+            Message::NotInterested => state.peer_interested = false,
+            // Peers are never unchoked, requests sent anyway are ignored
+            Message::Request(..) | Message::Cancel(..) => {}
+            Message::HashRequest(request) => {
+                send_message(
+                    peer,
+                    stream,
+                    &hash_reply(torrent_file, request),
+                    "sending hashes",
+                )
+                .await?;
+            }
+            // Piece layers are complete before the download starts, they come
+            // with the metainfo or from the peer that sent the metadata
+            Message::Hashes(..) | Message::HashReject(..) => {}
+            Message::Extended(HANDSHAKE_ID, payload) => {
+                peer.set_extension_client(extension_client_of(&payload));
+            }
+            Message::KeepAlive | Message::Extended(..) => {}
         }
     }
-    Ok(())
-}
-
-fn check_integrity(piece_work: &PieceWork, state: &State) -> bool {
-    let hash = <Sha1 as Digest>::digest(&state.piece_progress.buf);
-    hash.as_slice() == piece_work.hash
-}
-
-fn validate_piece_message(
-    piece_work: &PieceWork,
-    state: &State,
-    received_piece_index: u32,
-    received_block_index: u32,
-    payload: &Vec<u8>,
-) -> Result<()> {
-    if payload.len() < 8 {
-        return Err(anyhow!("payload too short"));
-    }
-    if received_piece_index != u32::try_from(piece_work.index).expect("pieces are too big") {
-        return Err(anyhow!("got wrong piece from peer"));
-    }
-    if received_block_index as usize > state.piece_progress.buf.len() {
-        return Err(anyhow!("piece offset too high"));
-    }
-    if received_block_index as usize + payload.len() > state.piece_progress.buf.len() {
-        return Err(anyhow!("data too long for offset"));
-    }
-    Ok(())
 }
 
 async fn end_download(
-    piece_work: &PieceWork,
-    state: &State,
-    tcp_stream: &mut TcpStream,
+    peer: &ConnectedPeer,
+    index: usize,
+    buf: Vec<u8>,
+    torrent_file: &TorrentFile,
+    stream: &mut RateLimited<'_, PeerStream>,
     result_sender: &Sender<PieceResult>,
+    events: &broadcast::Sender<Event>,
 ) -> Result<()> {
-    if !check_integrity(piece_work, state) {
-        return Err(anyhow!("wrong hash for piece {:?}", piece_work));
+    if !torrent_file.verify_piece(index, &buf) {
+        send_event(
+            events,
+            Event::HashFailed {
+                infohash: torrent_file.infohash,
+                index,
+                peer: peer.addr(),
+            },
+        );
+        return Err(TorrentError::PeerProtocol(format!(
+            "wrong hash for piece {}",
+            index
+        )));
     }
 
-    time::timeout(
-        Duration::new(TIMEOUT, 0),
-        write_message(tcp_stream, &Message::Have(piece_work.index as u32)),
-    )
-    .await??;
+    send_message(peer, stream, &Message::Have(index as u32), "sending have").await?;
 
-    result_sender
+    // The controller only stops listening once the download is over
+    let _ = result_sender
         .send(PieceResult {
-            index: piece_work.index,
-            buf: state.piece_progress.buf.clone(),
+            index,
+            peer: Some(peer.addr()),
+            buf,
         })
-        .await?;
+        .await;
 
     Ok(())
 }
+
+/// Answer from the piece layers of the metainfo, the only layer kept
+fn hash_reply(torrent_file: &TorrentFile, request: HashRequest) -> Message {
+    let piece_width = torrent_file.piece_length / MERKLE_BLOCK_SIZE;
+    let hashes = torrent_file
+        .piece_layers
+        .get(&request.pieces_root)
+        .filter(|_| request.base_layer == piece_width.trailing_zeros())
+        .and_then(|layer| {
+            hashes_with_proof(
+                layer,
+                padding_hash(piece_width),
+                request.index as usize,
+                request.length as usize,
+                request.proof_layers as usize,
+            )
+        });
+    match hashes {
+        Some(hashes) => Message::Hashes(request, hashes),
+        None => Message::HashReject(request),
+    }
+}
+
+/// Write `message` once upload limits allow it
+async fn send_message(
+    peer: &ConnectedPeer,
+    stream: &mut RateLimited<'_, PeerStream>,
+    message: &Message,
+    step: &str,
+) -> Result<()> {
+    let bytes = message.to_bytes()?;
+    peer.bandwidth().upload(bytes.len()).await;
+    time::timeout(Duration::new(TIMEOUT, 0), stream.write_all(&bytes))
+        .await
+        .map_err(|_| timed_out(step))??;
+    Ok(())
+}
+
+fn timed_out(step: &str) -> TorrentError {
+    TorrentError::PeerProtocol(format!("timed out {}", step))
+}
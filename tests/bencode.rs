@@ -0,0 +1,58 @@
+use torrent_client::{
+    bencode::{dict_value_span, value_end},
+    extension::ExtensionHandshake,
+};
+
+#[test]
+fn finds_the_raw_span_of_a_key() {
+    let bytes = b"d8:announce3:url4:infod6:lengthi5e4:name1:aee";
+    let span = dict_value_span(bytes, b"info").unwrap();
+    assert_eq!(&bytes[span], b"d6:lengthi5e4:name1:ae");
+    assert!(dict_value_span(bytes, b"missing").is_err());
+}
+
+#[test]
+fn value_end_skips_nested_values() {
+    let bytes = b"d1:ald1:xi1eee1:b3:xyze<appended>";
+    assert_eq!(
+        value_end(bytes, 0).unwrap(),
+        bytes.len() - b"<appended>".len()
+    );
+}
+
+#[test]
+fn rejects_string_lengths_past_the_data() {
+    assert!(value_end(b"5:abc", 0).is_err());
+    // Lengths whose sum with the position overflows, or wraps around to a
+    // small value in release builds
+    assert!(value_end(b"18446744073709551615:abc", 0).is_err());
+    assert!(value_end(b"18446744073709551614:abc", 0).is_err());
+    assert!(dict_value_span(b"d4:info18446744073709551615:abce", b"info").is_err());
+    assert!(dict_value_span(b"d18446744073709551611:info1:ae", b"info").is_err());
+}
+
+#[test]
+fn rejects_deep_nesting_without_overflowing_the_stack() {
+    let depth = 1_000_000;
+    let mut bytes = "l".repeat(depth).into_bytes();
+    bytes.extend(std::iter::repeat_n(b'e', depth));
+    assert!(value_end(&bytes, 0).is_err());
+
+    let mut dict = b"d4:info".to_vec();
+    dict.extend(std::iter::repeat_n(b'l', depth));
+    dict.extend(std::iter::repeat_n(b'e', depth + 1));
+    assert!(dict_value_span(&dict, b"info").is_err());
+
+    let mut handshake = b"d1:v".to_vec();
+    handshake.extend(std::iter::repeat_n(b'l', depth));
+    handshake.extend(std::iter::repeat_n(b'e', depth + 1));
+    assert!(ExtensionHandshake::from_bytes(&handshake).is_err());
+}
+
+#[test]
+fn accepts_reasonable_nesting() {
+    let depth = 100;
+    let mut bytes = "l".repeat(depth).into_bytes();
+    bytes.extend(std::iter::repeat_n(b'e', depth));
+    assert_eq!(value_end(&bytes, 0).unwrap(), bytes.len());
+}
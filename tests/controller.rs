@@ -0,0 +1,192 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::{broadcast, Mutex},
+    time,
+};
+use torrent_client::{
+    controller::{download_file, PeerFeeds},
+    create::{create_torrent, CreateOptions},
+    identity::Identity,
+    message::Message,
+    mse::EncryptionPolicy,
+    peer::{accept_handshake, PeerStream, TransportPreference, Transports},
+    picker::PiecePicker,
+    rate_limit::{Bandwidth, RateLimits},
+    storage::Storage,
+    swarm::{ConnectionLimits, ConnectionSlots},
+    torrent_file::TorrentFile,
+    worker::{read_message, write_message},
+};
+
+const PIECE_LENGTH: usize = 16384;
+
+fn compact(addr: SocketAddr) -> Vec<u8> {
+    let SocketAddr::V4(addr) = addr else {
+        panic!("tests listen on IPv4");
+    };
+    let mut peer = addr.ip().octets().to_vec();
+    peer.extend(addr.port().to_be_bytes());
+    peer
+}
+
+type Announces = Arc<StdMutex<Vec<String>>>;
+
+/// Tracker asking for announces every second, that knows `first` peer and
+/// then `later` one. Keeps the request line of every announce.
+async fn start_tracker(first: SocketAddr, later: SocketAddr) -> (String, Announces) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let announces = Announces::default();
+    let requests = announces.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let length = stream.read(&mut request).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..length]).into_owned();
+            let peer = {
+                let mut requests = requests.lock().unwrap();
+                requests.push(request.lines().next().unwrap_or_default().to_string());
+                match requests.len() {
+                    1 => first,
+                    _ => later,
+                }
+            };
+            let mut body = b"d8:intervali1e12:min intervali1e5:peers6:".to_vec();
+            body.extend(compact(peer));
+            body.push(b'e');
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    (format!("http://{}/announce", addr), announces)
+}
+
+/// Peer with every piece of `data`, serving each connection
+async fn start_seeder(torrent_file: &TorrentFile, data: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let infohash = torrent_file.infohash;
+    let num_bytes = torrent_file.num_pieces().div_ceil(8);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let data = data.clone();
+            tokio::spawn(async move {
+                let mut stream = PeerStream::Tcp(stream);
+                accept_handshake(&mut stream, &[infohash], &[2; 20])
+                    .await
+                    .unwrap();
+                for message in [Message::Bitfield(vec![0xff; num_bytes]), Message::Unchoke] {
+                    write_message(&mut stream, &message).await.unwrap();
+                }
+                while let Ok(message) = read_message(&mut stream).await {
+                    if let Message::Request(index, begin, length) = message {
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        let block = data[start..start + length as usize].to_vec();
+                        let piece = Message::Piece(index, begin, block);
+                        if write_message(&mut stream, &piece).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Nothing listens there, connecting fails right away
+async fn dead_peer() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn trackers_are_announced_to_again_for_new_peers() {
+    let root = std::env::temp_dir().join(format!("controller-reannounce-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let data: Vec<u8> = (0..40_000).map(|i| (i * 11 + i / 89) as u8).collect();
+    let path = root.join("file.bin");
+    fs::write(&path, &data).unwrap();
+    // Trackers are left out of the infohash, the seeder starts before the
+    // torrent has one
+    let content = TorrentFile::from_bytes(
+        &create_torrent(
+            &path,
+            &CreateOptions {
+                piece_length: Some(PIECE_LENGTH),
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    let seeder = start_seeder(&content, data.clone()).await;
+    let (tracker, announces) = start_tracker(dead_peer().await, seeder).await;
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        trackers: vec![vec![tracker]],
+        ..Default::default()
+    };
+    let torrent_file = TorrentFile::from_bytes(&create_torrent(&path, &options).unwrap()).unwrap();
+    let slots = ConnectionSlots::new(
+        ConnectionLimits::default(),
+        Transports::new(TransportPreference::TcpOnly, EncryptionPolicy::Disabled),
+        Identity::new("-TT0100-"),
+        Bandwidth::new(RateLimits::default(), RateLimits::default(), None),
+    );
+    let picker = Arc::new(Mutex::new(PiecePicker::new(&torrent_file)));
+    let download_dir = root.join("download");
+    let storage = Storage::new(&download_dir, &torrent_file);
+    let (events, _) = broadcast::channel(64);
+
+    time::timeout(
+        Duration::from_secs(20),
+        download_file(
+            &torrent_file,
+            &picker,
+            &storage,
+            &slots,
+            &events,
+            PeerFeeds::default(),
+            std::future::pending(),
+        ),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(fs::read(download_dir.join("file.bin")).unwrap() == data);
+
+    // Started, regular announces until the seeder is known, then completed
+    // and stopped since nothing is seeded
+    let announces = announces.lock().unwrap();
+    let [started, regular @ .., completed, stopped] = announces.as_slice() else {
+        panic!("announces {:?}", announces);
+    };
+    assert!(!regular.is_empty());
+    assert!(started.contains("event=started"));
+    assert!(started.contains("left=40000") && started.contains("downloaded=0"));
+    assert!(regular.iter().all(|regular| !regular.contains("event=")));
+    for last in [completed, stopped] {
+        assert!(last.contains("left=0") && last.contains("downloaded=40000"));
+        assert!(last.contains("uploaded=0"));
+    }
+    assert!(completed.contains("event=completed"));
+    assert!(stopped.contains("event=stopped"));
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use torrent_client::{
+    infohash::{hex_decode, hex_encode},
+    torrent_file::{MetaVersion, TorrentFile},
+};
+
+/// Expected infohashes were computed with Python's hashlib, SHA-1 and
+/// SHA-256 over the bytes of the `info` value, its bounds found by a
+/// standalone bencode scanner. This crate never produced them.
+struct Case {
+    path: &'static str,
+    meta_version: MetaVersion,
+    v1: Option<&'static str>,
+    v2: Option<&'static str>,
+}
+
+const CORPUS: &[Case] = &[
+    // Real torrent from cdimage.debian.org, single file
+    Case {
+        path: "assets/debian.torrent",
+        meta_version: MetaVersion::V1,
+        v1: Some("8ffeae56c32ab54d9992e4cbb20ef070637a9c72"),
+        v2: None,
+    },
+    // Nested directories and an announce-list
+    Case {
+        path: "tests/fixtures/multi_file.torrent",
+        meta_version: MetaVersion::V1,
+        v1: Some("321dc0aa599329bc9fda0698a0abd1606d6136bc"),
+        v2: None,
+    },
+    Case {
+        path: "tests/fixtures/private.torrent",
+        meta_version: MetaVersion::V1,
+        v1: Some("c09f4c0ec56428f75e139d5c067a7c0217097c5e"),
+        v2: None,
+    },
+    // `source`, `md5sum` and an unknown key, with keys out of order as some
+    // clients write them: re-encoding the parsed info would change the hash
+    Case {
+        path: "tests/fixtures/extra_keys.torrent",
+        meta_version: MetaVersion::V1,
+        v1: Some("c70dea3cba172b45f270f4cbb15f46a48e89a8f4"),
+        v2: None,
+    },
+    // The v2 and hybrid torrents are synthetic, generated for this corpus by
+    // following BEP 52 for the files of tests/v2.rs. No torrent made by
+    // another client is in the corpus yet.
+    Case {
+        path: "tests/fixtures/v2.torrent",
+        meta_version: MetaVersion::V2,
+        v1: None,
+        v2: Some("6bfe93a0ded3b442bb5783584740dac96862158012fd85f8992bfb36280584b1"),
+    },
+    Case {
+        path: "tests/fixtures/hybrid.torrent",
+        meta_version: MetaVersion::Hybrid,
+        v1: Some("2a5991d86896b97e6e73984af1533d01ee9764ee"),
+        v2: Some("2a4c7179a2078ce4bf371143e768ffb613e8b0f7e781f175bd092be76a1e10b2"),
+    },
+];
+
+fn load(path: &str) -> TorrentFile {
+    let bytes = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap();
+    TorrentFile::from_bytes(&bytes).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+#[test]
+fn infohashes_match_the_corpus() {
+    for case in CORPUS {
+        let torrent_file = load(case.path);
+        assert_eq!(
+            torrent_file.meta_version, case.meta_version,
+            "{}",
+            case.path
+        );
+        assert_eq!(
+            torrent_file
+                .infohash_v2
+                .map(|infohash| hex_encode(&infohash)),
+            case.v2.map(str::to_string),
+            "{}",
+            case.path
+        );
+        // v2 only torrents are identified by their truncated v2 infohash
+        let expected = case.v1.or(case.v2).unwrap();
+        assert_eq!(
+            hex_encode(&torrent_file.infohash),
+            expected[..40],
+            "{}",
+            case.path
+        );
+        assert_eq!(
+            torrent_file.infohash_encoded,
+            torrent_client::infohash::url_encode(&hex_decode(&expected[..40]).unwrap()),
+            "{}",
+            case.path
+        );
+    }
+}
+
+#[test]
+fn hybrid_torrents_join_both_swarms() {
+    let torrent_file = load("tests/fixtures/hybrid.torrent");
+    let swarms: Vec<String> = torrent_file
+        .swarm_infohashes()
+        .iter()
+        .map(|infohash| hex_encode(infohash))
+        .collect();
+    assert_eq!(
+        swarms,
+        [
+            "2a5991d86896b97e6e73984af1533d01ee9764ee",
+            "2a4c7179a2078ce4bf371143e768ffb613e8b0f7",
+        ]
+    );
+}
+
+#[test]
+fn metadata_keeps_the_parsed_fields() {
+    let private = load("tests/fixtures/private.torrent");
+    assert!(private.private);
+    assert_eq!(private.length, 50000);
+
+    let multi_file = load("tests/fixtures/multi_file.torrent");
+    assert_eq!(multi_file.files.len(), 3);
+    assert_eq!(
+        multi_file.files[2].path,
+        Path::new("album/data/sub/part2.bin")
+    );
+    assert_eq!(multi_file.trackers.len(), 2);
+
+    let extra_keys = load("tests/fixtures/extra_keys.torrent");
+    assert_eq!(extra_keys.length, 43000);
+}
@@ -0,0 +1,250 @@
+use std::{net::TcpListener as StdTcpListener, time::Duration};
+
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::mpsc,
+    time,
+};
+use torrent_client::{
+    identity::Identity,
+    listener::PeerListener,
+    mse::{accept, initiate, EncryptionPolicy, MseStream, Rc4},
+    peer::{handshake, PeerStream, TransportPreference, Transports},
+    rate_limit::{Bandwidth, RateLimits},
+    swarm::{ConnectionLimits, ConnectionSlots},
+    tracker::Peer,
+};
+
+const INFOHASH: [u8; 20] = [7; 20];
+const OTHER_INFOHASH: [u8; 20] = [9; 20];
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Keystream of `key` at each offset, from RFC 6229
+fn check_keystream(key: &[u8], expected: &[(usize, &str)]) {
+    let mut keystream = vec![0u8; 4096 + 16];
+    Rc4::new(key).apply(&mut keystream);
+    for (offset, bytes) in expected {
+        assert_eq!(
+            hex(&keystream[*offset..offset + 16]),
+            *bytes,
+            "offset {}",
+            offset
+        );
+    }
+}
+
+#[test]
+fn rc4_matches_rfc_6229() {
+    check_keystream(
+        &[1, 2, 3, 4, 5],
+        &[
+            (0, "b2396305f03dc027ccc3524a0a1118a8"),
+            (16, "6982944f18fc82d589c403a47a0d0919"),
+            (1024, "30abbcc7c20b01609f23ee2d5f6bb7df"),
+            (4096, "ff25b58995996707e51fbdf08b34d875"),
+        ],
+    );
+    check_keystream(
+        &(1..=16).collect::<Vec<u8>>(),
+        &[
+            (0, "9ac7cc9a609d1ef7b2932899cde41b97"),
+            (16, "5248c4959014126a6e8a84f11d1a9e1c"),
+            (1024, "bdf0324e6083dcc6d3cedd3ca8c53c16"),
+            (4096, "a36a4c301ae8ac13610ccbc12256cacc"),
+        ],
+    );
+}
+
+/// Both ends of a negotiation over an in-memory pipe
+async fn negotiate(
+    initiator: EncryptionPolicy,
+    acceptor: EncryptionPolicy,
+) -> (MseStream<DuplexStream>, MseStream<DuplexStream>) {
+    let (a, b) = duplex(64 * 1024);
+    let infohashes = [OTHER_INFOHASH, INFOHASH];
+    let (initiated, accepted) = tokio::join!(
+        initiate(a, &INFOHASH, initiator),
+        accept(b, &infohashes, acceptor)
+    );
+    (initiated.unwrap(), accepted.unwrap())
+}
+
+async fn assert_round_trip(a: &mut MseStream<DuplexStream>, b: &mut MseStream<DuplexStream>) {
+    let message: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    a.write_all(&message).await.unwrap();
+    let mut received = vec![0u8; message.len()];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, message);
+
+    b.write_all(b"reply").await.unwrap();
+    let mut reply = [0u8; 5];
+    a.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"reply");
+}
+
+#[tokio::test]
+async fn initiator_and_acceptor_agree_on_keys() {
+    for (initiator, acceptor) in [
+        (EncryptionPolicy::Preferred, EncryptionPolicy::Preferred),
+        (EncryptionPolicy::Required, EncryptionPolicy::Preferred),
+        (EncryptionPolicy::Preferred, EncryptionPolicy::Required),
+        (EncryptionPolicy::Required, EncryptionPolicy::Required),
+    ] {
+        let (mut a, mut b) = negotiate(initiator, acceptor).await;
+        assert!(a.is_encrypted() && b.is_encrypted());
+        assert_round_trip(&mut a, &mut b).await;
+    }
+}
+
+#[tokio::test]
+async fn unknown_infohashes_are_refused() {
+    let (a, b) = duplex(64 * 1024);
+    let (_, accepted) = tokio::join!(
+        initiate(a, &INFOHASH, EncryptionPolicy::Preferred),
+        accept(b, &[OTHER_INFOHASH], EncryptionPolicy::Preferred)
+    );
+    assert!(accepted.is_err());
+}
+
+fn plaintext_handshake() -> Vec<u8> {
+    let mut message = vec![19];
+    message.extend(b"BitTorrent protocol");
+    message.extend([0; 8]);
+    message.extend(INFOHASH);
+    message.extend([1; 20]);
+    message
+}
+
+#[tokio::test]
+async fn plaintext_peers_are_accepted_unless_encryption_is_required() {
+    let (mut a, b) = duplex(1024);
+    a.write_all(&plaintext_handshake()).await.unwrap();
+    let mut accepted = accept(b, &[INFOHASH], EncryptionPolicy::Preferred)
+        .await
+        .unwrap();
+    assert!(!accepted.is_encrypted());
+    // Bytes read to tell the peer apart are not lost
+    let mut received = vec![0u8; plaintext_handshake().len()];
+    accepted.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, plaintext_handshake());
+
+    let (mut a, b) = duplex(1024);
+    a.write_all(&plaintext_handshake()).await.unwrap();
+    assert!(accept(b, &[INFOHASH], EncryptionPolicy::Required)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn outgoing_connections_fall_back_to_plaintext() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = tokio::spawn(async move {
+        // Legacy peers close the connection on anything but a handshake
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut public_key = [0u8; 96];
+        stream.read_exact(&mut public_key).await.unwrap();
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; 4];
+        stream.read_exact(&mut received).await.unwrap();
+        received
+    });
+
+    let peer_addr = Peer {
+        ip: addr.ip(),
+        port: addr.port(),
+    };
+    let transports = Transports::new(TransportPreference::TcpOnly, EncryptionPolicy::Preferred);
+    let mut stream = transports.open(&peer_addr, &INFOHASH).await.unwrap();
+    assert!(matches!(stream, PeerStream::Tcp(_)));
+    stream.write_all(b"ping").await.unwrap();
+    assert_eq!(peer.await.unwrap(), b"ping");
+
+    let transports = Transports::new(TransportPreference::TcpOnly, EncryptionPolicy::Required);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(stream);
+    });
+    let peer_addr = Peer {
+        ip: addr.ip(),
+        port: addr.port(),
+    };
+    assert!(transports.open(&peer_addr, &INFOHASH).await.is_err());
+}
+
+fn slots(encryption: EncryptionPolicy) -> ConnectionSlots {
+    ConnectionSlots::new(
+        ConnectionLimits::default(),
+        Transports::new(TransportPreference::TcpOnly, encryption),
+        Identity::new("-TT0100-"),
+        Bandwidth::new(RateLimits::default(), RateLimits::default(), None),
+    )
+}
+
+#[tokio::test]
+async fn listener_hands_encrypted_peers_to_their_torrent() {
+    let port = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut listener_slots = slots(EncryptionPolicy::Preferred);
+    listener_slots.transports.port = port;
+    let listener_peer_id = listener_slots.identity.peer_id;
+    let listener = PeerListener::new(listener_slots);
+    let (sender, mut receiver) = mpsc::channel(1);
+    listener.add(INFOHASH, sender);
+
+    let peer = Peer {
+        ip: "127.0.0.1".parse().unwrap(),
+        port,
+    };
+    let client = slots(EncryptionPolicy::Required);
+    // The port is opened by a task of the listener
+    let (mut stream, remote) = loop {
+        match handshake(
+            &peer,
+            &INFOHASH,
+            &client.transports,
+            &client.identity.peer_id,
+        )
+        .await
+        {
+            Ok(connected) => break connected,
+            Err(_) => time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    assert_eq!(remote.peer_id, listener_peer_id);
+    assert!(remote.supports_v2());
+
+    let mut incoming = receiver.recv().await.unwrap();
+    assert_eq!(incoming.infohash, INFOHASH);
+    assert_eq!(incoming.remote.peer_id, client.identity.peer_id);
+    let PeerStream::Mse(mse) = &incoming.stream else {
+        panic!("incoming stream is not wrapped");
+    };
+    assert!(mse.is_encrypted());
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = [0u8; 4];
+    incoming.stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"ping");
+
+    // Peers of other swarms are disconnected during the handshake
+    listener.remove(&INFOHASH);
+    assert!(handshake(
+        &peer,
+        &INFOHASH,
+        &client.transports,
+        &client.identity.peer_id
+    )
+    .await
+    .is_err());
+}
@@ -0,0 +1,259 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use torrent_client::{
+    bitfield::bitfield_set_piece,
+    picker::{FilePriority, PickOrder, PiecePicker, BLOCK_SIZE},
+    torrent_file::TorrentFile,
+};
+
+/// Torrent of files of `lengths` bytes, the piece hashes are never checked
+fn torrent(lengths: &[usize], piece_length: usize) -> TorrentFile {
+    let announce = "http://127.0.0.1/announce";
+    let mut bytes = format!("d8:announce{}:{}4:infod5:filesl", announce.len(), announce);
+    for (index, length) in lengths.iter().enumerate() {
+        let name = format!("{}.bin", index);
+        bytes += &format!("d6:lengthi{}e4:pathl{}:{}ee", length, name.len(), name);
+    }
+    let total: usize = lengths.iter().sum();
+    let pieces = total.div_ceil(piece_length) * 20;
+    bytes += &format!(
+        "e4:name4:test12:piece lengthi{}e6:pieces{}:",
+        piece_length, pieces
+    );
+    let mut bytes = bytes.into_bytes();
+    bytes.extend(vec![0u8; pieces]);
+    bytes.extend(b"ee");
+    TorrentFile::from_bytes(&bytes).unwrap()
+}
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn has_all(num_pieces: usize) -> Vec<u8> {
+    vec![0xff; num_pieces.div_ceil(8)]
+}
+
+fn has(num_pieces: usize, pieces: &[usize]) -> Vec<u8> {
+    let mut bitfield = vec![0; num_pieces.div_ceil(8)];
+    for index in pieces {
+        bitfield_set_piece(&mut bitfield, *index);
+    }
+    bitfield
+}
+
+/// Pick every block of the next piece and verify it, returns its index
+fn download_next(picker: &mut PiecePicker, bitfield: &[u8]) -> Option<usize> {
+    let first = picker.pick_block(peer(1), bitfield)?;
+    let mut request = first;
+    loop {
+        assert_eq!(request.index, first.index);
+        let data = vec![0; request.length];
+        if picker
+            .block_received(request.index, request.begin, &data)
+            .is_some()
+        {
+            break;
+        }
+        request = picker.pick_block(peer(1), bitfield).unwrap();
+    }
+    picker.piece_verified(first.index);
+    Some(first.index)
+}
+
+#[test]
+fn higher_priority_files_come_first() {
+    // Three pieces per file
+    let torrent_file = torrent(
+        &[3 * BLOCK_SIZE, 3 * BLOCK_SIZE, 3 * BLOCK_SIZE],
+        BLOCK_SIZE,
+    );
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::Low, FilePriority::Skip, FilePriority::High],
+    );
+    let bitfield = has_all(9);
+    let order: Vec<usize> = std::iter::from_fn(|| download_next(&mut picker, &bitfield)).collect();
+    assert_eq!(order, [6, 7, 8, 0, 1, 2]);
+    assert!(picker.is_complete());
+    assert_eq!(picker.num_verified(), 6);
+}
+
+#[test]
+fn pieces_in_progress_are_finished_first() {
+    let torrent_file = torrent(&[4 * 2 * BLOCK_SIZE], 2 * BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    let bitfield = has_all(4);
+    let first = picker.pick_block(peer(1), &bitfield).unwrap();
+    let second = picker.pick_block(peer(2), &bitfield).unwrap();
+    assert_eq!((first.index, second.index), (0, 0));
+    assert_eq!(second.begin, BLOCK_SIZE);
+
+    // Blocks of a peer that left are requested again
+    picker.release_peer(peer(1));
+    assert_eq!(picker.pick_block(peer(3), &bitfield), Some(first));
+    // Only pieces the peer has are picked
+    let request = picker.pick_block(peer(4), &has(4, &[3])).unwrap();
+    assert_eq!(request.index, 3);
+}
+
+#[test]
+fn deadlines_go_before_priorities() {
+    let torrent_file = torrent(&[2 * BLOCK_SIZE, 2 * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::High, FilePriority::Skip],
+    );
+    let now = Instant::now();
+    picker.set_piece_deadline(3, now + Duration::from_secs(2));
+    picker.set_piece_deadline(2, now + Duration::from_secs(1));
+    let bitfield = has_all(4);
+    let order: Vec<usize> = std::iter::from_fn(|| download_next(&mut picker, &bitfield)).collect();
+    assert_eq!(order, [2, 3, 0, 1]);
+    assert!(picker.is_complete());
+}
+
+#[test]
+fn skipped_pieces_with_a_deadline_are_waited_for() {
+    let torrent_file = torrent(&[BLOCK_SIZE, BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::Normal, FilePriority::Skip],
+    );
+    picker.piece_verified(0);
+    assert!(picker.is_complete());
+    picker.set_piece_deadline(1, Instant::now());
+    assert!(!picker.is_complete());
+    assert_eq!(download_next(&mut picker, &has_all(2)), Some(1));
+    assert!(picker.is_complete());
+}
+
+#[test]
+fn only_wanted_pieces_are_left() {
+    let torrent_file = torrent(&[2 * BLOCK_SIZE, BLOCK_SIZE + 100], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    assert_eq!(picker.wanted_bytes_left(), 3 * BLOCK_SIZE + 100);
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::Skip, FilePriority::Normal],
+    );
+    picker.piece_verified(2);
+    assert_eq!(picker.wanted_bytes_left(), 100);
+    picker.set_piece_deadline(0, Instant::now());
+    assert_eq!(picker.wanted_bytes_left(), BLOCK_SIZE + 100);
+}
+
+#[test]
+fn first_and_last_pieces_of_files_come_first() {
+    let torrent_file = torrent(&[4 * BLOCK_SIZE, 4 * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_order(PickOrder {
+        sequential: None,
+        first_last_pieces: true,
+    });
+    let bitfield = has_all(8);
+    let order: Vec<usize> = std::iter::from_fn(|| download_next(&mut picker, &bitfield)).collect();
+    assert_eq!(order, [0, 3, 4, 7, 1, 2, 5, 6]);
+}
+
+#[test]
+fn web_seed_pieces_return_to_the_pool_when_they_fail() {
+    let torrent_file = torrent(&[3 * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    assert_eq!(picker.pick_piece(), Some(0));
+    assert_eq!(picker.pick_piece(), Some(1));
+    picker.piece_failed(0);
+    assert_eq!(picker.pick_piece(), Some(0));
+    assert_eq!(picker.pick_piece(), Some(2));
+    assert_eq!(picker.pick_piece(), None);
+
+    picker.reset_requests();
+    let order: Vec<usize> =
+        std::iter::from_fn(|| download_next(&mut picker, &has_all(3))).collect();
+    assert_eq!(order, [0, 1, 2]);
+}
+
+#[test]
+fn picking_does_not_scan_every_piece() {
+    let num_pieces = 200_000;
+    let torrent_file = torrent(&[num_pieces * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    let bitfield = has_all(num_pieces);
+    let start = Instant::now();
+    for index in 0..num_pieces {
+        assert_eq!(download_next(&mut picker, &bitfield), Some(index));
+        assert!(!picker.is_complete() || index == num_pieces - 1);
+    }
+    assert!(picker.is_complete());
+    // Scanning every piece on each pick takes minutes
+    assert!(start.elapsed() < Duration::from_secs(20));
+}
+
+fn sequential(lookahead: usize) -> PickOrder {
+    PickOrder {
+        sequential: Some(lookahead),
+        first_last_pieces: false,
+    }
+}
+
+#[test]
+fn sequential_window_follows_the_first_missing_piece() {
+    let torrent_file = torrent(&[4 * BLOCK_SIZE, 4 * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::Normal, FilePriority::High],
+    );
+    picker.set_order(sequential(2));
+    // Pieces past the window go by priority
+    let request = picker.pick_block(peer(2), &has(8, &[2, 3, 6])).unwrap();
+    assert_eq!(request.index, 6);
+
+    let order: Vec<usize> =
+        std::iter::from_fn(|| download_next(&mut picker, &has_all(8))).collect();
+    assert_eq!(order, [0, 1, 2, 3, 4, 5, 7]);
+}
+
+#[test]
+fn sequential_window_skips_unwanted_pieces() {
+    let torrent_file = torrent(&[4 * BLOCK_SIZE, 4 * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_order(sequential(1));
+    picker.set_file_priorities(
+        &torrent_file.files,
+        &[FilePriority::Skip, FilePriority::Low],
+    );
+    let bitfield = has_all(8);
+    assert_eq!(download_next(&mut picker, &bitfield), Some(4));
+    // A deadline before the window moves it back
+    picker.set_piece_deadline(1, Instant::now());
+    assert_eq!(download_next(&mut picker, &bitfield), Some(1));
+    let order: Vec<usize> = std::iter::from_fn(|| download_next(&mut picker, &bitfield)).collect();
+    assert_eq!(order, [5, 6, 7]);
+
+    // Priorities changed midway count from the start again
+    picker.set_file_priorities(&torrent_file.files, &[FilePriority::Low, FilePriority::Low]);
+    let order: Vec<usize> = std::iter::from_fn(|| download_next(&mut picker, &bitfield)).collect();
+    assert_eq!(order, [0, 2, 3]);
+}
+
+#[test]
+fn sequential_picking_does_not_scan_every_piece() {
+    let num_pieces = 200_000;
+    let torrent_file = torrent(&[num_pieces * BLOCK_SIZE], BLOCK_SIZE);
+    let mut picker = PiecePicker::new(&torrent_file);
+    picker.set_order(sequential(16));
+    let bitfield = has_all(num_pieces);
+    let start = Instant::now();
+    for index in 0..num_pieces {
+        assert_eq!(download_next(&mut picker, &bitfield), Some(index));
+    }
+    assert!(picker.is_complete());
+    assert!(start.elapsed() < Duration::from_secs(20));
+}
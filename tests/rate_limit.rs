@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    time,
+};
+use torrent_client::rate_limit::{Bandwidth, RateLimited, RateLimits};
+
+const INFOHASH: [u8; 20] = [5; 20];
+
+fn limited(download: u64) -> RateLimits {
+    RateLimits {
+        download: Some(download),
+        upload: None,
+    }
+}
+
+#[tokio::test]
+async fn reads_wait_for_the_peer_limits() {
+    let bandwidth = Bandwidth::new(RateLimits::default(), limited(32 * 1024), None);
+    let peer = bandwidth.peer(INFOHASH);
+    let (mut sender, receiver) = duplex(16 * 1024);
+    let data: Vec<u8> = (0..96 * 1024).map(|i| i as u8).collect();
+    let send = async {
+        sender.write_all(&data).await.unwrap();
+        drop(sender);
+    };
+
+    let start = Instant::now();
+    let mut stream = RateLimited::new(receiver, &peer);
+    let mut received = Vec::new();
+    let (_, read) = tokio::join!(send, stream.read_to_end(&mut received));
+    read.unwrap();
+    assert!(received == data);
+    // Every byte is charged, the last ones are waited for before the end of
+    // the stream is seen
+    assert!(start.elapsed() >= Duration::from_millis(2500));
+}
+
+#[tokio::test]
+async fn connections_share_the_torrent_limits() {
+    let bandwidth = Bandwidth::new(RateLimits::default(), RateLimits::default(), None);
+    bandwidth.set_torrent_limits(INFOHASH, limited(64 * 1024));
+    let (first, second) = (bandwidth.peer(INFOHASH), bandwidth.peer(INFOHASH));
+    // Other torrents are not slowed down
+    let other = bandwidth.peer([6; 20]);
+
+    let read = |peer| async move {
+        let (mut sender, receiver) = duplex(16 * 1024);
+        let mut stream = RateLimited::new(receiver, peer);
+        let send = async {
+            sender.write_all(&[0; 64 * 1024]).await.unwrap();
+            drop(sender);
+        };
+        let mut received = Vec::new();
+        let (_, read) = tokio::join!(send, stream.read_to_end(&mut received));
+        read.unwrap();
+        received.len()
+    };
+    let start = Instant::now();
+    assert_eq!(read(&other).await, 64 * 1024);
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    let start = Instant::now();
+    let (a, b) = tokio::join!(read(&first), read(&second));
+    assert_eq!(a + b, 128 * 1024);
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn throttling_pays_the_debt_of_past_reads() {
+    let bandwidth = Bandwidth::new(limited(16 * 1024), RateLimits::default(), None);
+    let peer = bandwidth.peer(INFOHASH);
+    let (mut sender, receiver) = duplex(64 * 1024);
+    sender.write_all(&[1; 32 * 1024]).await.unwrap();
+
+    let mut stream = RateLimited::new(receiver, &peer);
+    let mut buf = vec![0; 32 * 1024];
+    let start = Instant::now();
+    stream.read_exact(&mut buf).await.unwrap();
+    // The data was already there, the wait comes after
+    assert!(start.elapsed() < Duration::from_millis(500));
+    stream.throttle().await;
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn waits_for_bandwidth_are_left_out_of_read_timeouts() {
+    let bandwidth = Bandwidth::new(RateLimits::default(), limited(16 * 1024), None);
+    let peer = bandwidth.peer(INFOHASH);
+    let (mut sender, receiver) = duplex(64 * 1024);
+    // The last byte comes once the first ones fit in the limits
+    let send = async {
+        sender.write_all(&[1; 32 * 1024]).await.unwrap();
+        time::sleep(Duration::from_millis(2100)).await;
+        sender.write_all(&[1]).await.unwrap();
+    };
+
+    let mut stream = RateLimited::new(receiver, &peer);
+    stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let mut buf = vec![0; 32 * 1024 + 1];
+    let start = Instant::now();
+    let (_, read) = tokio::join!(send, stream.read_exact(&mut buf));
+    read.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(2000));
+
+    // A peer sending nothing still times out
+    stream.throttle().await;
+    stream.set_read_timeout(Some(Duration::from_millis(500)));
+    let start = Instant::now();
+    let error = stream.read_exact(&mut buf[..1]).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_millis(1000));
+}
@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use torrent_client::{
+    create::{create_torrent, CreateOptions},
+    infohash::hex_encode,
+    picker::{FilePriority, PiecePicker},
+    resume::ResumeData,
+    storage::Storage,
+    torrent_file::TorrentFile,
+};
+
+const PIECE_LENGTH: usize = 16384;
+
+/// Two files of a piece and a half, the middle piece spans both
+struct Download {
+    dir: PathBuf,
+    torrent_file: TorrentFile,
+    pieces: Vec<Vec<u8>>,
+}
+
+fn setup(test: &str) -> Download {
+    let root = std::env::temp_dir().join(format!("storage-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let content = root.join("content");
+    fs::create_dir_all(&content).unwrap();
+    let data: Vec<u8> = (0..3 * PIECE_LENGTH)
+        .map(|i| (i * 7 + i / 251) as u8)
+        .collect();
+    let half = 3 * PIECE_LENGTH / 2;
+    fs::write(content.join("a.bin"), &data[..half]).unwrap();
+    fs::write(content.join("b.bin"), &data[half..]).unwrap();
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        ..Default::default()
+    };
+    let torrent_file =
+        TorrentFile::from_bytes(&create_torrent(&content, &options).unwrap()).unwrap();
+    Download {
+        dir: root.join("download"),
+        torrent_file,
+        pieces: data.chunks(PIECE_LENGTH).map(<[u8]>::to_vec).collect(),
+    }
+}
+
+impl Download {
+    fn part_path(&self) -> PathBuf {
+        self.dir.join(format!(
+            ".{}.parts",
+            hex_encode(&self.torrent_file.infohash)
+        ))
+    }
+
+    /// Storage skipping the second file, with every piece written
+    fn write_all(&self) -> Storage {
+        let storage = Storage::new(&self.dir, &self.torrent_file);
+        storage
+            .set_file_priorities(
+                &self.torrent_file,
+                &[FilePriority::Normal, FilePriority::Skip],
+            )
+            .unwrap();
+        for (index, piece) in self.pieces.iter().enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        storage
+    }
+}
+
+#[test]
+fn corrupt_part_file_pieces_are_not_restored() {
+    let download = setup("corrupt");
+    let storage = download.write_all();
+    let mut picker = PiecePicker::new(&download.torrent_file);
+    for index in 0..3 {
+        picker.piece_verified(index);
+    }
+    let resume_data = ResumeData::new(&picker);
+    drop(storage);
+
+    // Flip a byte of the first slot, the piece spanning both files
+    let mut part = fs::read(download.part_path()).unwrap();
+    assert_eq!(part.len(), 2 * (4 + PIECE_LENGTH));
+    part[4 + 100] ^= 0xff;
+    fs::write(download.part_path(), &part).unwrap();
+
+    let storage = Storage::new(&download.dir, &download.torrent_file);
+    let mut picker = PiecePicker::new(&download.torrent_file);
+    resume_data
+        .restore(&mut picker, &storage, &download.torrent_file)
+        .unwrap();
+    assert_eq!(picker.num_verified(), 2);
+    assert!(!storage.has_piece(1, PIECE_LENGTH));
+    assert!(storage.has_piece(2, PIECE_LENGTH));
+
+    // Downloaded again, the files get the right data
+    storage
+        .set_file_priorities(
+            &download.torrent_file,
+            &[FilePriority::Normal, FilePriority::Normal],
+        )
+        .unwrap();
+    for index in 1..3 {
+        storage.write_piece(index, &download.pieces[index]).unwrap();
+    }
+    for (index, piece) in download.pieces.iter().enumerate() {
+        assert!(storage.read_piece(index, PIECE_LENGTH).unwrap() == *piece);
+    }
+}
+
+#[test]
+fn part_files_left_by_crashes_are_read() {
+    let download = setup("crash");
+    let storage = Storage::new(&download.dir, &download.torrent_file);
+    storage
+        .set_file_priorities(
+            &download.torrent_file,
+            &[FilePriority::Normal, FilePriority::Skip],
+        )
+        .unwrap();
+    storage.write_piece(2, &download.pieces[2]).unwrap();
+    drop(storage);
+
+    // A slot of a piece the torrent does not have and a slot cut short
+    let slot_length = 4 + PIECE_LENGTH;
+    let mut part = fs::read(download.part_path()).unwrap();
+    part.extend(u32::MAX.to_be_bytes());
+    part.extend(vec![1; PIECE_LENGTH]);
+    part.extend(1u32.to_be_bytes());
+    part.extend(vec![2; PIECE_LENGTH / 2]);
+    fs::write(download.part_path(), &part).unwrap();
+
+    let storage = Storage::new(&download.dir, &download.torrent_file);
+    storage.verify_part_file(&download.torrent_file).unwrap();
+    storage
+        .set_file_priorities(
+            &download.torrent_file,
+            &[FilePriority::Normal, FilePriority::Skip],
+        )
+        .unwrap();
+    assert!(storage.has_piece(2, PIECE_LENGTH));
+    assert!(!storage.has_piece(1, PIECE_LENGTH));
+    // The stray slot is free for new pieces
+    storage.write_piece(1, &download.pieces[1]).unwrap();
+    let length = fs::metadata(download.part_path()).unwrap().len() as usize;
+    assert_eq!(length, 2 * slot_length + 4 + PIECE_LENGTH / 2);
+    drop(storage);
+
+    let storage = Storage::new(&download.dir, &download.torrent_file);
+    storage.verify_part_file(&download.torrent_file).unwrap();
+    for index in 1..3 {
+        assert!(storage.read_piece(index, PIECE_LENGTH).unwrap() == download.pieces[index]);
+    }
+}
+
+#[test]
+fn dropped_pieces_stay_dropped_and_free_their_slot() {
+    let download = setup("cycle");
+    let slot_length = 4 + PIECE_LENGTH;
+    drop(download.write_all());
+    for _ in 0..3 {
+        let storage = Storage::new(&download.dir, &download.torrent_file);
+        let all = [FilePriority::Normal, FilePriority::Normal];
+        storage
+            .set_file_priorities(&download.torrent_file, &all)
+            .unwrap();
+        drop(storage);
+
+        // The files are read from now on, even after a restart
+        let b = download.dir.join(&download.torrent_file.files[1].path);
+        let mut changed = fs::read(&b).unwrap();
+        changed[PIECE_LENGTH / 2] ^= 0xff;
+        fs::write(&b, &changed).unwrap();
+        let storage = Storage::new(&download.dir, &download.torrent_file);
+        let piece = storage.read_piece(2, PIECE_LENGTH).unwrap();
+        assert_eq!(piece[0], changed[PIECE_LENGTH / 2]);
+        changed[PIECE_LENGTH / 2] ^= 0xff;
+        fs::write(&b, &changed).unwrap();
+
+        // Skipped again, the pieces go back to the same slots
+        storage
+            .set_file_priorities(
+                &download.torrent_file,
+                &[FilePriority::Normal, FilePriority::Skip],
+            )
+            .unwrap();
+        for index in 1..3 {
+            storage.write_piece(index, &download.pieces[index]).unwrap();
+        }
+        let length = fs::metadata(download.part_path()).unwrap().len() as usize;
+        assert_eq!(length, 2 * slot_length);
+    }
+}
+
+#[test]
+fn last_pieces_of_v2_files_keep_their_length() {
+    let bytes =
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v2.torrent")).unwrap();
+    let torrent_file = TorrentFile::from_bytes(&bytes).unwrap();
+    let dir = std::env::temp_dir().join(format!("storage-v2-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    // Same content as the fixture, see tests/v2.rs
+    let data: Vec<u8> = (0..100_000)
+        .map(|i| ((i * 31 + i / 251 + 1) % 256) as u8)
+        .collect();
+    let skip_first = [FilePriority::Skip, FilePriority::Normal];
+    let storage = Storage::new(&dir, &torrent_file);
+    storage
+        .set_file_priorities(&torrent_file, &skip_first)
+        .unwrap();
+    let v2_piece_length = torrent_file.piece_length;
+    for index in 0..4 {
+        let (start, end) = torrent_file.calculate_bound_for_piece(index);
+        assert_eq!(end - start, (100_000 - start).min(v2_piece_length));
+        storage.write_piece(index, &data[start..end]).unwrap();
+    }
+    drop(storage);
+
+    let storage = Storage::new(&dir, &torrent_file);
+    storage.verify_part_file(&torrent_file).unwrap();
+    storage
+        .set_file_priorities(&torrent_file, &skip_first)
+        .unwrap();
+    for index in 0..4 {
+        let length = torrent_file.calculate_piece_size(index);
+        assert!(storage.has_piece(index, length));
+        let (start, end) = torrent_file.calculate_bound_for_piece(index);
+        assert!(storage.read_piece(index, length).unwrap() == data[start..end]);
+    }
+}
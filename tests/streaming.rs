@@ -0,0 +1,68 @@
+use std::{fs, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use torrent_client::{
+    create::{create_torrent, CreateOptions},
+    infohash::hex_encode,
+    session::{Session, SessionConfig},
+    streaming::StreamingServer,
+    torrent_file::TorrentFile,
+};
+
+const FILE_LENGTH: usize = 40000;
+
+/// Response head to a HEAD request of the file with a `Range` header
+async fn head(server: &StreamingServer, infohash: &[u8; 20], range: &str) -> String {
+    let path = format!("/{}/0", hex_encode(infohash));
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let request = format!("HEAD {} HTTP/1.1\r\nRange: {}\r\n\r\n", path, range);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn ranges_past_the_end_stop_at_the_last_byte() {
+    let root = std::env::temp_dir().join(format!("streaming-ranges-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let path = root.join("video.mp4");
+    fs::write(&path, vec![3u8; FILE_LENGTH]).unwrap();
+    let torrent_file =
+        TorrentFile::from_bytes(&create_torrent(&path, &CreateOptions::default()).unwrap())
+            .unwrap();
+    let infohash = torrent_file.infohash;
+
+    let session = Session::new(SessionConfig {
+        download_dir: root.join("download"),
+        local_discovery: false,
+        accept_incoming: false,
+        ..Default::default()
+    });
+    let server = session
+        .start_streaming("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let handle = session.add_torrent(torrent_file);
+    while handle.torrent_file().is_none() {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let response = head(&server, &infohash, "bytes=0-18446744073709551615").await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    assert!(response.contains("Content-Range: bytes 0-39999/40000\r\n"));
+    assert!(response.contains("Content-Length: 40000\r\n"));
+
+    let response = head(&server, &infohash, "bytes=39990-50000").await;
+    assert!(response.contains("Content-Range: bytes 39990-39999/40000\r\n"));
+    assert!(response.contains("Content-Length: 10\r\n"));
+
+    let response = head(&server, &infohash, "bytes=40000-18446744073709551615").await;
+    assert!(response.starts_with("HTTP/1.1 416"), "{}", response);
+    session.shutdown().await;
+}
@@ -0,0 +1,37 @@
+use torrent_client::{error::TorrentError, torrent_file::TorrentFile};
+
+/// Single file torrent with `num_hashes` piece hashes
+fn torrent(length: usize, piece_length: usize, num_hashes: usize) -> Vec<u8> {
+    let mut bytes = format!(
+        "d8:announce25:http://127.0.0.1/announce4:infod6:lengthi{}e4:name8:test.bin12:piece lengthi{}e6:pieces{}:",
+        length,
+        piece_length,
+        num_hashes * 20
+    )
+    .into_bytes();
+    bytes.extend(vec![0u8; num_hashes * 20]);
+    bytes.extend(b"ee");
+    bytes
+}
+
+fn is_metainfo_error(bytes: &[u8]) -> bool {
+    matches!(
+        TorrentFile::from_bytes(bytes),
+        Err(TorrentError::Metainfo(_))
+    )
+}
+
+#[test]
+fn zero_piece_length_is_rejected() {
+    assert!(is_metainfo_error(&torrent(100, 0, 1)));
+    assert!(is_metainfo_error(&torrent(0, 0, 0)));
+}
+
+#[test]
+fn hash_count_must_match_the_length() {
+    assert!(TorrentFile::from_bytes(&torrent(100, 64, 2)).is_ok());
+    assert!(TorrentFile::from_bytes(&torrent(0, 64, 0)).is_ok());
+    assert!(is_metainfo_error(&torrent(100, 64, 3)));
+    assert!(is_metainfo_error(&torrent(100, 64, 1)));
+    assert!(is_metainfo_error(&torrent(0, 64, 1)));
+}
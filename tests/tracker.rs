@@ -0,0 +1,136 @@
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use torrent_client::{
+    identity::{Identity, DEFAULT_CLIENT_PREFIX},
+    torrent_file::TorrentFile,
+    tracker::{AnnounceStats, TrackerTiers},
+};
+
+/// Tracker answering every announce with the peer 10.0.0.1:6881, or with
+/// an HTTP error when `fail` is set. Counts the announces it gets.
+async fn start_tracker(fail: bool) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let announces = Arc::new(AtomicUsize::new(0));
+    let counter = announces.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).await;
+            let response: Vec<u8> = if fail {
+                b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_vec()
+            } else {
+                let body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(body);
+                response
+            };
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    (format!("http://{}/announce", addr), announces)
+}
+
+/// Nothing listens there, connecting fails right away
+async fn dead_tracker() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    format!("http://{}/announce", addr)
+}
+
+fn torrent_file() -> TorrentFile {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/private.torrent");
+    TorrentFile::from_bytes(&std::fs::read(path).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn falls_back_to_later_tiers_and_reports_the_tracker_that_replied() {
+    let dead = dead_tracker().await;
+    let (failing, failing_announces) = start_tracker(true).await;
+    let (working, working_announces) = start_tracker(false).await;
+    let torrent_file = torrent_file();
+    let identity = Identity::new(DEFAULT_CLIENT_PREFIX);
+
+    let mut trackers = TrackerTiers::new(&[vec![dead, failing], vec![working.clone()]]);
+    let announced = trackers
+        .fetch_peers(
+            &torrent_file.infohash,
+            &identity,
+            AnnounceStats::default(),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(announced.tracker, working);
+    assert_eq!(announced.peers.len(), 1);
+    assert_eq!(
+        announced.peers[0].addr(),
+        "10.0.0.1:6881".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(failing_announces.load(Ordering::SeqCst), 1);
+    assert_eq!(working_announces.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn replying_trackers_move_to_the_front_of_their_tier() {
+    let (failing, failing_announces) = start_tracker(true).await;
+    let (working, working_announces) = start_tracker(false).await;
+    let torrent_file = torrent_file();
+    let identity = Identity::new(DEFAULT_CLIENT_PREFIX);
+
+    let mut trackers = TrackerTiers::new(&[vec![failing, working.clone()]]);
+    for _ in 0..3 {
+        let announced = trackers
+            .fetch_peers(
+                &torrent_file.infohash,
+                &identity,
+                AnnounceStats::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(announced.tracker, working);
+    }
+    // The failing tracker is tried at most once, before the working one
+    // replied for the first time
+    assert!(failing_announces.load(Ordering::SeqCst) <= 1);
+    assert_eq!(working_announces.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn fails_when_every_tracker_fails() {
+    let (failing, _) = start_tracker(true).await;
+    let torrent_file = torrent_file();
+    let identity = Identity::new(DEFAULT_CLIENT_PREFIX);
+
+    let mut trackers = TrackerTiers::new(&[vec![dead_tracker().await], vec![failing]]);
+    assert!(trackers
+        .fetch_peers(
+            &torrent_file.infohash,
+            &identity,
+            AnnounceStats::default(),
+            None
+        )
+        .await
+        .is_err());
+    assert!(TrackerTiers::new(&[]).is_empty());
+}
@@ -0,0 +1,270 @@
+use std::{
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::mpsc,
+    time,
+};
+use torrent_client::{
+    identity::Identity,
+    listener::PeerListener,
+    mse::EncryptionPolicy,
+    peer::{handshake, PeerStream, TransportPreference, Transports},
+    rate_limit::{Bandwidth, RateLimits},
+    swarm::{ConnectionLimits, ConnectionSlots},
+    tracker::Peer,
+    utp::{UtpSocket, UtpStream},
+};
+
+const TRANSFER_LEN: usize = 4 * 1024 * 1024;
+/// First byte of uTP packets, type and version
+const ST_DATA: u8 = 0x01;
+const ST_FIN: u8 = 0x11;
+
+fn loopback() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+fn content(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + i / 4096) as u8).collect()
+}
+
+/// Send `content` from a connection to `connect_to`, which ends up at
+/// `server`, and read it back there until the FIN
+async fn transfer(server: &UtpSocket, connect_to: SocketAddr) -> Vec<u8> {
+    let client = UtpSocket::bind(loopback()).await.unwrap();
+    let (connected, accepted) = tokio::join!(client.connect(connect_to), server.accept());
+    let (mut connected, mut accepted) = (connected.unwrap(), accepted.unwrap());
+
+    let data = content(TRANSFER_LEN);
+    let send = async {
+        connected.write_all(&data).await.unwrap();
+        connected.shutdown().await.unwrap();
+    };
+    let mut received = Vec::new();
+    let receive = accepted.read_to_end(&mut received);
+    let (_, read) = time::timeout(Duration::from_secs(60), async {
+        tokio::join!(send, receive)
+    })
+    .await
+    .expect("transfer timed out");
+    read.unwrap();
+    received
+}
+
+#[tokio::test]
+async fn streams_carry_megabytes_until_fin() {
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let received = transfer(&server, server.local_addr().unwrap()).await;
+    assert_eq!(received.len(), TRANSFER_LEN);
+    assert!(received == content(TRANSFER_LEN));
+}
+
+/// Relays datagrams between one client and `server`, dropping the ones from
+/// the client that `drop` selects by their first byte and their count of
+/// that kind
+async fn start_lossy_proxy(
+    server: SocketAddr,
+    drop: fn(u8, usize) -> bool,
+) -> (SocketAddr, mpsc::UnboundedReceiver<u8>) {
+    let client_side = Arc::new(UdpSocket::bind(loopback()).await.unwrap());
+    let server_side = Arc::new(UdpSocket::bind(loopback()).await.unwrap());
+    let addr = client_side.local_addr().unwrap();
+    let (dropped_sender, dropped) = mpsc::unbounded_channel();
+    let (client_sender, mut client_addr) = mpsc::unbounded_channel::<SocketAddr>();
+
+    let (from_client, to_server) = (client_side.clone(), server_side.clone());
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        let mut counts = [0usize; 256];
+        let mut client = None;
+        while let Ok((length, from)) = from_client.recv_from(&mut buf).await {
+            if client.is_none() {
+                client = Some(from);
+                let _ = client_sender.send(from);
+            }
+            let kind = buf[0];
+            counts[kind as usize] += 1;
+            if drop(kind, counts[kind as usize]) {
+                let _ = dropped_sender.send(kind);
+                continue;
+            }
+            let _ = to_server.send_to(&buf[..length], server).await;
+        }
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        let Some(client) = client_addr.recv().await else {
+            return;
+        };
+        while let Ok(length) = server_side.recv(&mut buf).await {
+            let _ = client_side.send_to(&buf[..length], client).await;
+        }
+    });
+    (addr, dropped)
+}
+
+#[tokio::test]
+async fn lost_packets_are_sent_again() {
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let (proxy, mut dropped) = start_lossy_proxy(server.local_addr().unwrap(), |kind, count| {
+        (kind == ST_DATA && (count == 10 || count == 11 || count == 2000))
+            || (kind == ST_FIN && count == 1)
+    })
+    .await;
+    let received = transfer(&server, proxy).await;
+    assert_eq!(received.len(), TRANSFER_LEN);
+    assert!(received == content(TRANSFER_LEN));
+
+    let mut kinds = Vec::new();
+    while let Ok(kind) = dropped.try_recv() {
+        kinds.push(kind);
+    }
+    assert_eq!(kinds, [ST_DATA, ST_DATA, ST_DATA, ST_FIN]);
+}
+
+#[tokio::test]
+async fn listener_accepts_utp_peers() {
+    let port = StdUdpSocket::bind(loopback())
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let slots = |preference, port| {
+        let mut transports = Transports::new(preference, EncryptionPolicy::Preferred);
+        transports.port = port;
+        ConnectionSlots::new(
+            ConnectionLimits::default(),
+            transports,
+            Identity::new("-TT0100-"),
+            Bandwidth::new(RateLimits::default(), RateLimits::default(), None),
+        )
+    };
+    let listener = PeerListener::new(slots(TransportPreference::PreferTcp, port));
+    let (sender, mut receiver) = mpsc::channel(1);
+    let infohash = [3; 20];
+    listener.add(infohash, sender);
+
+    let client = slots(TransportPreference::UtpOnly, 0);
+    let peer = Peer {
+        ip: "127.0.0.1".parse().unwrap(),
+        port,
+    };
+    // The socket is bound by a task of the listener
+    let (mut stream, _) = loop {
+        match handshake(
+            &peer,
+            &infohash,
+            &client.transports,
+            &client.identity.peer_id,
+        )
+        .await
+        {
+            Ok(connected) => break connected,
+            Err(_) => time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let mut incoming = receiver.recv().await.unwrap();
+    let PeerStream::Mse(mse) = &incoming.stream else {
+        panic!("incoming stream is not wrapped");
+    };
+    assert!(matches!(mse.get_ref(), PeerStream::Utp(_)));
+    assert_eq!(incoming.remote.peer_id, client.identity.peer_id);
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = [0u8; 4];
+    incoming.stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"ping");
+}
+
+#[tokio::test]
+async fn silent_transports_give_way_to_the_next() {
+    // SYNs are never answered, TCP connections are accepted on the same port
+    let tcp = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let port = tcp.local_addr().unwrap().port();
+    let _silent = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+    tokio::spawn(async move { while tcp.accept().await.is_ok() {} });
+
+    let mut transports =
+        Transports::new(TransportPreference::PreferUtp, EncryptionPolicy::Disabled);
+    transports.port = 0;
+    let peer = Peer {
+        ip: "127.0.0.1".parse().unwrap(),
+        port,
+    };
+    let start = time::Instant::now();
+    let stream = transports.connect(&peer).await.unwrap();
+    assert!(matches!(stream, PeerStream::Tcp(_)));
+    // uTP gives up on its own after about 7 s of SYN retransmissions
+    assert!(start.elapsed() < Duration::from_secs(7));
+}
+
+/// Raw uTP packet from a peer that sends without waiting for acks
+fn packet(kind: u8, connection_id: u16, seq_nr: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind, 0];
+    packet.extend(connection_id.to_be_bytes());
+    // Timestamps, window and ack number are ignored on the receiving side
+    packet.extend([0; 12]);
+    packet.extend(seq_nr.to_be_bytes());
+    packet.extend([0; 2]);
+    packet.extend(payload);
+    packet
+}
+
+/// Bytes readable from `stream` before it waits for more
+async fn read_available(stream: &mut UtpStream) -> usize {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    while let Ok(Ok(read)) = time::timeout(Duration::from_millis(500), stream.read(&mut buf)).await
+    {
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+    total
+}
+
+#[tokio::test]
+async fn data_past_the_window_is_dropped() {
+    const ST_SYN: u8 = 0x41;
+    const WINDOW: usize = 1024 * 1024;
+    let server = UtpSocket::bind(loopback()).await.unwrap();
+    let peer = UdpSocket::bind(loopback()).await.unwrap();
+    peer.connect(server.local_addr().unwrap()).await.unwrap();
+    let id = 1000;
+    let accept = server.accept();
+    peer.send(&packet(ST_SYN, id, 1, &[])).await.unwrap();
+    let mut stream = accept.await.unwrap();
+
+    // In order, twice what we advertise
+    let payload = vec![7u8; 1000];
+    for seq_nr in 2..2 + (2 * WINDOW / 1000) as u16 {
+        peer.send(&packet(ST_DATA, id + 1, seq_nr, &payload))
+            .await
+            .unwrap();
+    }
+    let read = read_available(&mut stream).await;
+    assert!(read > 0 && read <= WINDOW, "read {}", read);
+
+    // Out of order with the largest payloads, buffered up to the window
+    let next = 2 + (read / 1000) as u16;
+    let payload = vec![8u8; 60_000];
+    for seq_nr in next + 1..next + 60 {
+        peer.send(&packet(ST_DATA, id + 1, seq_nr, &payload))
+            .await
+            .unwrap();
+        // Socket buffers are smaller than the burst
+        time::sleep(Duration::from_millis(1)).await;
+    }
+    peer.send(&packet(ST_DATA, id + 1, next, &[9; 1000]))
+        .await
+        .unwrap();
+    let read = read_available(&mut stream).await;
+    assert!(read > WINDOW / 2 && read <= WINDOW, "read {}", read);
+}
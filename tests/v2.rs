@@ -0,0 +1,169 @@
+use std::path::Path;
+
+use tokio::net::{TcpListener, TcpStream};
+use torrent_client::{
+    bencode::dict_value_span,
+    merkle::{hashes_with_proof, merkle_root, padding_hash, proof_root, sha256},
+    message::Message,
+    metadata::fetch_piece_layers,
+    peer::PeerStream,
+    torrent_file::TorrentFile,
+    worker::{read_message, write_message},
+};
+
+const PIECE_LENGTH: usize = 32768;
+
+/// Content of the files of the v2 and hybrid fixtures
+fn content(length: usize, seed: usize) -> Vec<u8> {
+    (0..length)
+        .map(|i| ((i * 31 + i / 251 + seed) % 256) as u8)
+        .collect()
+}
+
+fn load(path: &str) -> (Vec<u8>, TorrentFile) {
+    let bytes = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap();
+    let torrent_file = TorrentFile::from_bytes(&bytes).unwrap();
+    let info_bytes = bytes[dict_value_span(&bytes, b"info").unwrap()].to_vec();
+    (info_bytes, torrent_file)
+}
+
+fn pieces(torrent_file: &TorrentFile, data: &[u8]) -> Vec<Vec<u8>> {
+    (0..torrent_file.num_pieces())
+        .map(|index| {
+            let (start, end) = torrent_file.calculate_bound_for_piece(index);
+            data[start..end].to_vec()
+        })
+        .collect()
+}
+
+#[test]
+fn v2_pieces_are_checked_against_their_roots() {
+    let (_, torrent_file) = load("tests/fixtures/v2.torrent");
+    // Files start on piece boundaries, the gap is never part of a piece
+    let mut data = content(100_000, 1);
+    data.resize(4 * PIECE_LENGTH, 0);
+    data.extend(content(500, 2));
+    let pieces = pieces(&torrent_file, &data);
+    assert_eq!(pieces.len(), 5);
+    assert_eq!(pieces[3].len(), 100_000 - 3 * PIECE_LENGTH);
+    for (index, piece) in pieces.iter().enumerate() {
+        assert!(torrent_file.verify_piece(index, piece), "piece {}", index);
+    }
+    let mut corrupted = pieces[4].clone();
+    corrupted[0] ^= 1;
+    assert!(!torrent_file.verify_piece(4, &corrupted));
+}
+
+#[test]
+fn hybrid_pieces_must_match_both_hashes() {
+    let (_, torrent_file) = load("tests/fixtures/hybrid.torrent");
+    let mut data = content(45_000, 3);
+    data.resize(2 * PIECE_LENGTH, 0);
+    data.extend(content(20_000, 4));
+    let pieces = pieces(&torrent_file, &data);
+    assert_eq!(torrent_file.piece_roots.len(), pieces.len());
+    for (index, piece) in pieces.iter().enumerate() {
+        assert!(torrent_file.verify_piece(index, piece), "piece {}", index);
+    }
+
+    // The second piece ends with padding, only covered by its SHA-1
+    let mut wrong_root = torrent_file.clone();
+    wrong_root.piece_roots[1].root[0] ^= 1;
+    assert!(!wrong_root.verify_piece(1, &pieces[1]));
+    let mut wrong_sha1 = torrent_file.clone();
+    wrong_sha1.piece_hashes[1][0] ^= 1;
+    assert!(!wrong_sha1.verify_piece(1, &pieces[1]));
+}
+
+/// Peer answering hash requests from the piece layers of `torrent_file`,
+/// with the first hash of each reply flipped when `tamper` is set
+async fn start_peer(torrent_file: TorrentFile, tamper: bool) -> PeerStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = PeerStream::Tcp(stream);
+        let piece_width = torrent_file.piece_length / 16384;
+        while let Ok(message) = read_message(&mut stream).await {
+            let Message::HashRequest(request) = message else {
+                continue;
+            };
+            let layer = &torrent_file.piece_layers[&request.pieces_root];
+            assert_eq!(request.base_layer, piece_width.trailing_zeros());
+            let mut hashes = hashes_with_proof(
+                layer,
+                padding_hash(piece_width),
+                request.index as usize,
+                request.length as usize,
+                request.proof_layers as usize,
+            )
+            .unwrap();
+            if tamper {
+                hashes[0][0] ^= 1;
+            }
+            // Unrelated messages may come first
+            write_message(&mut stream, &Message::Unchoke).await.unwrap();
+            write_message(&mut stream, &Message::Hashes(request, hashes))
+                .await
+                .unwrap();
+        }
+    });
+    PeerStream::Tcp(TcpStream::connect(addr).await.unwrap())
+}
+
+#[tokio::test]
+async fn piece_layers_of_magnets_come_from_peers() {
+    let (info_bytes, torrent_file) = load("tests/fixtures/v2.torrent");
+    let announce = "http://tracker.example.org/announce".to_string();
+    assert!(TorrentFile::from_metadata(&info_bytes, announce.clone(), None).is_err());
+
+    let mut stream = start_peer(torrent_file.clone(), false).await;
+    let piece_layers = fetch_piece_layers(&mut stream, &info_bytes).await.unwrap();
+    let resolved = TorrentFile::from_metadata(&info_bytes, announce, Some(piece_layers)).unwrap();
+    assert_eq!(resolved.piece_layers, torrent_file.piece_layers);
+    let roots = |torrent_file: &TorrentFile| -> Vec<[u8; 32]> {
+        torrent_file
+            .piece_roots
+            .iter()
+            .map(|piece| piece.root)
+            .collect()
+    };
+    assert_eq!(roots(&resolved), roots(&torrent_file));
+}
+
+#[tokio::test]
+async fn hashes_not_matching_the_pieces_root_are_rejected() {
+    let (info_bytes, torrent_file) = load("tests/fixtures/v2.torrent");
+    let mut stream = start_peer(torrent_file, true).await;
+    assert!(fetch_piece_layers(&mut stream, &info_bytes).await.is_err());
+}
+
+#[test]
+fn hybrid_torrents_without_piece_layers_fall_back_to_sha1() {
+    let (info_bytes, _) = load("tests/fixtures/hybrid.torrent");
+    let announce = "http://tracker.example.org/announce".to_string();
+    let torrent_file = TorrentFile::from_metadata(&info_bytes, announce, None).unwrap();
+    assert!(torrent_file.piece_roots.is_empty());
+    let data = content(45_000, 3);
+    assert!(torrent_file.verify_piece(0, &data[..PIECE_LENGTH]));
+}
+
+#[test]
+fn proofs_lead_to_the_root() {
+    let layer: Vec<[u8; 32]> = (0..1000u32).map(|i| sha256(&i.to_be_bytes())).collect();
+    let padding = padding_hash(2);
+    let root = merkle_root(&layer, 1024, padding);
+    for index in (0..1024).step_by(256) {
+        let hashes = hashes_with_proof(&layer, padding, index, 256, 10).unwrap();
+        assert_eq!(hashes.len(), 256 + 2);
+        assert_eq!(proof_root(&hashes, index, 256), Some(root));
+
+        let mut tampered = hashes.clone();
+        let last = tampered.len() - 1;
+        tampered[last][0] ^= 1;
+        assert_ne!(proof_root(&tampered, index, 256), Some(root));
+        // Missing uncles don't reach the root
+        assert_ne!(proof_root(&hashes[..257], index, 256), Some(root));
+    }
+    assert_eq!(proof_root(&layer[..10], 0, 16), None);
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, Mutex},
+    time,
+};
+use torrent_client::{
+    create::{create_torrent, CreateOptions},
+    event::Event,
+    picker::PiecePicker,
+    rate_limit::{Bandwidth, RateLimits},
+    torrent_file::TorrentFile,
+    webseed::{start_web_seed_worker, WebSeed},
+};
+
+const PIECE_LENGTH: usize = 16384;
+
+/// Directory of files shared by a mirror, with a torrent of them
+struct Content {
+    root: PathBuf,
+    torrent_file: TorrentFile,
+}
+
+/// Write `files` under a fresh directory named `name` and create a torrent
+/// of it, or of its only file when `single_file` is set
+fn make_content(test: &str, name: &str, files: &[(&str, usize)], single_file: bool) -> Content {
+    let root = std::env::temp_dir().join(format!("webseed-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let mut seed = 7u32;
+    for (path, length) in files {
+        let path = root.join(name).join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let data: Vec<u8> = (0..*length)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        fs::write(path, data).unwrap();
+    }
+    let shared = if single_file {
+        root.join(name).join(files[0].0)
+    } else {
+        root.join(name)
+    };
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        ..Default::default()
+    };
+    let torrent_file =
+        TorrentFile::from_bytes(&create_torrent(&shared, &options).unwrap()).unwrap();
+    if single_file {
+        // Served from the root like the files of multi file torrents
+        fs::rename(&shared, root.join(files[0].0)).unwrap();
+    }
+    Content { root, torrent_file }
+}
+
+/// HTTP server answering range requests for the files under `root`, or
+/// failing every request. Records the paths it was asked for.
+async fn start_http_mirror(root: PathBuf, fail: bool) -> (String, Arc<StdMutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(StdMutex::new(Vec::new()));
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let root = root.clone();
+            let recorded = recorded.clone();
+            tokio::spawn(serve_http(stream, root, fail, recorded));
+        }
+    });
+    (format!("http://{}", addr), requests)
+}
+
+async fn serve_http(
+    stream: TcpStream,
+    root: PathBuf,
+    fail: bool,
+    recorded: Arc<StdMutex<Vec<String>>>,
+) {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await.unwrap();
+    let path = request_line.split(' ').nth(1).unwrap().to_string();
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+            let (from, to) = value.trim().split_once('-').unwrap();
+            range = Some((from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap()));
+        }
+    }
+    recorded.lock().unwrap().push(path.clone());
+    let file = root.join(path.trim_start_matches('/').replace("%20", " "));
+    let response = match (fail, fs::read(file)) {
+        (false, Ok(data)) => {
+            let (from, to) = range.unwrap_or((0, data.len() - 1));
+            let mut response = format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                to + 1 - from,
+                from,
+                to,
+                data.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&data[from..=to]);
+            response
+        }
+        (true, _) => {
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_vec()
+        }
+        (false, Err(_)) => {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        }
+    };
+    let _ = stream.get_mut().write_all(&response).await;
+}
+
+/// Anonymous FTP server in passive mode for the files under `root`.
+/// Records the `RETR` paths with their restart offsets.
+async fn start_ftp_mirror(root: PathBuf) -> (String, Arc<StdMutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(StdMutex::new(Vec::new()));
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve_ftp(stream, root.clone(), recorded.clone()));
+        }
+    });
+    (format!("ftp://{}", addr), requests)
+}
+
+async fn serve_ftp(stream: TcpStream, root: PathBuf, recorded: Arc<StdMutex<Vec<String>>>) {
+    let mut control = BufReader::new(stream);
+    let reply = |text: String| text + "\r\n";
+    control
+        .get_mut()
+        .write_all(reply("220-Welcome\r\n220 Ready".to_string()).as_bytes())
+        .await
+        .unwrap();
+    let mut data_listener = None;
+    let mut offset = 0;
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line).await.unwrap() == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let response = match command {
+            "USER" => "331 Password required".to_string(),
+            "PASS" => "230 Logged in".to_string(),
+            "TYPE" => "200 Binary".to_string(),
+            "EPSV" => {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let port = listener.local_addr().unwrap().port();
+                data_listener = Some(listener);
+                format!("229 Entering Extended Passive Mode (|||{}|)", port)
+            }
+            "REST" => {
+                offset = argument.parse().unwrap();
+                "350 Restarting".to_string()
+            }
+            "RETR" => {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}@{}", argument, offset));
+                let data = fs::read(root.join(argument.trim_start_matches('/'))).unwrap();
+                control
+                    .get_mut()
+                    .write_all(reply("150 Sending".to_string()).as_bytes())
+                    .await
+                    .unwrap();
+                let (mut data_stream, _) = data_listener.take().unwrap().accept().await.unwrap();
+                // The client closes the connection once it has its range
+                let _ = data_stream.write_all(&data[offset..]).await;
+                drop(data_stream);
+                offset = 0;
+                "226 Transfer complete".to_string()
+            }
+            _ => "502 Not implemented".to_string(),
+        };
+        control
+            .get_mut()
+            .write_all(reply(response).as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+fn unlimited() -> Bandwidth {
+    Bandwidth::new(RateLimits::default(), RateLimits::default(), None)
+}
+
+/// Run a worker for `web_seed` until every piece of `torrent_file` came in,
+/// returning the pieces
+async fn download(
+    web_seed: WebSeed,
+    torrent_file: &TorrentFile,
+    bandwidth: &Bandwidth,
+) -> Vec<Vec<u8>> {
+    let bandwidth = bandwidth.peer(torrent_file.infohash);
+    let picker = Arc::new(Mutex::new(PiecePicker::new(torrent_file)));
+    let (result_sender, mut result_receiver) = mpsc::channel(16);
+    let (events, _) = broadcast::channel(16);
+    let worker = {
+        let torrent_file = torrent_file.clone();
+        let picker = picker.clone();
+        tokio::spawn(async move {
+            start_web_seed_worker(
+                &web_seed,
+                &torrent_file,
+                &picker,
+                &bandwidth,
+                &result_sender,
+                &events,
+            )
+            .await
+        })
+    };
+    let mut pieces = vec![Vec::new(); torrent_file.num_pieces()];
+    for _ in 0..torrent_file.num_pieces() {
+        let piece = time::timeout(Duration::from_secs(10), result_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        picker.lock().await.piece_verified(piece.index);
+        pieces[piece.index] = piece.buf;
+    }
+    time::timeout(Duration::from_secs(10), worker)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    pieces
+}
+
+/// Data of the torrent as stored under `root`, padding files left out
+fn torrent_data(root: &Path, torrent_file: &TorrentFile, single_file: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    for file in &torrent_file.files {
+        let path = if single_file {
+            root.join(file.path.file_name().unwrap())
+        } else {
+            root.join(&file.path)
+        };
+        data.extend(fs::read(path).unwrap());
+    }
+    data
+}
+
+#[tokio::test]
+async fn downloads_a_single_file_torrent_over_http() {
+    let content = make_content("http-single", "dir", &[("movie.mkv", 100_000)], true);
+    let (base, requests) = start_http_mirror(content.root.clone(), false).await;
+    let url = format!("{}/movie.mkv", base);
+
+    let pieces = download(WebSeed::UrlList(url), &content.torrent_file, &unlimited()).await;
+    assert_eq!(
+        pieces.concat(),
+        torrent_data(&content.root, &content.torrent_file, true)
+    );
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), content.torrent_file.num_pieces());
+    assert!(requests.iter().all(|path| path == "/movie.mkv"));
+}
+
+#[tokio::test]
+async fn maps_pieces_to_the_files_of_a_multi_file_torrent() {
+    let files = [
+        ("a.bin", 20_000),
+        ("sub dir/b.bin", 30_000),
+        ("sub dir/deeper/c.bin", 5_000),
+    ];
+    let content = make_content("http-multi", "album", &files, false);
+    let (base, requests) = start_http_mirror(content.root.clone(), false).await;
+
+    // A trailing slash makes the URL the directory holding the torrent's
+    let pieces = download(
+        WebSeed::UrlList(format!("{}/", base)),
+        &content.torrent_file,
+        &unlimited(),
+    )
+    .await;
+    assert_eq!(
+        pieces.concat(),
+        torrent_data(&content.root, &content.torrent_file, false)
+    );
+    let paths: BTreeSet<String> = requests.lock().unwrap().iter().cloned().collect();
+    assert_eq!(
+        paths,
+        BTreeSet::from([
+            "/album/a.bin".to_string(),
+            "/album/sub%20dir/b.bin".to_string(),
+            "/album/sub%20dir/deeper/c.bin".to_string(),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn downloads_from_ftp_mirrors() {
+    let files = [("a.bin", 20_000), ("b.bin", 30_000)];
+    let content = make_content("ftp-multi", "album", &files, false);
+    let (base, requests) = start_ftp_mirror(content.root.clone()).await;
+
+    let pieces = download(
+        WebSeed::UrlList(format!("{}/", base)),
+        &content.torrent_file,
+        &unlimited(),
+    )
+    .await;
+    assert_eq!(
+        pieces.concat(),
+        torrent_data(&content.root, &content.torrent_file, false)
+    );
+    // The second piece starts inside a.bin and ends inside b.bin
+    let requests = requests.lock().unwrap();
+    assert!(requests.contains(&"/album/a.bin@16384".to_string()));
+    assert!(requests.contains(&"/album/b.bin@0".to_string()));
+    assert!(requests.contains(&"/album/b.bin@12768".to_string()));
+}
+
+#[tokio::test]
+async fn bodies_count_against_the_torrent_limits() {
+    let content = make_content("http-limited", "dir", &[("file.bin", 100_000)], true);
+    let (base, _) = start_http_mirror(content.root.clone(), false).await;
+    let bandwidth = unlimited();
+    bandwidth.set_torrent_limits(
+        content.torrent_file.infohash,
+        RateLimits {
+            download: Some(40_000),
+            upload: None,
+        },
+    );
+
+    let start = Instant::now();
+    let web_seed = WebSeed::UrlList(format!("{}/file.bin", base));
+    let pieces = download(web_seed, &content.torrent_file, &bandwidth).await;
+    assert_eq!(pieces.concat().len(), 100_000);
+    // The bucket starts empty, every byte waits for its share of the rate
+    assert!(start.elapsed() >= Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn backs_off_from_a_failing_mirror() {
+    let content = make_content("http-failing", "dir", &[("file.bin", 40_000)], true);
+    let (base, requests) = start_http_mirror(content.root.clone(), true).await;
+    let web_seed = WebSeed::UrlList(format!("{}/file.bin", base));
+    let torrent_file = content.torrent_file.clone();
+    let picker = Arc::new(Mutex::new(PiecePicker::new(&torrent_file)));
+    let (result_sender, _result_receiver) = mpsc::channel(16);
+    let (events, mut event_receiver) = broadcast::channel(16);
+    let worker = tokio::spawn(async move {
+        let bandwidth = unlimited().peer(torrent_file.infohash);
+        start_web_seed_worker(
+            &web_seed,
+            &torrent_file,
+            &picker,
+            &bandwidth,
+            &result_sender,
+            &events,
+        )
+        .await
+    });
+
+    let mut failures = Vec::new();
+    while failures.len() < 2 {
+        let event = time::timeout(Duration::from_secs(10), event_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        if let Event::WebSeedFailed { .. } = event {
+            failures.push(Instant::now());
+        }
+    }
+    // The first retry waits a few seconds, the next ones longer
+    assert!(failures[1] - failures[0] >= Duration::from_millis(2900));
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(requests.lock().unwrap().len(), 2);
+    worker.abort();
+}
+
+#[tokio::test]
+async fn reports_unsupported_mirrors() {
+    let content = make_content("unsupported", "dir", &[("file.bin", 1000)], true);
+    let picker = Arc::new(Mutex::new(PiecePicker::new(&content.torrent_file)));
+    let (result_sender, _result_receiver) = mpsc::channel(16);
+    let (events, mut event_receiver) = broadcast::channel(16);
+    for url in [
+        "gopher://example.org/file.bin",
+        "ftp://example.org/seed.php",
+    ] {
+        let web_seed = match url.ends_with(".php") {
+            // BEP 17 seeds are scripts, only reachable over HTTP
+            true => WebSeed::HttpSeed(url.to_string()),
+            false => WebSeed::UrlList(url.to_string()),
+        };
+        let result = start_web_seed_worker(
+            &web_seed,
+            &content.torrent_file,
+            &picker,
+            &unlimited().peer(content.torrent_file.infohash),
+            &result_sender,
+            &events,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(matches!(
+            event_receiver.recv().await.unwrap(),
+            Event::WebSeedFailed { url: failed, .. } if failed == url
+        ));
+    }
+}
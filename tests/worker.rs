@@ -0,0 +1,245 @@
+use std::{fs, net::TcpListener as StdTcpListener, sync::Arc, time::Duration};
+
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, Mutex},
+    task::JoinHandle,
+    time,
+};
+use torrent_client::{
+    controller::PieceResult,
+    create::{create_torrent, CreateOptions},
+    error::Result,
+    identity::Identity,
+    listener::PeerListener,
+    message::Message,
+    mse::EncryptionPolicy,
+    peer::{accept_handshake, handshake, PeerStream, TransportPreference, Transports},
+    picker::PiecePicker,
+    rate_limit::{Bandwidth, RateLimits},
+    swarm::{ConnectionLimits, ConnectionSlots},
+    torrent_file::TorrentFile,
+    tracker::Peer,
+    worker::{read_message, start_download_worker, start_incoming_worker, write_message},
+};
+
+const PIECE_LENGTH: usize = 16384;
+
+/// Single file torrent of three pieces, with its data
+fn content(test: &str) -> (TorrentFile, Vec<u8>) {
+    let root = std::env::temp_dir().join(format!("worker-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let data: Vec<u8> = (0..40_000).map(|i| (i * 13 + i / 97) as u8).collect();
+    let path = root.join("file.bin");
+    fs::write(&path, &data).unwrap();
+    let options = CreateOptions {
+        piece_length: Some(PIECE_LENGTH),
+        ..Default::default()
+    };
+    let torrent_file = TorrentFile::from_bytes(&create_torrent(&path, &options).unwrap()).unwrap();
+    (torrent_file, data)
+}
+
+fn slots() -> ConnectionSlots {
+    ConnectionSlots::new(
+        ConnectionLimits::default(),
+        Transports::new(TransportPreference::TcpOnly, EncryptionPolicy::Disabled),
+        Identity::new("-TT0100-"),
+        Bandwidth::new(RateLimits::default(), RateLimits::default(), None),
+    )
+}
+
+/// Seed `data` after sending `opening`, returns the first two messages
+/// received
+async fn seed(mut stream: PeerStream, data: Vec<u8>, opening: Vec<Message>) -> Vec<Message> {
+    let first = vec![
+        read_message(&mut stream).await.unwrap(),
+        read_message(&mut stream).await.unwrap(),
+    ];
+    for message in opening {
+        write_message(&mut stream, &message).await.unwrap();
+    }
+    while let Ok(message) = read_message(&mut stream).await {
+        if let Message::Request(index, begin, length) = message {
+            let start = index as usize * PIECE_LENGTH + begin as usize;
+            let block = data[start..start + length as usize].to_vec();
+            write_message(&mut stream, &Message::Piece(index, begin, block))
+                .await
+                .unwrap();
+        }
+    }
+    first
+}
+
+/// The way leechers talk to us: interest and requests first
+fn leecher_messages() -> Vec<Message> {
+    vec![
+        Message::Interested,
+        Message::Request(0, 0, PIECE_LENGTH as u32),
+        Message::Cancel(0, 0, PIECE_LENGTH as u32),
+        Message::NotInterested,
+    ]
+}
+
+/// Pieces announced one by one instead of a bitfield
+fn without_bitfield(num_pieces: usize) -> Vec<Message> {
+    let mut opening = leecher_messages();
+    opening.extend((0..num_pieces).map(|index| Message::Have(index as u32)));
+    opening.push(Message::Unchoke);
+    opening
+}
+
+/// Collect the pieces of `worker`, marking them verified the way the
+/// controller does
+async fn download(
+    torrent_file: &TorrentFile,
+    picker: Arc<Mutex<PiecePicker>>,
+    mut results: mpsc::Receiver<PieceResult>,
+    worker: JoinHandle<Result<()>>,
+) -> Vec<u8> {
+    let mut pieces = vec![Vec::new(); torrent_file.num_pieces()];
+    for _ in 0..torrent_file.num_pieces() {
+        let piece = time::timeout(Duration::from_secs(10), results.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        picker.lock().await.piece_verified(piece.index);
+        pieces[piece.index] = piece.buf;
+    }
+    // Messages of leechers never end the connection
+    assert!(!worker.is_finished());
+    worker.abort();
+    pieces.concat()
+}
+
+fn assert_opening(first: &[Message], num_pieces: usize) {
+    match first {
+        [Message::Bitfield(bitfield), Message::Interested] => {
+            assert_eq!(*bitfield, vec![0; num_pieces.div_ceil(8)]);
+        }
+        _ => panic!("unexpected opening {:?}", first),
+    }
+}
+
+/// Download from a peer listening on a local port that sends `opening`
+async fn download_from_seeder(torrent_file: &TorrentFile, data: &[u8], opening: Vec<Message>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seeder = {
+        let (infohash, data) = (torrent_file.infohash, data.to_vec());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = PeerStream::Tcp(stream);
+            accept_handshake(&mut stream, &[infohash], &[2; 20])
+                .await
+                .unwrap();
+            seed(stream, data, opening).await
+        })
+    };
+
+    let picker = Arc::new(Mutex::new(PiecePicker::new(torrent_file)));
+    let (result_sender, results) = mpsc::channel(16);
+    let worker = {
+        let (torrent_file, picker) = (torrent_file.clone(), picker.clone());
+        let peer = Peer {
+            ip: addr.ip(),
+            port: addr.port(),
+        };
+        tokio::spawn(async move {
+            let (events, _) = broadcast::channel(16);
+            start_download_worker(
+                &peer,
+                &torrent_file.infohash,
+                &torrent_file,
+                &picker,
+                &slots(),
+                &result_sender,
+                &events,
+            )
+            .await
+        })
+    };
+    assert!(download(torrent_file, picker, results, worker).await == data);
+    assert_opening(&seeder.await.unwrap(), torrent_file.num_pieces());
+}
+
+#[tokio::test]
+async fn messages_of_leechers_keep_the_connection() {
+    let (torrent_file, data) = content("leecher");
+    let num_bytes = torrent_file.num_pieces().div_ceil(8);
+    let mut opening = vec![Message::Bitfield(vec![0; num_bytes])];
+    opening.extend(leecher_messages());
+    // A late bitfield still tells what the peer has
+    opening.push(Message::Bitfield(vec![0xff; num_bytes]));
+    opening.push(Message::Unchoke);
+    download_from_seeder(&torrent_file, &data, opening).await;
+}
+
+#[tokio::test]
+async fn peers_without_a_bitfield_are_downloaded_from() {
+    let (torrent_file, data) = content("no-bitfield");
+    let opening = without_bitfield(torrent_file.num_pieces());
+    download_from_seeder(&torrent_file, &data, opening).await;
+}
+
+#[tokio::test]
+async fn incoming_peers_get_our_bitfield() {
+    let (torrent_file, data) = content("incoming");
+    let port = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut listener_slots = slots();
+    listener_slots.transports.port = port;
+    let listener = PeerListener::new(listener_slots.clone());
+    let (sender, mut receiver) = mpsc::channel(1);
+    listener.add(torrent_file.infohash, sender);
+
+    let peer = Peer {
+        ip: "127.0.0.1".parse().unwrap(),
+        port,
+    };
+    let client = slots();
+    // The port is opened by a task of the listener
+    let (stream, _) = loop {
+        match handshake(
+            &peer,
+            &torrent_file.infohash,
+            &client.transports,
+            &client.identity.peer_id,
+        )
+        .await
+        {
+            Ok(connected) => break connected,
+            Err(_) => time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let seeder = tokio::spawn(seed(
+        stream,
+        data.clone(),
+        without_bitfield(torrent_file.num_pieces()),
+    ));
+
+    let incoming = receiver.recv().await.unwrap();
+    let picker = Arc::new(Mutex::new(PiecePicker::new(&torrent_file)));
+    let (result_sender, results) = mpsc::channel(16);
+    let worker = {
+        let (torrent_file, picker) = (torrent_file.clone(), picker.clone());
+        tokio::spawn(async move {
+            let (events, _) = broadcast::channel(16);
+            start_incoming_worker(
+                incoming,
+                &torrent_file,
+                &picker,
+                &listener_slots,
+                &result_sender,
+                &events,
+            )
+            .await
+        })
+    };
+    assert!(download(&torrent_file, picker, results, worker).await == data);
+    assert_opening(&seeder.await.unwrap(), torrent_file.num_pieces());
+}